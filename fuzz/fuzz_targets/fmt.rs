@@ -5,6 +5,11 @@ extern crate libfuzzer_sys;
 
 fuzz_target!(|data: &[u8]| {
     if let Ok(text) = std::str::from_utf8(data) {
-        nixpkgs_fmt::reformat_string(text);
+        // Panics are turned into internal errors, which are bugs as well.
+        if let Err(nixpkgs_fmt::FmtError::Internal(message)) =
+            nixpkgs_fmt::reformat(text, &nixpkgs_fmt::Config::default())
+        {
+            panic!("{}", message)
+        }
     }
 });
@@ -0,0 +1,42 @@
+/* C bindings to nixpkgs-fmt, see `src/capi.rs`.
+ *
+ * Regenerate with `cbindgen --config cbindgen.toml --output include/nixfmt.h`
+ * after changing the bindings. */
+
+#ifndef NIXFMT_H
+#define NIXFMT_H
+
+#include <stdbool.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Formatting options. The optional widths are represented with `0` for "not
+ * set". */
+typedef struct NixfmtConfig {
+  uint32_t continuation_indent;
+  uint32_t max_width;
+  bool canonical_layout;
+  uint32_t comment_width;
+  bool align_trailing_comments;
+  bool trailing_commas;
+} NixfmtConfig;
+
+/* Returns the default formatting options. */
+NixfmtConfig nixfmt_config_default(void);
+
+/* Formats the NUL-terminated UTF-8 `source`. If `config` is NULL, the default
+ * options are used. Returns NULL if `source` is NULL or is not valid UTF-8.
+ * The result must be freed with `nixfmt_string_free`. */
+char *nixfmt_format(const char *source, const NixfmtConfig *config);
+
+/* Frees a string returned by `nixfmt_format`. Does nothing for NULL. */
+void nixfmt_string_free(char *string);
+
+#ifdef __cplusplus
+} /* extern "C" */
+#endif
+
+#endif /* NIXFMT_H */
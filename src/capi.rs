@@ -0,0 +1,110 @@
+//! C bindings, enabled by the `capi` feature. The declarations are in
+//! `include/nixfmt.h`, keep it in sync with this module.
+use std::{
+    ffi::{CStr, CString},
+    os::raw::c_char,
+    ptr,
+};
+
+use crate::Config;
+
+/// Formatting options, see `Config` for the meaning of the fields.
+///
+/// The optional widths are represented with `0` for "not set".
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct NixfmtConfig {
+    pub continuation_indent: u32,
+    pub max_width: u32,
+    pub canonical_layout: bool,
+    pub comment_width: u32,
+    pub align_trailing_comments: bool,
+    pub trailing_commas: bool,
+}
+
+impl From<NixfmtConfig> for Config {
+    fn from(config: NixfmtConfig) -> Config {
+        let optional = |width: u32| if width == 0 { None } else { Some(width) };
+        Config {
+            continuation_indent: config.continuation_indent,
+            max_width: optional(config.max_width),
+            canonical_layout: config.canonical_layout,
+            comment_width: optional(config.comment_width),
+            align_trailing_comments: config.align_trailing_comments,
+            trailing_commas: config.trailing_commas,
+            ..Config::default()
+        }
+    }
+}
+
+/// Returns the default formatting options.
+#[no_mangle]
+pub extern "C" fn nixfmt_config_default() -> NixfmtConfig {
+    let config = Config::default();
+    NixfmtConfig {
+        continuation_indent: config.continuation_indent,
+        max_width: config.max_width.unwrap_or(0),
+        canonical_layout: config.canonical_layout,
+        comment_width: config.comment_width.unwrap_or(0),
+        align_trailing_comments: config.align_trailing_comments,
+        trailing_commas: config.trailing_commas,
+    }
+}
+
+/// Formats the NUL-terminated UTF-8 `source`, like `reformat_string_with_config`.
+///
+/// If `config` is NULL, the default options are used. Returns NULL if `source`
+/// is NULL or is not valid UTF-8. The result must be freed with
+/// `nixfmt_string_free`.
+///
+/// # Safety
+///
+/// `source` must be NULL or point to a NUL-terminated string, and `config`
+/// must be NULL or point to a `NixfmtConfig`.
+#[no_mangle]
+pub unsafe extern "C" fn nixfmt_format(
+    source: *const c_char,
+    config: *const NixfmtConfig,
+) -> *mut c_char {
+    if source.is_null() {
+        return ptr::null_mut();
+    }
+    let source = match CStr::from_ptr(source).to_str() {
+        Ok(it) => it,
+        Err(_) => return ptr::null_mut(),
+    };
+    let config = if config.is_null() { Config::default() } else { Config::from(*config) };
+    let res = crate::reformat_string_with_config(source, &config);
+    // The output has a NUL only if the input had one, which it can't.
+    CString::new(res).map_or(ptr::null_mut(), CString::into_raw)
+}
+
+/// Frees a string returned by `nixfmt_format`. Does nothing for NULL.
+///
+/// # Safety
+///
+/// `string` must be NULL or a string returned by `nixfmt_format`, which hasn't
+/// been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn nixfmt_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_c_strings() {
+        let source = CString::new("{a=1;}").unwrap();
+        let config = NixfmtConfig { max_width: 8, ..nixfmt_config_default() };
+        unsafe {
+            let res = nixfmt_format(source.as_ptr(), &config);
+            assert_eq!(CStr::from_ptr(res).to_str(), Ok("{\n  a = 1;\n}\n"));
+            nixfmt_string_free(res);
+            assert!(nixfmt_format(b"\xff\0".as_ptr() as *const c_char, ptr::null()).is_null());
+        }
+    }
+}
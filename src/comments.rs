@@ -0,0 +1,187 @@
+//! Attaches comments to the syntax elements they describe.
+//!
+//! In the syntax tree, comments are just trivia between the tokens. For
+//! formatting, we need to know what a comment is about:
+//!
+//! ```nix
+//! {
+//!   # Leading comment of `a`
+//!   a = 1; # Trailing comment of `a = 1;`
+//!
+//!   # Detached comment, which describes the group below
+//!
+//!   b = 2;
+//! }
+//! ```
+//!
+//! Rules which need to keep comments together with the code use this instead
+//! of inspecting the neighbouring whitespace themselves.
+use std::iter::successors;
+
+use rnix::{
+    SyntaxElement,
+    SyntaxKind::{TOKEN_COMMENT, TOKEN_WHITESPACE},
+    SyntaxToken, T,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Attachment {
+    /// The comment follows the element on the same line.
+    Trailing(SyntaxElement),
+    /// The comment is on the line(s) right before the element, or, for block
+    /// comments, right before it on the same line.
+    Leading(SyntaxElement),
+    /// The comment is separated from the following code by a blank line, or is
+    /// the last thing in its block.
+    Detached,
+}
+
+pub(crate) fn attachment(comment: &SyntaxToken) -> Attachment {
+    debug_assert_eq!(comment.kind(), TOKEN_COMMENT);
+    let is_line_comment = comment.text().starts_with('#');
+    if is_line_comment || !followed_by_code_on_same_line(comment) {
+        if let Some(prev) = code_before_on_same_line(comment) {
+            return Attachment::Trailing(prev);
+        }
+    }
+    let mut next = comment.next_sibling_or_token();
+    while let Some(element) = next {
+        match element.as_token() {
+            Some(ws) if ws.kind() == TOKEN_WHITESPACE => {
+                if ws.text().matches('\n').count() > 1 {
+                    return Attachment::Detached;
+                }
+            }
+            Some(it) if it.kind() == TOKEN_COMMENT => (),
+            // A comment at the end of a block describes its contents
+            Some(it) if [T!["}"], T!["]"], T![")"]].contains(&it.kind()) => {
+                return Attachment::Detached
+            }
+            _ => return Attachment::Leading(element),
+        }
+        next = element.next_sibling_or_token();
+    }
+    Attachment::Detached
+}
+
+/// The comment on the same line after `element`, if any.
+pub(crate) fn trailing_comment(element: &SyntaxElement) -> Option<SyntaxToken> {
+    let mut next = element.next_sibling_or_token()?;
+    if next.kind() == TOKEN_WHITESPACE {
+        next = next.next_sibling_or_token()?;
+    }
+    let comment = next.into_token().filter(|it| it.kind() == TOKEN_COMMENT)?;
+    match attachment(&comment) {
+        Attachment::Trailing(it) if &it == element => Some(comment),
+        _ => None,
+    }
+}
+
+/// The comments on the lines right before `element`.
+pub(crate) fn leading_comments(element: &SyntaxElement) -> Vec<SyntaxToken> {
+    let mut res: Vec<SyntaxToken> =
+        successors(element.prev_sibling_or_token(), |it| it.prev_sibling_or_token())
+            .filter(|it| it.kind() != TOKEN_WHITESPACE)
+            .map_while(|it| it.into_token().filter(|it| it.kind() == TOKEN_COMMENT))
+            .take_while(|it| attachment(it) == Attachment::Leading(element.clone()))
+            .collect();
+    res.reverse();
+    res
+}
+
+fn code_before_on_same_line(comment: &SyntaxToken) -> Option<SyntaxElement> {
+    let mut prev = comment.prev_sibling_or_token();
+    if let Some(ws) = prev.as_ref().and_then(|it| it.as_token()) {
+        if ws.kind() == TOKEN_WHITESPACE {
+            if ws.text().contains('\n') {
+                return None;
+            }
+            prev = ws.prev_sibling_or_token();
+        }
+    }
+    match prev {
+        Some(it) if it.kind() != TOKEN_COMMENT => Some(it),
+        Some(_) => None,
+        // The comment is the first child, like in `f (# comment`
+        None => {
+            let prev_token = comment.prev_token()?;
+            let prev_token = match prev_token.kind() {
+                TOKEN_WHITESPACE if prev_token.text().contains('\n') => return None,
+                TOKEN_WHITESPACE => prev_token.prev_token()?,
+                _ => prev_token,
+            };
+            Some(prev_token.into())
+        }
+    }
+}
+
+fn followed_by_code_on_same_line(comment: &SyntaxToken) -> bool {
+    match comment.next_token() {
+        Some(ws) if ws.kind() == TOKEN_WHITESPACE => !ws.text().contains('\n'),
+        Some(_) => true,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rnix::{SyntaxKind, SyntaxKind::*, SyntaxNode};
+
+    use super::*;
+
+    fn comments(text: &str) -> (SyntaxNode, Vec<SyntaxToken>) {
+        let root = rnix::parse(text).node();
+        let comments = root
+            .descendants_with_tokens()
+            .filter_map(|it| it.into_token())
+            .filter(|it| it.kind() == TOKEN_COMMENT)
+            .collect();
+        (root, comments)
+    }
+
+    fn kind(attachment: Attachment) -> Option<(&'static str, SyntaxKind)> {
+        match attachment {
+            Attachment::Trailing(it) => Some(("trailing", it.kind())),
+            Attachment::Leading(it) => Some(("leading", it.kind())),
+            Attachment::Detached => None,
+        }
+    }
+
+    #[test]
+    fn attaches_comments() {
+        let (_root, comments) = comments(
+            "{
+  # leading
+  # leading
+  a = 1; # trailing
+  b = /* leading */ 2;
+
+  # detached
+
+  c = 3;
+  # detached
+}",
+        );
+        let actual: Vec<_> = comments.iter().map(|it| kind(attachment(it))).collect();
+        assert_eq!(
+            actual,
+            vec![
+                Some(("leading", NODE_KEY_VALUE)),
+                Some(("leading", NODE_KEY_VALUE)),
+                Some(("trailing", NODE_KEY_VALUE)),
+                Some(("leading", NODE_LITERAL)),
+                None,
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_comments_of_element() {
+        let (root, comments) = comments("{\n  # one\n  # two\n  a = 1; # three\n}");
+        let key_value: SyntaxElement =
+            root.descendants().find(|it| it.kind() == NODE_KEY_VALUE).unwrap().into();
+        assert_eq!(leading_comments(&key_value), comments[..2].to_vec());
+        assert_eq!(trailing_comment(&key_value), Some(comments[2].clone()));
+    }
+}
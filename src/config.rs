@@ -0,0 +1,91 @@
+//! Options which tweak the formatting style.
+
+/// Formatting options.
+///
+/// The defaults correspond to the nixpkgs style, which is what
+/// `reformat_string` uses. To change some of the options, start from the
+/// defaults:
+///
+/// ```
+/// let config = nixpkgs_fmt::Config { max_width: Some(80), ..Default::default() };
+/// assert_eq!(nixpkgs_fmt::reformat_string_with_config("[1 2]", &config), "[ 1 2 ]\n");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Config {
+    /// Number of spaces by which continuation lines (operands of a wrapped
+    /// binary operator, wrapped function arguments, etc) are indented relative
+    /// to the line they continue.
+    ///
+    /// Bodies of blocks, like attribute sets or lists, always use the usual
+    /// indent of two spaces.
+    pub continuation_indent: u32,
+    /// If set, lines longer than this many columns are broken where possible.
+    ///
+    /// By default, the line length is not enforced, and line breaks are
+    /// preserved as written.
+    pub max_width: Option<u32>,
+    /// Lay out the code independently of the line breaks in the input: short
+    /// multi-line constructs are joined onto a single line.
+    ///
+    /// Has no effect unless `max_width` is set.
+    pub canonical_layout: bool,
+    /// If set, runs of `#` comments with lines longer than this many columns
+    /// are re-wrapped.
+    ///
+    /// Paragraphs are preserved, and the lines which look like code are left
+    /// alone.
+    pub comment_width: Option<u32>,
+    /// Align the `#` of trailing comments on consecutive lines to a common
+    /// column.
+    pub align_trailing_comments: bool,
+    /// Lay out the lambda formals which don't fit on one line with a comma
+    /// at the end of each line, instead of the nixpkgs style with a comma at
+    /// the start of each line:
+    ///
+    /// ```nix
+    /// {
+    ///   stdenv,
+    ///   fetchurl
+    /// }:
+    /// ```
+    pub trailing_commas: bool,
+    /// The version of the style rules. Pin it to avoid churn when the crate
+    /// is updated.
+    pub style_version: StyleVersion,
+}
+
+/// A version of the formatting style.
+///
+/// For a fixed version (and the rest of `Config`), the output never changes
+/// across releases of the crate; changes to the style only go into new
+/// versions. The default is the latest version, so it can change between
+/// releases.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[non_exhaustive]
+pub enum StyleVersion {
+    V1,
+}
+
+impl StyleVersion {
+    pub const LATEST: StyleVersion = StyleVersion::V1;
+}
+
+impl Default for StyleVersion {
+    fn default() -> StyleVersion {
+        StyleVersion::LATEST
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            continuation_indent: 2,
+            max_width: None,
+            canonical_layout: false,
+            comment_width: None,
+            align_trailing_comments: false,
+            trailing_commas: false,
+            style_version: StyleVersion::default(),
+        }
+    }
+}
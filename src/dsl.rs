@@ -1,13 +1,30 @@
 //! This module contains a definition of pattern-based formatting DSL.
 use std::fmt;
 
-use rnix::SyntaxElement;
+use rnix::{SyntaxElement, SyntaxKind, SyntaxNode, TextRange};
 
 use crate::{
-    pattern::Pattern,
+    pattern::{self_or_ancestor, Pattern},
     tree_utils::{next_non_whitespace_sibling, prev_non_whitespace_sibling},
 };
 
+/// Declares a named rule of a `SpacingDsl` or an `IndentDsl` together with
+/// its examples, which are checked like the `test`s of the DSL:
+///
+/// ```text
+/// rule!(dsl, "Space before =":
+///     inside(NODE_KEY_VALUE).before(T![=]).single_space();
+///     "{ a=92; }" => "{ a = 92; }",
+/// );
+/// ```
+macro_rules! rule {
+    ($dsl:expr, $name:literal: $($method:ident $args:tt).+; $($before:expr => $after:expr),* $(,)?) => {{
+        let dsl = &mut $dsl;
+        $(dsl.named_test($name, $before, $after);)*
+        dsl.rule($name)$(.$method $args)+;
+    }};
+}
+
 /// `SpacingRule` describes whitespace requirements between `SyntaxElement` Note
 /// that it doesn't handle indentation (first whitespace on a line), there's
 /// `IndentRule` for that!
@@ -18,6 +35,8 @@ pub(crate) struct SpacingRule {
     pub(crate) pattern: Pattern,
     /// How much space to add/remove at the start or end of the element.
     pub(crate) space: Space,
+    /// Rules with higher priority override the ones with lower priority.
+    pub(crate) priority: i32,
 }
 
 /// Make `SpacingRule` usable with `PatternSet`
@@ -35,14 +54,32 @@ pub(crate) struct Space {
     pub(crate) loc: SpaceLoc,
 }
 
-#[derive(Debug, Clone, Copy)]
-pub(crate) enum SpaceValue {
+/// A whitespace requirement of a spacing rule. The actions of
+/// `SpacingRuleBuilder`, like `single_space`, are shorthands for these.
+///
+/// Where several rules apply to the same whitespace, the one with the highest
+/// priority wins, and among the rules with the same priority, the last one
+/// wins. The exceptions are `MaxBlankLines` and `SameLine`: they only limit
+/// the line breaks, and are applied after the other rules, whatever their
+/// priority.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SpaceValue {
     /// Single whitespace char, like ` `
     Single,
     /// Single whitespace char, like ` `, but preserve existing line break.
     SingleOptionalNewline,
-    /// A single newline (`\n`) char
+    /// A newline (`\n`) char, existing line breaks and blank lines are kept.
     Newline,
+    /// Exactly one newline, blank lines are removed.
+    SingleNewline,
+    /// Exactly this many blank lines.
+    BlankLines(u32),
+    /// Line breaks are preserved, but runs of more than this many blank lines
+    /// are shortened.
+    MaxBlankLines(u32),
+    /// Line breaks are replaced with a single space, other whitespace is kept.
+    SameLine,
     /// No whitespace at all.
     None,
     /// No space, but preserve existing line break.
@@ -67,12 +104,21 @@ pub(crate) enum SpaceLoc {
     Around,
 }
 
+/// A test case written next to the rules it checks.
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct InlineTest {
+    pub(crate) name: Option<&'static str>,
+    pub(crate) before: &'static str,
+    pub(crate) after: &'static str,
+}
+
 /// A builder to conveniently specify a set of `SpacingRule`s
 #[derive(Debug, Default)]
-pub(crate) struct SpacingDsl {
+pub struct SpacingDsl {
     pub(crate) rules: Vec<SpacingRule>,
     #[cfg(test)]
-    pub(crate) tests: Vec<(&'static str, &'static str)>,
+    pub(crate) tests: Vec<InlineTest>,
 }
 
 impl SpacingDsl {
@@ -85,74 +131,113 @@ impl SpacingDsl {
         self
     }
     /// Add a new rule with the given `name`.
-    pub(crate) fn rule(&mut self, name: &'static str) -> SpacingRuleBuilder<'_> {
+    pub fn rule(&mut self, name: &'static str) -> SpacingRuleBuilder<'_> {
         SpacingRuleBuilder {
             dsl: self,
             rule_name: Some(name),
             parent: None,
+            ancestor: None,
             child: None,
             between: None,
             loc: None,
+            priority: 0,
         }
     }
     /// Specify an anonymous spacing rule for an element which is a child of `parent`.
-    pub(crate) fn inside(&mut self, parent: impl Into<Pattern>) -> SpacingRuleBuilder<'_> {
+    pub fn inside(&mut self, parent: impl Into<Pattern>) -> SpacingRuleBuilder<'_> {
         SpacingRuleBuilder {
             dsl: self,
             rule_name: None,
             parent: None,
+            ancestor: None,
             child: None,
             between: None,
             loc: None,
+            priority: 0,
         }
         .inside(parent)
     }
+    /// Adds a test case for the rules above. `after` gets a trailing newline
+    /// if it lacks one. Named tests, like the indentation ones, may span
+    /// several lines, and are unindented.
     pub(crate) fn test(&mut self, before: &'static str, after: &'static str) -> &mut SpacingDsl {
         #[cfg(test)]
         {
-            self.tests.push((before, after));
+            self.tests.push(InlineTest { name: None, before, after });
         }
         let _ = (before, after);
         self
     }
+    /// Like `test`, but with a name, which is shown if the test fails.
+    pub(crate) fn named_test(
+        &mut self,
+        name: &'static str,
+        before: &'static str,
+        after: &'static str,
+    ) -> &mut SpacingDsl {
+        #[cfg(test)]
+        {
+            self.tests.push(InlineTest { name: Some(name), before, after });
+        }
+        let _ = (name, before, after);
+        self
+    }
 }
 
+/// The priority of `SpacingRuleBuilder::force_break`, which wins over all
+/// the other rules.
+pub(crate) const FORCE_BREAK_PRIORITY: i32 = i32::MAX;
+
 /// A builder to conveniently specify a single rule.
-pub(crate) struct SpacingRuleBuilder<'a> {
+pub struct SpacingRuleBuilder<'a> {
     dsl: &'a mut SpacingDsl,
     rule_name: Option<&'static str>,
     parent: Option<Pattern>,
+    ancestor: Option<Pattern>,
     child: Option<Pattern>,
     between: Option<(Pattern, Pattern)>,
     loc: Option<SpaceLoc>,
+    priority: i32,
 }
 
 impl<'a> SpacingRuleBuilder<'a> {
     /// The rule applies to direct children of the `parent` element.
-    pub(crate) fn inside(mut self, parent: impl Into<Pattern>) -> SpacingRuleBuilder<'a> {
+    pub fn inside(mut self, parent: impl Into<Pattern>) -> SpacingRuleBuilder<'a> {
         self.parent = Some(parent.into());
         self
     }
+    /// The rule applies to direct children of an element of any of the
+    /// `parents` kinds.
+    pub fn inside_any(self, parents: &[SyntaxKind]) -> SpacingRuleBuilder<'a> {
+        self.inside(parents)
+    }
+    /// The rule applies only somewhere inside of `ancestor`, at any depth.
+    /// This can be repeated, to require several ancestors, which need not be
+    /// the same node.
+    pub fn within(mut self, ancestor: impl Into<Pattern>) -> SpacingRuleBuilder<'a> {
+        self.ancestor = add_ancestor(self.ancestor.take(), ancestor.into());
+        self
+    }
     /// The rule applies to both sides of the element `child`.
-    pub(crate) fn around(mut self, child: impl Into<Pattern>) -> SpacingRuleBuilder<'a> {
+    pub fn around(mut self, child: impl Into<Pattern>) -> SpacingRuleBuilder<'a> {
         self.child = Some(child.into());
         self.loc = Some(SpaceLoc::Around);
         self
     }
     /// The rule applies to the leading whitespace before `child`.
-    pub(crate) fn before(mut self, child: impl Into<Pattern>) -> SpacingRuleBuilder<'a> {
+    pub fn before(mut self, child: impl Into<Pattern>) -> SpacingRuleBuilder<'a> {
         self.child = Some(child.into());
         self.loc = Some(SpaceLoc::Before);
         self
     }
     /// The rule applies to the trailing whitespace after `child`.
-    pub(crate) fn after(mut self, child: impl Into<Pattern>) -> SpacingRuleBuilder<'a> {
+    pub fn after(mut self, child: impl Into<Pattern>) -> SpacingRuleBuilder<'a> {
         self.child = Some(child.into());
         self.loc = Some(SpaceLoc::After);
         self
     }
     /// The rule applies to the whitespace between the two nodes.
-    pub(crate) fn between(
+    pub fn between(
         mut self,
         left: impl Into<Pattern>,
         right: impl Into<Pattern>,
@@ -161,42 +246,88 @@ impl<'a> SpacingRuleBuilder<'a> {
         self.loc = Some(SpaceLoc::After);
         self
     }
-    /// The rule applies if the `cond` is true.
-    pub(crate) fn when(mut self, cond: fn(&SyntaxElement) -> bool) -> SpacingRuleBuilder<'a> {
+    /// The rule applies if the `cond` is true. Conditions can be combined
+    /// with `PatternExt::and`, `PatternExt::or` and `not`.
+    pub fn when(mut self, cond: impl Into<Pattern>) -> SpacingRuleBuilder<'a> {
         let pred = cond.into();
         let prev = self.child.take().unwrap();
         self.child = Some(prev & pred);
         self
     }
+    /// Where several rules set the same whitespace, the one with the highest
+    /// priority wins. The default priority is `0`, give refinements of more
+    /// general rules a higher one.
+    ///
+    /// Among the rules with the same priority, the last one wins, and
+    /// `Formatter::rule_conflicts` reports it if they disagree.
+    pub fn priority(mut self, priority: i32) -> SpacingRuleBuilder<'a> {
+        self.priority = priority;
+        self
+    }
+    /// Enforce the whitespace requirement `value`.
+    pub fn space(self, value: SpaceValue) -> &'a mut SpacingDsl {
+        self.finish(value)
+    }
     /// Enforce single whitespace character.
-    pub(crate) fn single_space(self) -> &'a mut SpacingDsl {
+    pub fn single_space(self) -> &'a mut SpacingDsl {
         self.finish(SpaceValue::Single)
     }
-    pub(crate) fn single_space_or_optional_newline(self) -> &'a mut SpacingDsl {
+    pub fn single_space_or_optional_newline(self) -> &'a mut SpacingDsl {
         self.finish(SpaceValue::SingleOptionalNewline)
     }
-    pub(crate) fn no_space_or_optional_newline(self) -> &'a mut SpacingDsl {
+    pub fn no_space_or_optional_newline(self) -> &'a mut SpacingDsl {
         self.finish(SpaceValue::NoneOptionalNewline)
     }
     /// Enforce the absence of any space.
-    pub(crate) fn no_space(self) -> &'a mut SpacingDsl {
+    pub fn no_space(self) -> &'a mut SpacingDsl {
         self.finish(SpaceValue::None)
     }
     /// Enforce a single whitespace or newline character.
-    pub(crate) fn single_space_or_newline(self) -> &'a mut SpacingDsl {
+    pub fn single_space_or_newline(self) -> &'a mut SpacingDsl {
         self.finish(SpaceValue::SingleOrNewline)
     }
     /// Enforce a absence of whitespace or a newline character.
-    pub(crate) fn no_space_or_newline(self) -> &'a mut SpacingDsl {
+    pub fn no_space_or_newline(self) -> &'a mut SpacingDsl {
         self.finish(SpaceValue::NoneOrNewline)
     }
-    /// Enforce a newline
-    pub(crate) fn newline(self) -> &'a mut SpacingDsl {
+    /// Enforce a newline. Existing blank lines are kept.
+    pub fn newline(self) -> &'a mut SpacingDsl {
         self.finish(SpaceValue::Newline)
     }
+    /// Enforce a newline, like `newline`, but with the highest priority, so
+    /// that no other rule can put the elements on the same line, not even
+    /// `glue`.
+    pub fn force_break(mut self) -> &'a mut SpacingDsl {
+        self.priority = FORCE_BREAK_PRIORITY;
+        self.finish(SpaceValue::Newline)
+    }
+    /// Enforce a newline, without blank lines.
+    pub fn single_newline(self) -> &'a mut SpacingDsl {
+        self.finish(SpaceValue::SingleNewline)
+    }
+    /// Enforce exactly `count` blank lines.
+    pub fn blank_lines(self, count: u32) -> &'a mut SpacingDsl {
+        self.finish(SpaceValue::BlankLines(count))
+    }
+    /// Keep the existing line breaks, but allow at most `count` blank lines.
+    /// This is applied after the other rules, whatever their priority.
+    pub fn max_blank_lines(self, count: u32) -> &'a mut SpacingDsl {
+        self.finish(SpaceValue::MaxBlankLines(count))
+    }
+    /// Keep the elements on the same line, replacing a line break with a
+    /// single space. Like `max_blank_lines`, this is applied after the other
+    /// rules. A line break after a `#` comment or from `force_break` is never
+    /// removed.
+    pub fn glue(self) -> &'a mut SpacingDsl {
+        self.finish(SpaceValue::SameLine)
+    }
     fn finish(self, value: SpaceValue) -> &'a mut SpacingDsl {
         assert!(self.between.is_some() ^ self.child.is_some());
         let parent = self.parent.expect("parent must be set for each rule");
+        let parent = match self.ancestor {
+            Some(ancestor) => parent & ancestor,
+            None => parent,
+        };
         if let Some((left, right)) = self.between {
             let child = {
                 let left = left.clone();
@@ -209,6 +340,7 @@ impl<'a> SpacingRuleBuilder<'a> {
                 name: self.rule_name.map(RuleName),
                 pattern: child.with_parent(parent.clone()),
                 space: Space { value, loc: SpaceLoc::After },
+                priority: self.priority,
             };
             self.dsl.add_rule(rule);
 
@@ -220,6 +352,7 @@ impl<'a> SpacingRuleBuilder<'a> {
                 name: self.rule_name.map(RuleName),
                 pattern: child.with_parent(parent),
                 space: Space { value, loc: SpaceLoc::Before },
+                priority: self.priority,
             };
             self.dsl.add_rule(rule);
         } else {
@@ -227,6 +360,7 @@ impl<'a> SpacingRuleBuilder<'a> {
                 name: self.rule_name.map(RuleName),
                 pattern: self.child.unwrap().with_parent(parent),
                 space: Space { value, loc: self.loc.unwrap() },
+                priority: self.priority,
             };
             self.dsl.add_rule(rule);
         }
@@ -234,6 +368,17 @@ impl<'a> SpacingRuleBuilder<'a> {
     }
 }
 
+/// Adds `ancestor` to the ancestors required by `within`. Each of them may be
+/// matched by a different node: the parent of the element, or one of its
+/// ancestors.
+fn add_ancestor(within: Option<Pattern>, ancestor: Pattern) -> Option<Pattern> {
+    let ancestor = self_or_ancestor(ancestor);
+    Some(match within {
+        Some(prev) => prev & ancestor,
+        None => ancestor,
+    })
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum Modality {
     Positive,
@@ -241,8 +386,12 @@ pub(crate) enum Modality {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub(crate) enum IndentValue {
+pub enum IndentValue {
+    /// Indent a block body by one level.
     Indent,
+    /// Indent a line which continues the previous one, by
+    /// `Config::continuation_indent`.
+    Continuation,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -260,17 +409,51 @@ impl RuleName {
         assert!(!name.ends_with('.'), "rule names should not end in `.`");
         RuleName(name)
     }
+    pub(crate) fn as_str(self) -> &'static str {
+        self.0
+    }
+}
+
+/// Two spacing rules with the same priority which set the same whitespace
+/// differently, see `Formatter::rule_conflicts`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleConflict {
+    /// The range of the whitespace in the input.
+    pub range: TextRange,
+    /// The names of the overridden rule and of the winning one, `None` for
+    /// unnamed rules.
+    pub rules: [Option<&'static str>; 2],
+    /// The requirements of each of the rules.
+    pub values: [SpaceValue; 2],
+    /// The whitespace set by each of the rules.
+    pub texts: [String; 2],
+}
+
+/// Which set of rules a rule belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleGroup {
+    Spacing,
+    Indentation,
+    Wrapping,
+    Lint,
+}
+
+/// A read-only description of a built-in rule, as listed by `builtin_rules`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleInfo {
+    /// The name which `explain` shows for the edits made by this rule.
+    pub name: &'static str,
+    pub group: RuleGroup,
 }
 
 /// `IndentRule` describes how an element should be indented.
 ///
 /// `IndentRule`s are only effective for elements which begin the line.
 ///
-/// Note that currently we support only two kinds of indentation:
+/// Note that currently we support only three kinds of indentation:
 /// * the same, as parent (default)
 /// * indent relative to the parent.
-///
-/// For this reason, `indent_value` is mostly unused.
+/// * continuation indent relative to the parent.
 #[derive(Debug)]
 pub(crate) struct IndentRule {
     pub(crate) name: RuleName,
@@ -297,16 +480,27 @@ pub(crate) struct IndentRule {
     /// applies and `f = x ...` is the thing to which the `anchor_pattern`
     /// applies.
     pub(crate) anchor_pattern: Option<Pattern>,
+    /// If set, the indent is computed relative to the line on which the
+    /// nearest element matching this pattern starts, instead of the anchor.
+    /// The candidates are the ancestors of the element, and the elements
+    /// which precede the ancestors among their siblings, like the key of the
+    /// enclosing entry.
+    pub(crate) relative_to: Option<Pattern>,
     pub(crate) indent_value: IndentValue,
 }
 
 /// A builder to conveniently specify a set of `IndentRule`s.
 #[derive(Default)]
-pub(crate) struct IndentDsl {
+pub struct IndentDsl {
     pub(crate) rules: Vec<IndentRule>,
+    /// Rules added on top of the built-in `rules`. Unlike the built-in ones,
+    /// these may overlap, and the last matching rule wins.
+    pub(crate) extra_rules: Vec<IndentRule>,
     pub(crate) anchors: Vec<Pattern>,
+    /// The width of `IndentValue::Continuation`.
+    pub(crate) continuation_indent: u32,
     #[cfg(test)]
-    pub(crate) tests: Vec<(&'static str, &'static str)>,
+    pub(crate) tests: Vec<InlineTest>,
 }
 
 impl IndentDsl {
@@ -325,32 +519,41 @@ impl IndentDsl {
     ///
     /// we want to indent `y = z;` relative to `baz ? ...`, although it doesn't
     /// start on the first line.
-    pub(crate) fn anchor(&mut self, pattern: impl Into<Pattern>) -> &mut IndentDsl {
+    pub fn anchor(&mut self, pattern: impl Into<Pattern>) -> &mut IndentDsl {
         self.anchors.push(pattern.into());
         self
     }
     /// Adds a new indent rule with the given name
-    pub(crate) fn rule<'a>(&'a mut self, rule_name: &'static str) -> IndentRuleBuilder<'a> {
+    pub fn rule<'a>(&'a mut self, rule_name: &'static str) -> IndentRuleBuilder<'a> {
         IndentRuleBuilder::new(self, rule_name)
     }
-    pub(crate) fn test(&mut self, before: &'static str, after: &'static str) -> &mut IndentDsl {
+    /// Adds a test case for the rule `name`, which is shown if the test fails.
+    /// The `rule!` macro adds these for the examples of a rule.
+    pub(crate) fn named_test(
+        &mut self,
+        name: &'static str,
+        before: &'static str,
+        after: &'static str,
+    ) -> &mut IndentDsl {
         #[cfg(test)]
         {
-            self.tests.push((before, after));
+            self.tests.push(InlineTest { name: Some(name), before, after });
         }
-        let _ = (before, after);
+        let _ = (name, before, after);
         self
     }
 }
 
 /// A builder to conveniently specify a single `IndentRule`.
-pub(crate) struct IndentRuleBuilder<'a> {
+pub struct IndentRuleBuilder<'a> {
     dsl: &'a mut IndentDsl,
     rule_name: &'static str,
     parent: Option<Pattern>,
+    ancestor: Option<Pattern>,
     child: Option<Pattern>,
     child_modality: Modality,
     anchor_pattern: Option<Pattern>,
+    relative_to: Option<Pattern>,
 }
 
 impl<'a> IndentRuleBuilder<'a> {
@@ -359,21 +562,35 @@ impl<'a> IndentRuleBuilder<'a> {
             dsl,
             rule_name,
             parent: None,
+            ancestor: None,
             child: None,
             child_modality: Modality::Positive,
             anchor_pattern: None,
+            relative_to: None,
         }
     }
 
     /// Rule applies if element's parent matches.
-    pub(crate) fn inside(mut self, parent: impl Into<Pattern>) -> Self {
+    pub fn inside(mut self, parent: impl Into<Pattern>) -> Self {
         let prev = self.parent.replace(parent.into());
         assert!(prev.is_none());
         self
     }
 
+    /// Rule applies if element's parent is of any of the `parents` kinds.
+    pub fn inside_any(self, parents: &[SyntaxKind]) -> Self {
+        self.inside(parents)
+    }
+
+    /// Rule applies if element is somewhere inside of `ancestor`, at any
+    /// depth. Like `SpacingRuleBuilder::within`, this can be repeated.
+    pub fn within(mut self, ancestor: impl Into<Pattern>) -> Self {
+        self.ancestor = add_ancestor(self.ancestor.take(), ancestor.into());
+        self
+    }
+
     /// Rule applies if element itself does *not* match.
-    pub(crate) fn not_matching(self, child: impl Into<Pattern>) -> Self {
+    pub fn not_matching(self, child: impl Into<Pattern>) -> Self {
         self.matching_modality(child.into(), Modality::Negative)
     }
 
@@ -385,15 +602,20 @@ impl<'a> IndentRuleBuilder<'a> {
     }
 
     /// Which indent does the rule applies?
-    pub(crate) fn set(self, indent_value: IndentValue) -> &'a mut IndentDsl {
+    pub fn set(self, indent_value: IndentValue) -> &'a mut IndentDsl {
         let dsl = self.dsl;
         let name = self.rule_name;
+        let parent = self.parent.unwrap_or_else(|| panic!("incomplete rule: {}", name));
         let rule = IndentRule {
             name: RuleName::new(name),
-            parent: self.parent.unwrap_or_else(|| panic!("incomplete rule: {}", name)),
+            parent: match self.ancestor {
+                Some(ancestor) => parent & ancestor,
+                None => parent,
+            },
             child: self.child,
             child_modality: self.child_modality,
             anchor_pattern: self.anchor_pattern,
+            relative_to: self.relative_to,
             indent_value,
         };
         dsl.rules.push(rule);
@@ -402,8 +624,480 @@ impl<'a> IndentRuleBuilder<'a> {
 
     /// Only apply this rule when `cond` is true for the anchor node, relative
     /// to which we compute indentation level.
-    pub(crate) fn when_anchor(mut self, cond: impl Into<Pattern>) -> Self {
+    pub fn when_anchor(mut self, cond: impl Into<Pattern>) -> Self {
         self.anchor_pattern = Some(cond.into());
         self
     }
+
+    /// Indent relative to the line on which the nearest `pattern` starts,
+    /// looking at the ancestors of the element and at what precedes them,
+    /// for example, at the key of the enclosing entry:
+    ///
+    /// ```nix
+    /// {
+    ///   foo = bar
+    ///     baz;
+    /// }
+    /// ```
+    ///
+    /// If there is no such element, the indent is computed as usual.
+    pub fn relative_to(mut self, pattern: impl Into<Pattern>) -> Self {
+        let prev = self.relative_to.replace(pattern.into());
+        assert!(prev.is_none());
+        self
+    }
+}
+
+/// `BreakRule` describes where a node can be split over several lines, if it
+/// doesn't fit into `Config::max_width`.
+///
+/// Breaking a node inserts a newline before each of its break points, and then
+/// spacing and indentation rules do the rest: a single newline in a list is
+/// enough for the list to be laid out one element per line.
+pub(crate) struct BreakRule {
+    pub(crate) name: RuleName,
+    /// A node to which this rule applies.
+    pub(crate) pattern: Pattern,
+    /// Elements which should start a line, once the node is broken.
+    pub(crate) break_points: BreakPoints,
+}
+
+type BreakPoints = Box<dyn Fn(&SyntaxNode) -> Vec<SyntaxElement> + Send + Sync>;
+
+impl fmt::Debug for BreakRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BreakRule").field("name", &self.name).finish()
+    }
+}
+
+/// `JoinRule` describes a node which is laid out on a single line if it fits
+/// into `Config::max_width`, in spite of the newlines in the input.
+///
+/// Joining only happens with `Config::canonical_layout`.
+#[derive(Debug)]
+pub(crate) struct JoinRule {
+    pub(crate) name: RuleName,
+    pub(crate) pattern: Pattern,
+}
+
+/// A builder to conveniently specify a set of `BreakRule`s and `JoinRule`s.
+///
+/// Rules are tried in the order they are defined.
+#[derive(Debug, Default)]
+pub(crate) struct WrapDsl {
+    pub(crate) rules: Vec<BreakRule>,
+    pub(crate) joins: Vec<JoinRule>,
+    #[cfg(test)]
+    pub(crate) tests: Vec<InlineTest>,
+}
+
+impl WrapDsl {
+    /// Adds a new break rule with the given name
+    pub(crate) fn rule<'a>(&'a mut self, rule_name: &'static str) -> BreakRuleBuilder<'a> {
+        BreakRuleBuilder { dsl: self, rule_name, pattern: None }
+    }
+    /// Inline tests are checked with the width of `TEST_WIDTH`, in canonical
+    /// layout mode.
+    pub(crate) fn test(&mut self, before: &'static str, after: &'static str) -> &mut WrapDsl {
+        #[cfg(test)]
+        {
+            self.tests.push(InlineTest { name: None, before, after });
+        }
+        let _ = (before, after);
+        self
+    }
+}
+
+/// A width which is used to check the inline tests of the `WrapDsl`.
+#[cfg(test)]
+pub(crate) const TEST_WIDTH: u32 = 40;
+
+/// A builder to conveniently specify a single `BreakRule` or `JoinRule`.
+pub(crate) struct BreakRuleBuilder<'a> {
+    dsl: &'a mut WrapDsl,
+    rule_name: &'static str,
+    pattern: Option<Pattern>,
+}
+
+impl<'a> BreakRuleBuilder<'a> {
+    /// Rule applies to the nodes which match `pattern`.
+    pub(crate) fn inside(mut self, pattern: impl Into<Pattern>) -> Self {
+        let prev = self.pattern.replace(pattern.into());
+        assert!(prev.is_none());
+        self
+    }
+
+    /// Break before each direct child of the node which matches `child`.
+    pub(crate) fn break_before(self, child: impl Into<Pattern>) -> &'a mut WrapDsl {
+        let child = child.into();
+        self.break_before_each(move |node| {
+            node.children_with_tokens().filter(|it| child.matches(it)).collect()
+        })
+    }
+
+    /// Join the node onto a single line, if it fits.
+    pub(crate) fn join(self) -> &'a mut WrapDsl {
+        let name = self.rule_name;
+        let rule = JoinRule {
+            name: RuleName::new(name),
+            pattern: self.pattern.unwrap_or_else(|| panic!("incomplete rule: {}", name)),
+        };
+        self.dsl.joins.push(rule);
+        self.dsl
+    }
+
+    /// Break before each element returned by `break_points`.
+    pub(crate) fn break_before_each(
+        self,
+        break_points: impl Fn(&SyntaxNode) -> Vec<SyntaxElement> + Send + Sync + 'static,
+    ) -> &'a mut WrapDsl {
+        let name = self.rule_name;
+        let rule = BreakRule {
+            name: RuleName::new(name),
+            pattern: self.pattern.unwrap_or_else(|| panic!("incomplete rule: {}", name)),
+            break_points: Box::new(break_points),
+        };
+        self.dsl.rules.push(rule);
+        self.dsl
+    }
+}
+
+/// `LintRule` flags the code which the formatter doesn't fix, like lines which
+/// are too long. Lints never change the text.
+pub(crate) struct LintRule {
+    pub(crate) name: RuleName,
+    /// An element to which this rule applies.
+    pub(crate) pattern: Pattern,
+    /// The ranges to flag in the matching element.
+    pub(crate) ranges: LintRanges,
+}
+
+type LintRanges = Box<dyn Fn(&SyntaxElement) -> Vec<TextRange> + Send + Sync>;
+
+/// Make `LintRule` usable with `PatternSet`
+impl AsRef<Pattern> for LintRule {
+    fn as_ref(&self) -> &Pattern {
+        &self.pattern
+    }
+}
+
+impl fmt::Debug for LintRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LintRule").field("name", &self.name).finish()
+    }
+}
+
+/// A place flagged by a lint rule, see `lint`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LintWarning {
+    pub range: TextRange,
+    /// The name of the rule, as in `builtin_rules`.
+    pub rule: &'static str,
+}
+
+/// A builder to conveniently specify a set of `LintRule`s.
+#[derive(Debug, Default)]
+pub(crate) struct LintDsl {
+    pub(crate) rules: Vec<LintRule>,
+    #[cfg(test)]
+    pub(crate) tests: Vec<LintTest>,
+}
+
+/// An example for a `LintDsl`: `text` and the texts of the flagged ranges.
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct LintTest {
+    pub(crate) text: &'static str,
+    pub(crate) flagged: &'static [&'static str],
+}
+
+impl LintDsl {
+    /// Adds a new lint rule with the given name
+    pub(crate) fn rule<'a>(&'a mut self, rule_name: &'static str) -> LintRuleBuilder<'a> {
+        LintRuleBuilder { dsl: self, rule_name, pattern: None }
+    }
+    /// Inline tests are checked with the width of `TEST_WIDTH`.
+    pub(crate) fn test(
+        &mut self,
+        text: &'static str,
+        flagged: &'static [&'static str],
+    ) -> &mut LintDsl {
+        #[cfg(test)]
+        {
+            self.tests.push(LintTest { text, flagged });
+        }
+        let _ = (text, flagged);
+        self
+    }
+}
+
+/// A builder to conveniently specify a single `LintRule`.
+pub(crate) struct LintRuleBuilder<'a> {
+    dsl: &'a mut LintDsl,
+    rule_name: &'static str,
+    pattern: Option<Pattern>,
+}
+
+impl<'a> LintRuleBuilder<'a> {
+    /// Rule applies to the elements which match `pattern`, including
+    /// whitespace.
+    pub(crate) fn matching(mut self, pattern: impl Into<Pattern>) -> Self {
+        let prev = self.pattern.replace(pattern.into());
+        assert!(prev.is_none());
+        self
+    }
+
+    /// Flag the whole matching element.
+    pub(crate) fn warn(self) -> &'a mut LintDsl {
+        self.warn_at(|element| vec![element.text_range()])
+    }
+
+    /// Flag the ranges returned by `ranges`.
+    pub(crate) fn warn_at(
+        self,
+        ranges: impl Fn(&SyntaxElement) -> Vec<TextRange> + Send + Sync + 'static,
+    ) -> &'a mut LintDsl {
+        let name = self.rule_name;
+        let rule = LintRule {
+            name: RuleName::new(name),
+            pattern: self.pattern.unwrap_or_else(|| panic!("incomplete rule: {}", name)),
+            ranges: Box::new(ranges),
+        };
+        self.dsl.rules.push(rule);
+        self.dsl
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rnix::SyntaxKind::{
+        NODE_APPLY, NODE_ATTR_SET, NODE_IDENT, NODE_KEY, NODE_KEY_VALUE, NODE_LET_IN, NODE_LIST,
+        NODE_PAREN, TOKEN_ASSIGN, TOKEN_IN, TOKEN_SQUARE_B_OPEN,
+    };
+
+    use crate::{
+        is_first_child, is_last_child, with_text, Config, Formatter, FormatterBuilder, IndentDsl,
+        IndentValue, SpaceValue, SpacingDsl,
+    };
+
+    /// The built-in rules with the extra spacing rules of a test.
+    fn with_spacing(house_rules: fn(&mut SpacingDsl)) -> FormatterBuilder {
+        Formatter::builder().spacing_rules(house_rules)
+    }
+
+    /// Checks that `formatter` turns `before` into `after`, and leaves `after`
+    /// as is.
+    fn check(formatter: FormatterBuilder, before: &str, after: &str) {
+        let formatter = formatter.build();
+        assert_eq!(formatter.format(before), after);
+        assert_eq!(formatter.format(after), after, "formatting is not idempotent");
+    }
+
+    #[test]
+    fn reports_rule_conflicts() {
+        fn same_priority(dsl: &mut SpacingDsl) {
+            dsl.rule("No space after [")
+                .inside(NODE_LIST)
+                .after(TOKEN_SQUARE_B_OPEN)
+                .space(SpaceValue::None);
+        }
+        fn higher_priority(dsl: &mut SpacingDsl) {
+            dsl.rule("No space after [")
+                .inside(NODE_LIST)
+                .after(TOKEN_SQUARE_B_OPEN)
+                .priority(2)
+                .no_space();
+        }
+
+        let conflicts = with_spacing(same_priority).build().rule_conflicts("[1]");
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].rules, [None, Some("No space after [")]);
+        assert_eq!(conflicts[0].values, [SpaceValue::SingleOrNewline, SpaceValue::None]);
+        assert_eq!(conflicts[0].texts, [" ".to_string(), String::new()]);
+
+        assert_eq!(with_spacing(higher_priority).build().rule_conflicts("[1]"), vec![]);
+        check(with_spacing(higher_priority), "[1]", "[1 ]\n");
+    }
+
+    #[test]
+    fn lists_only_winning_rules() {
+        fn house_rules(dsl: &mut SpacingDsl) {
+            dsl.rule("Space after [")
+                .inside(NODE_LIST)
+                .after(TOKEN_SQUARE_B_OPEN)
+                .priority(2)
+                .single_space()
+                .rule("No space after [")
+                .inside(NODE_LIST)
+                .after(TOKEN_SQUARE_B_OPEN)
+                .priority(2)
+                .no_space();
+        }
+        let fired = with_spacing(house_rules).build().fired_rules("[1]");
+        assert!(fired.contains(&"No space after ["));
+        assert!(!fired.contains(&"Space after ["));
+    }
+
+    #[test]
+    fn matches_ancestors() {
+        fn house_rules(dsl: &mut SpacingDsl) {
+            dsl.rule("No space after [ in let")
+                .inside_any(&[NODE_LIST, NODE_PAREN])
+                .after(TOKEN_SQUARE_B_OPEN)
+                .within(NODE_LET_IN)
+                .priority(2)
+                .no_space();
+        }
+        check(
+            with_spacing(house_rules),
+            "[1 (let a=[{b=[1];}]; in a)]",
+            "[ 1 (let a = [{ b = [1 ]; }]; in a) ]\n",
+        );
+
+        // The ancestors may be different nodes.
+        fn nested_rules(dsl: &mut SpacingDsl) {
+            dsl.rule("No space after [ in a set in let")
+                .inside(NODE_LIST)
+                .after(TOKEN_SQUARE_B_OPEN)
+                .within(NODE_LET_IN)
+                .within(NODE_ATTR_SET)
+                .priority(2)
+                .no_space();
+        }
+        check(
+            with_spacing(nested_rules),
+            "let a=[{b=[1];}]; c=[2]; in a",
+            "let a = [{ b = [1 ]; }]; c = [ 2 ]; in a\n",
+        );
+    }
+
+    #[test]
+    fn limits_blank_lines() {
+        fn house_rules(dsl: &mut SpacingDsl) {
+            dsl.rule("At most one blank line between bindings")
+                .inside(NODE_ATTR_SET)
+                .before(NODE_KEY_VALUE)
+                .max_blank_lines(1)
+                .rule("Blank line before in")
+                .inside(NODE_LET_IN)
+                .before(TOKEN_IN)
+                .priority(2)
+                .blank_lines(1);
+        }
+        check(
+            with_spacing(house_rules),
+            "let\n  x = {\n    a = 1;\n\n\n\n    b = 2;\n    c = 3;\n  };\nin x",
+            "let\n  x = {\n    a = 1;\n\n    b = 2;\n    c = 3;\n  };\n\nin\nx\n",
+        );
+        // The lines keep their indent when nothing re-indents them.
+        check(
+            with_spacing(house_rules).indentation(false),
+            "{\n      a = 1;\n\n\n      b = 2;\n}",
+            "{\n      a = 1;\n\n      b = 2;\n}\n",
+        );
+    }
+
+    #[test]
+    fn forces_line_breaks() {
+        fn house_rules(dsl: &mut SpacingDsl) {
+            dsl.rule("Each binding on its own line")
+                .inside(NODE_LET_IN)
+                .before(NODE_KEY_VALUE)
+                .force_break()
+                .rule("in on its own line")
+                .inside(NODE_LET_IN)
+                .around(TOKEN_IN)
+                .force_break();
+        }
+        let expected = "let\n  a = 1;\n  b = 2;\nin\na + b\n";
+        check(with_spacing(house_rules), "let a = 1; b = 2; in a+b", expected);
+        // Joining the lines to fit into the width doesn't remove them either.
+        let config = Config { max_width: Some(80), ..Config::default() };
+        check(
+            with_spacing(house_rules).config(config),
+            "let\n  a = 1;\n  b = 2;\nin\na + b",
+            expected,
+        );
+    }
+
+    #[test]
+    fn glues_elements() {
+        fn house_rules(dsl: &mut SpacingDsl) {
+            dsl.rule("= on the line of the key").inside(NODE_KEY_VALUE).before(TOKEN_ASSIGN).glue();
+        }
+        check(
+            with_spacing(house_rules),
+            "{\n  a\n  = 1;\n  b # c\n  = 2;\n}",
+            "{\n  a = 1;\n  b # c\n    = 2;\n}\n",
+        );
+
+        // A forced line break is not glued.
+        fn let_rules(dsl: &mut SpacingDsl) {
+            dsl.rule("= on its own line in let")
+                .inside(NODE_KEY_VALUE)
+                .before(TOKEN_ASSIGN)
+                .within(NODE_LET_IN)
+                .force_break();
+        }
+        check(
+            with_spacing(house_rules).spacing_rules(let_rules),
+            "{ b\n= 2; c = let a\n= 1; in a; }",
+            "{\n  b = 2;\n  c =\n    let\n      a\n        = 1;\n    in\n    a;\n}\n",
+        );
+    }
+
+    #[test]
+    fn matches_first_and_last_children() {
+        fn house_rules(dsl: &mut SpacingDsl) {
+            dsl.rule("No blank line after {")
+                .inside(NODE_ATTR_SET)
+                .before(NODE_KEY_VALUE)
+                .when(is_first_child)
+                .max_blank_lines(0)
+                .rule("No blank line before }")
+                .inside(NODE_ATTR_SET)
+                .after(NODE_KEY_VALUE)
+                .when(is_last_child)
+                .max_blank_lines(0);
+        }
+        check(
+            with_spacing(house_rules),
+            "{\n\n  a = 1;\n\n  b = 2;\n\n}",
+            "{\n  a = 1;\n\n  b = 2;\n}\n",
+        );
+    }
+
+    #[test]
+    fn matches_token_text() {
+        fn house_rules(dsl: &mut SpacingDsl) {
+            dsl.rule("Import on one line")
+                .inside(NODE_APPLY)
+                .after(with_text(NODE_IDENT, "import"))
+                .priority(2)
+                .single_space();
+        }
+        check(
+            with_spacing(house_rules),
+            "[(f\n./a.nix) (import\n./b.nix)]",
+            "[\n  (f\n    ./a.nix)\n  (import ./b.nix)\n]\n",
+        );
+    }
+
+    #[test]
+    fn indents_relative_to_line_of_key() {
+        fn house_rules(dsl: &mut IndentDsl) {
+            dsl.rule("Indent arguments relative to the key")
+                .inside(NODE_APPLY)
+                .within(NODE_KEY_VALUE)
+                .relative_to(NODE_KEY)
+                .set(IndentValue::Indent);
+        }
+        let text = "{\n  foo =\n    bar\n    baz;\n}";
+        check(Formatter::builder(), text, "{\n  foo =\n    bar\n      baz;\n}\n");
+        check(
+            Formatter::builder().indentation_rules(house_rules),
+            text,
+            "{\n  foo =\n    bar\n    baz;\n}\n",
+        );
+    }
 }
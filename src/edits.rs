@@ -0,0 +1,347 @@
+//! Describes the formatting as a list of small edits, so that editors could
+//! apply it without moving the cursor and other marks around.
+use std::{fmt, iter::Peekable};
+
+use rnix::{
+    SyntaxKind::{self, TOKEN_WHITESPACE},
+    SyntaxToken, TextRange, TextSize,
+};
+
+use crate::tree_utils::walk_tokens;
+
+/// Replacement of the text in `range` of the input with `new_text`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextEdit {
+    pub range: TextRange,
+    pub new_text: String,
+}
+
+/// A token of the formatted text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputToken {
+    pub kind: SyntaxKind,
+    /// The range in the formatted text.
+    pub range: TextRange,
+    /// The range of the same token in the input. For whitespace, this is the
+    /// whitespace between the same tokens in the input. `None` if the token
+    /// was added by formatting.
+    pub origin: Option<TextRange>,
+}
+
+/// Applies non-overlapping `edits`, sorted by their ranges, to `text`.
+pub(crate) fn apply(text: &str, edits: &[TextEdit]) -> String {
+    let len = edits
+        .iter()
+        .fold(text.len(), |len, edit| len + edit.new_text.len() - usize::from(edit.range.len()));
+    let mut res = String::with_capacity(len);
+    let mut writer =
+        EditWriter::new(&mut res, edits.iter().map(|it| (it.range, it.new_text.as_str())));
+    // unwrap justification: writing to a `String` doesn't fail
+    writer.push(text).and_then(|()| writer.finish()).unwrap();
+    res
+}
+
+/// Writes a text, which comes in consecutive chunks, like the tokens of a
+/// syntax tree, with `edits` applied on the fly.
+///
+/// This is a piece table which is never stored: the unchanged parts of the
+/// chunks are copied straight to the output, so the original text is never
+/// assembled, and the output is written only once.
+pub(crate) struct EditWriter<'e, W, I: Iterator<Item = (TextRange, &'e str)>> {
+    out: W,
+    /// Non-overlapping replacements of the ranges of the original text,
+    /// sorted by their ranges.
+    edits: Peekable<I>,
+    /// The offset of the next chunk.
+    offset: TextSize,
+    /// The original text before this offset is written or deleted.
+    pos: TextSize,
+}
+
+impl<'e, W: fmt::Write, I: Iterator<Item = (TextRange, &'e str)>> EditWriter<'e, W, I> {
+    pub(crate) fn new(out: W, edits: I) -> EditWriter<'e, W, I> {
+        let start = TextSize::from(0);
+        EditWriter { out, edits: edits.peekable(), offset: start, pos: start }
+    }
+
+    /// Writes the next `chunk` of the original text.
+    pub(crate) fn push(&mut self, chunk: &str) -> fmt::Result {
+        let (start, end) = (self.offset, self.offset + TextSize::of(chunk));
+        self.offset = end;
+        let slice = |from: TextSize, to: TextSize| {
+            &chunk[usize::from(from - start)..usize::from(to - start)]
+        };
+        let mut pos = self.pos.max(start);
+        while let Some(&(range, insert)) = self.edits.peek().filter(|(it, _)| it.start() < end) {
+            if range.start() > pos {
+                self.out.write_str(slice(pos, range.start()))?;
+            }
+            self.out.write_str(insert)?;
+            pos = pos.max(range.end());
+            self.edits.next();
+        }
+        if pos < end {
+            self.out.write_str(slice(pos, end))?;
+        }
+        self.pos = pos;
+        Ok(())
+    }
+
+    /// Writes the insertions at the end of the original text.
+    pub(crate) fn finish(mut self) -> fmt::Result {
+        for (_range, insert) in self.edits.by_ref() {
+            self.out.write_str(insert)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether `edit` adds or removes a line break, or changes the indentation.
+pub(crate) fn changes_lines(text: &str, edit: &TextEdit) -> bool {
+    let is_space = |c: char| c == ' ' || c == '\t' || c == '\r' || c == '\n';
+    let start = text[..usize::from(edit.range.start())].trim_end_matches(is_space).len();
+    let end = text[usize::from(edit.range.end())..].trim_start_matches(is_space).len();
+    let whitespace = &text[start..text.len() - end];
+    whitespace.contains('\n') || edit.new_text.contains('\n')
+}
+
+/// Lists the tokens of `after`, the formatted `before`, with their origins.
+pub(crate) fn output_tokens(before: &str, after: &str) -> Vec<OutputToken> {
+    let old_tokens = significant_tokens(before);
+    let new_root = rnix::parse(after).node();
+    let new_count = walk_tokens(&new_root).filter(|it| it.kind() != TOKEN_WHITESPACE).count();
+    let matches = old_tokens.len() == new_count
+        && walk_tokens(&new_root)
+            .filter(|it| it.kind() != TOKEN_WHITESPACE)
+            .zip(old_tokens.iter())
+            .all(|(new, old)| new.kind() == old.kind());
+    let mut res = Vec::new();
+    let mut idx = 0usize;
+    for token in walk_tokens(&new_root) {
+        let origin = if !matches {
+            None
+        } else if token.kind() == TOKEN_WHITESPACE {
+            let start = idx.checked_sub(1).map_or(0.into(), |it| old_tokens[it].text_range().end());
+            let end =
+                old_tokens.get(idx).map_or(TextSize::of(before), |it| it.text_range().start());
+            Some(TextRange::new(start, end)).filter(|it| !it.is_empty())
+        } else {
+            idx += 1;
+            Some(old_tokens[idx - 1].text_range())
+        };
+        res.push(OutputToken { kind: token.kind(), range: token.text_range(), origin });
+    }
+    res
+}
+
+/// Translates the offsets in the input of formatting to the offsets in the
+/// output, for example, to keep the cursor on the same token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceMap {
+    edits: Vec<TextEdit>,
+}
+
+impl SourceMap {
+    pub(crate) fn new(edits: Vec<TextEdit>) -> SourceMap {
+        SourceMap { edits }
+    }
+
+    /// Maps a byte offset in the input to the corresponding offset in the
+    /// output. An offset inside of replaced text is moved by at most the
+    /// length of the replacement, so it stays inside of it.
+    pub fn map_offset(&self, offset: TextSize) -> TextSize {
+        let mut res = offset;
+        for edit in self.edits.iter() {
+            let new_len = TextSize::of(edit.new_text.as_str());
+            if offset < edit.range.start() {
+                break;
+            }
+            if offset < edit.range.end() {
+                let start = res - (offset - edit.range.start());
+                return start + (offset - edit.range.start()).min(new_len);
+            }
+            res = res + new_len - edit.range.len();
+        }
+        res
+    }
+}
+
+/// Computes the edits which turn `before` into `after`.
+///
+/// Formatting mostly changes the whitespace between tokens, so the tokens of
+/// both texts are matched one to one, and only the differing whitespace and
+/// tokens are replaced. If the tokens don't match, the whole changed part of
+/// the text is replaced in one edit.
+pub(crate) fn diff(before: &str, after: &str) -> Vec<TextEdit> {
+    if before == after {
+        return Vec::new();
+    }
+    let old_tokens = significant_tokens(before);
+    let new_tokens = significant_tokens(after);
+    let matches = old_tokens.len() == new_tokens.len()
+        && old_tokens.iter().zip(new_tokens.iter()).all(|(old, new)| old.kind() == new.kind());
+    if !matches {
+        return minimal_edit(before, after, TextSize::from(0)).into_iter().collect();
+    }
+    let mut res = Vec::new();
+    let (mut old_prev, mut new_prev) = (TextSize::from(0), TextSize::from(0));
+    for (old, new) in old_tokens.iter().zip(new_tokens.iter()) {
+        let (old_range, new_range) = (old.text_range(), new.text_range());
+        // The whitespace before the token, and then the token itself.
+        let gaps = [
+            (
+                TextRange::new(old_prev, old_range.start()),
+                TextRange::new(new_prev, new_range.start()),
+            ),
+            (old_range, new_range),
+        ];
+        for &(old_gap, new_gap) in gaps.iter() {
+            let edit = minimal_edit(&before[old_gap], &after[new_gap], old_gap.start());
+            res.extend(edit);
+        }
+        old_prev = old_range.end();
+        new_prev = new_range.end();
+    }
+    let old_rest = TextRange::new(old_prev, TextSize::of(before));
+    let new_rest = TextRange::new(new_prev, TextSize::of(after));
+    res.extend(minimal_edit(&before[old_rest], &after[new_rest], old_rest.start()));
+    res
+}
+
+/// Expands `range` to whole lines, and to whole tokens, so that a multiline
+/// string or comment is formatted either completely or not at all.
+pub(crate) fn expand_range(text: &str, range: TextRange) -> TextRange {
+    let len = TextSize::of(text);
+    let range = TextRange::new(range.start().min(len), range.end().min(len));
+    let root = rnix::parse(text).node();
+    let mut res = range;
+    for &offset in [range.start(), range.end()].iter() {
+        for token in root.token_at_offset(offset) {
+            if token.kind() != TOKEN_WHITESPACE {
+                res = res.cover(token.text_range())
+            }
+        }
+    }
+    let start = text[..usize::from(res.start())].rfind('\n').map_or(0, |it| it + 1);
+    let end = text[usize::from(res.end())..]
+        .find('\n')
+        .map_or(text.len(), |it| it + usize::from(res.end()));
+    TextRange::new(TextSize::from(start as u32), TextSize::from(end as u32))
+}
+
+fn significant_tokens(text: &str) -> Vec<SyntaxToken> {
+    walk_tokens(&rnix::parse(text).node()).filter(|it| it.kind() != TOKEN_WHITESPACE).collect()
+}
+
+/// Replaces `before`, which starts at `offset` in the input, with `after`,
+/// leaving out their common prefix and suffix.
+fn minimal_edit(before: &str, after: &str, offset: TextSize) -> Option<TextEdit> {
+    if before == after {
+        return None;
+    }
+    let prefix = common_prefix_len(before.chars(), after.chars());
+    let (before_rest, after_rest) = (&before[prefix..], &after[prefix..]);
+    let suffix = common_prefix_len(before_rest.chars().rev(), after_rest.chars().rev());
+    let removed = &before_rest[..before_rest.len() - suffix];
+    let start = offset + TextSize::of(&before[..prefix]);
+    Some(TextEdit {
+        range: TextRange::at(start, TextSize::of(removed)),
+        new_text: after_rest[..after_rest.len() - suffix].to_string(),
+    })
+}
+
+/// Length in bytes of the common prefix of two sequences of chars.
+fn common_prefix_len(a: impl Iterator<Item = char>, b: impl Iterator<Item = char>) -> usize {
+    a.zip(b).take_while(|(a, b)| a == b).map(|(a, _)| a.len_utf8()).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edits_only_whitespace() {
+        let before = "{\n    a =1;\n}";
+        let after = "{\n  a = 1;\n}\n";
+        let edits = diff(before, after);
+        assert_eq!(
+            edits,
+            vec![
+                TextEdit { range: TextRange::new(4.into(), 6.into()), new_text: String::new() },
+                TextEdit { range: TextRange::empty(9.into()), new_text: " ".to_string() },
+                TextEdit { range: TextRange::empty(13.into()), new_text: "\n".to_string() },
+            ]
+        );
+        assert_eq!(apply(before, &edits), after);
+    }
+
+    #[test]
+    fn applies_edits_to_chunks() {
+        let edits = [
+            (TextRange::new(1.into(), 4.into()), "x"),
+            (TextRange::empty(5.into()), "y"),
+            (TextRange::empty(7.into()), "z"),
+        ];
+        let mut res = String::new();
+        let mut writer = EditWriter::new(&mut res, edits.iter().copied());
+        for chunk in ["ab", "cd", "e", "", "fg"].iter() {
+            writer.push(chunk).unwrap();
+        }
+        writer.finish().unwrap();
+        assert_eq!(res, "axeyfgz");
+    }
+
+    #[test]
+    fn expands_range_to_lines_and_tokens() {
+        let text = "{\n  a = 1;\n  b = ''\n    x\n  '';\n}\n";
+        let range = |start: u32, end: u32| TextRange::new(start.into(), end.into());
+        assert_eq!(expand_range(text, range(6, 6)), range(2, 10));
+        assert_eq!(expand_range(text, range(23, 24)), range(11, 31));
+    }
+
+    #[test]
+    fn maps_offsets() {
+        let before = "{\n    a =1;\n}";
+        let map = SourceMap::new(diff(before, "{\n  a = 1;\n}\n"));
+        let mapped: Vec<u32> =
+            [0, 3, 5, 6, 8, 9, 13].iter().map(|&it| map.map_offset(it.into()).into()).collect();
+        assert_eq!(mapped, vec![0, 3, 4, 4, 6, 8, 13]);
+    }
+
+    #[test]
+    fn lists_output_tokens() {
+        let tokens = output_tokens("[1  2]", "[ 1 2 ]\n");
+        let tokens: Vec<(SyntaxKind, u32, Option<u32>)> = tokens
+            .into_iter()
+            .map(|it| (it.kind, it.range.start().into(), it.origin.map(|it| it.start().into())))
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                (SyntaxKind::TOKEN_SQUARE_B_OPEN, 0, Some(0)),
+                (TOKEN_WHITESPACE, 1, None),
+                (SyntaxKind::TOKEN_INTEGER, 2, Some(1)),
+                (TOKEN_WHITESPACE, 3, Some(2)),
+                (SyntaxKind::TOKEN_INTEGER, 4, Some(4)),
+                (TOKEN_WHITESPACE, 5, None),
+                (SyntaxKind::TOKEN_SQUARE_B_CLOSE, 6, Some(5)),
+                (TOKEN_WHITESPACE, 7, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn falls_back_to_a_single_edit() {
+        let before = "[ a b ]";
+        let after = "[ a (b) ]";
+        let edits = diff(before, after);
+        assert_eq!(
+            edits,
+            vec![TextEdit {
+                range: TextRange::new(4.into(), 5.into()),
+                new_text: "(b)".to_string()
+            }]
+        );
+        assert_eq!(apply(before, &edits), after);
+    }
+}
@@ -1,44 +1,148 @@
 //! This module applies the rules from `super::dsl` to a `SyntaxNode`, to
 //! get a `FmtDiff`.
+mod align;
 mod fmt_model;
 mod indentation;
+mod lint;
 mod spacing;
 mod fixes;
+mod protected;
+mod reflow;
+mod wrapping;
 
-use rnix::{SyntaxNode, TextRange};
+use rnix::{SyntaxKind::TOKEN_COMMENT, SyntaxNode, TextRange};
 use smol_str::SmolStr;
 
+pub(crate) use self::{lint::lint, protected::protected_ranges};
+
 use crate::{
-    dsl::{IndentDsl, RuleName, SpacingDsl},
+    config::Config,
+    dsl::{IndentDsl, RuleConflict, RuleName, SpacingDsl, SpacingRule, WrapDsl},
     engine::fmt_model::{BlockPosition, FmtModel, SpaceBlock, SpaceBlockOrToken},
+    error::FmtError,
+    formatter::NodeHook,
     pattern::PatternSet,
-    tree_utils::walk_non_whitespace_non_interpol,
+    tree_utils::walk_formattable,
     AtomEdit, FmtDiff,
 };
 
-/// The main entry point for formatting
+/// Joining and breaking lines normally converges after a couple of rounds,
+/// this is just a safety net.
+const MAX_LAYOUT_ROUNDS: usize = 8;
+
+/// The main entry point for formatting. Without `indent_dsl`, the indentation
+/// is left as is. All the edits are passed through the `hook`, if any.
 pub(crate) fn reformat(
     spacing_dsl: &SpacingDsl,
-    indent_dsl: &IndentDsl,
+    indent_dsl: Option<&IndentDsl>,
+    wrap_dsl: &WrapDsl,
+    config: &Config,
+    hook: Option<&dyn NodeHook>,
     node: &SyntaxNode,
     // Passing optional reference is just a cute type-safe way for the caller to
     // decide if they need explanation.
-    mut explanation: Option<&mut Vec<(AtomEdit, Option<RuleName>)>>,
-) -> SyntaxNode {
-    // First, adjust spacing rules between the nodes.
-    // This can force some newlines.
-    let mut model = FmtModel::new(node.clone());
+    explanation: Option<&mut Vec<(AtomEdit, Option<RuleName>)>>,
+) -> Result<SyntaxNode, FmtError> {
+    let max_width = match config.max_width {
+        Some(it) => it,
+        None => return layout(spacing_dsl, indent_dsl, config, hook, node, explanation),
+    };
+    wrap_layout(spacing_dsl, indent_dsl, wrap_dsl, config, max_width, hook, node, &mut Vec::new())
+}
 
-    // First, adjust spacing rules between the nodes.
-    // This can force some newlines.
-    let spacing_rule_set = PatternSet::new(spacing_dsl.rules.iter());
-    for element in walk_non_whitespace_non_interpol(node) {
-        for rule in spacing_rule_set.matching(element.clone()) {
-            rule.apply(&element, &mut model)
+/// Lays `node` out within `max_width`, and adds the wrapping rules which break
+/// or join some lines to `fired`.
+#[allow(clippy::too_many_arguments)]
+fn wrap_layout(
+    spacing_dsl: &SpacingDsl,
+    indent_dsl: Option<&IndentDsl>,
+    wrap_dsl: &WrapDsl,
+    config: &Config,
+    max_width: u32,
+    hook: Option<&dyn NodeHook>,
+    node: &SyntaxNode,
+    fired: &mut Vec<RuleName>,
+) -> Result<SyntaxNode, FmtError> {
+    // Lines are joined and broken by laying the whole tree out over and over,
+    // as only then we know how long they are.
+    let mut relayout =
+        |node: &SyntaxNode| layout(spacing_dsl, indent_dsl, config, hook, node, None);
+    if !config.canonical_layout {
+        let node = relayout(node)?;
+        return wrapping::wrap(wrap_dsl, max_width, hook, node, &mut relayout, fired);
+    }
+    // Breaking a line can make some other node fit, so we repeat until
+    // nothing changes, to make sure that the formatting is idempotent.
+    let mut node = node.clone();
+    let mut prev = None;
+    for _ in 0..MAX_LAYOUT_ROUNDS {
+        let joined = wrapping::join(wrap_dsl, max_width, hook, node, &mut relayout, fired)?;
+        let wrapped = wrapping::wrap(wrap_dsl, max_width, hook, joined, &mut relayout, fired)?;
+        let text = wrapped.to_string();
+        node = wrapped;
+        if prev.as_ref() == Some(&text) {
+            break;
         }
+        prev = Some(text);
     }
+    Ok(node)
+}
+
+/// Like `reformat`, but leaves the edits of the last phase unapplied, so that
+/// the caller could write them out without building the formatted tree.
+pub(crate) fn reformat_diff(
+    spacing_dsl: &SpacingDsl,
+    indent_dsl: Option<&IndentDsl>,
+    wrap_dsl: &WrapDsl,
+    config: &Config,
+    hook: Option<&dyn NodeHook>,
+    node: &SyntaxNode,
+) -> Result<FmtDiff, FmtError> {
+    if config.max_width.is_some() {
+        let node = reformat(spacing_dsl, indent_dsl, wrap_dsl, config, hook, node, None)?;
+        return Ok(FmtDiff { original_node: node, edits: Vec::new() });
+    }
+    layout_diff(spacing_dsl, indent_dsl, config, hook, node, None)
+}
+
+/// Checks whether `reformat` would leave the `node` as is.
+///
+/// A phase may change the text only for a later one to change it back, so
+/// this looks at the text after the last phase, without building its tree.
+pub(crate) fn is_formatted(
+    spacing_dsl: &SpacingDsl,
+    indent_dsl: Option<&IndentDsl>,
+    wrap_dsl: &WrapDsl,
+    config: &Config,
+    hook: Option<&dyn NodeHook>,
+    node: &SyntaxNode,
+) -> Result<bool, FmtError> {
+    let diff = reformat_diff(spacing_dsl, indent_dsl, wrap_dsl, config, hook, node)?;
+    Ok(node.text() == diff.to_string().as_str())
+}
 
-    let spacing_diff = model.into_diff();
+/// Applies spacing, indentation and other local rules.
+fn layout(
+    spacing_dsl: &SpacingDsl,
+    indent_dsl: Option<&IndentDsl>,
+    config: &Config,
+    hook: Option<&dyn NodeHook>,
+    node: &SyntaxNode,
+    explanation: Option<&mut Vec<(AtomEdit, Option<RuleName>)>>,
+) -> Result<SyntaxNode, FmtError> {
+    Ok(layout_diff(spacing_dsl, indent_dsl, config, hook, node, explanation)?.to_node())
+}
+
+/// Like `layout`, but returns the edits of the last phase unapplied.
+fn layout_diff(
+    spacing_dsl: &SpacingDsl,
+    indent_dsl: Option<&IndentDsl>,
+    config: &Config,
+    hook: Option<&dyn NodeHook>,
+    node: &SyntaxNode,
+    mut explanation: Option<&mut Vec<(AtomEdit, Option<RuleName>)>>,
+) -> Result<FmtDiff, FmtError> {
+    let spacing_diff = apply_spacing(spacing_dsl, hook, node);
     if let Some(explanation) = &mut explanation {
         if spacing_diff.has_changes() {
             explanation.extend(spacing_diff.edits.clone())
@@ -46,11 +150,112 @@ pub(crate) fn reformat(
     }
     let node = spacing_diff.to_node();
 
-    // Next, for each node which starts the newline, adjust the indent.
+    let indent_diff = match indent_dsl {
+        Some(indent_dsl) => apply_indentation(indent_dsl, hook, &node)?,
+        None => FmtDiff { original_node: node, edits: Vec::new() },
+    };
+    if let Some(explanation) = explanation {
+        // We don't add indentation explanations if we had whitespace changes,
+        // as that'll require fixing up the original ranges. This could be done,
+        // but it's not clear if it is really necessary.
+        if indent_diff.has_changes() && explanation.is_empty() {
+            explanation.extend(indent_diff.edits.clone())
+        }
+    }
+    if config.comment_width.is_none() && !config.align_trailing_comments {
+        return Ok(indent_diff);
+    }
+    let mut node = indent_diff.to_node();
+    if let Some(comment_width) = config.comment_width {
+        node = reflow::reflow_comments(&node, comment_width, hook)
+    }
+    if config.align_trailing_comments {
+        node = align::align_trailing_comments(&node, hook)
+    }
+    Ok(FmtDiff { original_node: node, edits: Vec::new() })
+}
+
+/// Adjusts spacing between the nodes. This can force some newlines.
+fn apply_spacing(
+    spacing_dsl: &SpacingDsl,
+    hook: Option<&dyn NodeHook>,
+    node: &SyntaxNode,
+) -> FmtDiff {
+    spacing_model(spacing_dsl, node, false).into_diff(hook)
+}
+
+/// Applies the spacing rules like `apply_spacing`, and returns the rules which
+/// disagreed.
+pub(crate) fn spacing_conflicts(spacing_dsl: &SpacingDsl, node: &SyntaxNode) -> Vec<RuleConflict> {
+    spacing_model(spacing_dsl, node, false).conflicts
+}
+
+/// Lists the spacing and indentation rules which decide some whitespace in
+/// `node`. A rule which is overridden by another one doesn't count.
+pub(crate) fn fired_rules(
+    spacing_dsl: &SpacingDsl,
+    indent_dsl: Option<&IndentDsl>,
+    wrap_dsl: &WrapDsl,
+    config: &Config,
+    node: &SyntaxNode,
+) -> Result<Vec<RuleName>, FmtError> {
+    let mut model = spacing_model(spacing_dsl, node, true);
+    let mut res = model.fired.take().unwrap_or_default();
+    if let Some(indent_dsl) = indent_dsl {
+        let spaced = model.into_diff(None).to_node();
+        let model = indentation_model(indent_dsl, &spaced, true)?;
+        res.extend(model.fired.unwrap_or_default());
+    }
+    if let Some(max_width) = config.max_width {
+        wrap_layout(spacing_dsl, indent_dsl, wrap_dsl, config, max_width, None, node, &mut res)?;
+    }
+    Ok(res)
+}
+
+/// With `record_fired`, the model lists the rules which were applied.
+fn spacing_model(spacing_dsl: &SpacingDsl, node: &SyntaxNode, record_fired: bool) -> FmtModel {
     let mut model = FmtModel::new(node.clone());
+    if record_fired {
+        model.fired = Some(Vec::new());
+    }
+    // Limits on the line breaks are applied last, to what the other rules
+    // produced.
+    let (limits, rules): (Vec<&SpacingRule>, Vec<&SpacingRule>) =
+        spacing_dsl.rules.iter().partition(|it| it.is_limit());
+    for rules in [rules, limits].iter().filter(|it| !it.is_empty()) {
+        let spacing_rule_set = PatternSet::new(rules.iter());
+        for element in walk_formattable(node) {
+            for rule in spacing_rule_set.matching(element.clone()) {
+                rule.apply(&element, &mut model)
+            }
+        }
+    }
+    // Only now it's known which rule won each block.
+    model.record_claims();
+    model
+}
+
+/// For each node which starts the newline, adjusts the indent.
+fn apply_indentation(
+    indent_dsl: &IndentDsl,
+    hook: Option<&dyn NodeHook>,
+    node: &SyntaxNode,
+) -> Result<FmtDiff, FmtError> {
+    Ok(indentation_model(indent_dsl, node, false)?.into_diff(hook))
+}
+
+fn indentation_model(
+    indent_dsl: &IndentDsl,
+    node: &SyntaxNode,
+    record_fired: bool,
+) -> Result<FmtModel, FmtError> {
+    let mut model = FmtModel::new(node.clone());
+    if record_fired {
+        model.fired = Some(Vec::new());
+    }
 
     let anchor_set = PatternSet::new(indent_dsl.anchors.iter());
-    for element in walk_non_whitespace_non_interpol(&node) {
+    for element in walk_formattable(node) {
         let block = model.block_for(&element, BlockPosition::Before);
         if !block.has_newline() {
             // No need to indent an element if it doesn't start a line
@@ -70,31 +275,39 @@ pub(crate) fn reformat(
             continue;
         }
 
+        let extra = indent_dsl.extra_rules.iter().rev().find(|it| it.matches(&element));
         let mut matching = indent_dsl.rules.iter().filter(|it| it.matches(&element));
-        if let Some(rule) = matching.next() {
-            rule.apply(&element, &mut model, &anchor_set);
-            assert!(matching.next().is_none(), "more that one indent rule matched");
+        if let Some(rule) = extra {
+            rule.apply(&element, &mut model, &anchor_set, indent_dsl.continuation_indent);
+        } else if let Some(rule) = matching.next() {
+            if let Some(other) = matching.next() {
+                return Err(FmtError::Internal(format!(
+                    "indent rules {:?} and {:?} both match at {:?}",
+                    rule.name.as_str(),
+                    other.name.as_str(),
+                    element.text_range(),
+                )));
+            }
+            rule.apply(&element, &mut model, &anchor_set, indent_dsl.continuation_indent);
         } else {
             indentation::default_indent(&element, &mut model, &anchor_set)
         }
     }
 
+    // Comments are indented together with the code they describe.
+    for element in walk_formattable(node) {
+        if let Some(comment) = element.as_token().filter(|it| it.kind() == TOKEN_COMMENT) {
+            indentation::indent_leading_comment(comment, &mut model)
+        }
+    }
+
     // Finally, do custom touch-ups like re-indenting of string literals and
     // replacing URLs with string literals.
-    for element in walk_non_whitespace_non_interpol(&node) {
+    for element in walk_formattable(node) {
         fixes::fix(element, &mut model, &anchor_set)
     }
 
-    let indent_diff = model.into_diff();
-    if let Some(explanation) = explanation {
-        // We don't add indentation explanations if we had whitespace changes,
-        // as that'll require fixing up the original ranges. This could be done,
-        // but it's not clear if it is really necessary.
-        if indent_diff.has_changes() && explanation.is_empty() {
-            explanation.extend(indent_diff.edits.clone())
-        }
-    }
-    indent_diff.to_node()
+    Ok(model)
 }
 
 impl FmtDiff {
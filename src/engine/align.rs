@@ -0,0 +1,81 @@
+//! Aligns the trailing comments of consecutive lines to a common column, if
+//! `Config::align_trailing_comments` is set.
+use rnix::{
+    SyntaxKind::{TOKEN_COMMENT, TOKEN_WHITESPACE},
+    SyntaxNode, TextRange,
+};
+
+use crate::{
+    comments::{self, Attachment},
+    engine::wrapping::line_width,
+    formatter::NodeHook,
+    AtomEdit, FmtDiff,
+};
+
+struct TrailingComment {
+    line: usize,
+    /// The whitespace between the code and the comment.
+    space: TextRange,
+    /// Width of the code before the comment.
+    code_width: u32,
+}
+
+pub(super) fn align_trailing_comments(
+    root: &SyntaxNode,
+    hook: Option<&dyn NodeHook>,
+) -> SyntaxNode {
+    let comments = trailing_comments(root);
+    let mut edits = Vec::new();
+    for run in runs(&comments) {
+        if run.len() < 2 {
+            continue;
+        }
+        let column = run.iter().map(|it| it.code_width).max().unwrap_or(0) + 1;
+        for comment in run {
+            let insert = " ".repeat((column - comment.code_width) as usize);
+            edits.push((AtomEdit { delete: comment.space, insert: insert.into() }, None));
+        }
+    }
+    FmtDiff { original_node: root.clone(), edits }.without_vetoed_edits(hook).to_node()
+}
+
+fn trailing_comments(root: &SyntaxNode) -> Vec<TrailingComment> {
+    let text = root.to_string();
+    let line_starts: Vec<usize> =
+        std::iter::once(0).chain(text.match_indices('\n').map(|(idx, _)| idx + 1)).collect();
+    let mut res = Vec::new();
+    let comments = root
+        .descendants_with_tokens()
+        .filter_map(|it| it.into_token())
+        .filter(|it| it.kind() == TOKEN_COMMENT && it.text().starts_with('#'));
+    for comment in comments {
+        if !matches!(comments::attachment(&comment), Attachment::Trailing(_)) {
+            continue;
+        }
+        let space = match comment.prev_token() {
+            Some(ws) if ws.kind() == TOKEN_WHITESPACE => ws.text_range(),
+            _ => TextRange::empty(comment.text_range().start()),
+        };
+        let code_end = usize::from(space.start());
+        let line = match line_starts.binary_search(&code_end) {
+            Ok(it) => it,
+            Err(it) => it - 1,
+        };
+        let code_width = line_width(&text[line_starts[line]..code_end]);
+        res.push(TrailingComment { line, space, code_width })
+    }
+    res
+}
+
+/// Splits the comments into the runs on consecutive lines.
+fn runs(comments: &[TrailingComment]) -> Vec<&[TrailingComment]> {
+    let mut res = Vec::new();
+    let mut start = 0;
+    for idx in 1..=comments.len() {
+        if idx == comments.len() || comments[idx].line != comments[idx - 1].line + 1 {
+            res.push(&comments[start..idx]);
+            start = idx;
+        }
+    }
+    res
+}
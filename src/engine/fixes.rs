@@ -1,8 +1,11 @@
-use std::{cmp::min, convert::TryFrom};
+use std::{cmp::min, collections::BTreeMap, iter::successors};
 
 use rnix::{
     NodeOrToken, SyntaxElement,
-    SyntaxKind::{NODE_STRING, TOKEN_COMMENT, TOKEN_STRING_CONTENT, TOKEN_WHITESPACE},
+    SyntaxKind::{
+        NODE_ATTR_SET, NODE_LET_IN, NODE_LIST, NODE_PAREN, NODE_STRING, NODE_STRING_INTERPOL,
+        TOKEN_COMMENT, TOKEN_IN, TOKEN_STRING_CONTENT, TOKEN_WHITESPACE,
+    },
     SyntaxNode, SyntaxToken, TextRange, TextSize,
 };
 
@@ -25,6 +28,7 @@ pub(super) fn fix(element: SyntaxElement, model: &mut FmtModel, anchor_set: &Pat
         }
         NodeOrToken::Token(token) => {
             if let TOKEN_COMMENT = token.kind() {
+                fix_comment_spacing(&token, model);
                 fix_comment_indentation(&token, model, anchor_set)
             }
         }
@@ -49,13 +53,96 @@ fn fix_string_indentation(
         }
     };
     let content_indent = quote_indent.indent();
+    let literal_lines = match string_line_indents(node, content_indent, quote_indent) {
+        Some(it) => it,
+        None => return,
+    };
 
-    let indent_ranges: Vec<TextRange> = node_indent_ranges(node).collect();
+    // Lay out the lines of multi-line interpolations, going down the string.
+    // Each line is indented relative to the (possibly shifted) line on which
+    // the innermost bracket around it is opened. The new indent of each line
+    // is recorded by the offset of the line.
+    let mut lines: BTreeMap<TextSize, TextSize> = BTreeMap::new();
+    for (range, new_len) in literal_lines {
+        if new_len != range.len() {
+            model
+                .raw_edit(AtomEdit { delete: range, insert: IndentLevel::from_len(new_len).into() })
+        }
+        lines.insert(range.start(), new_len);
+    }
+    // The new indents of the lines of the `''` strings inside of the
+    // interpolations, which are computed once the line on which a string
+    // starts is known.
+    let mut nested_lines: BTreeMap<TextSize, TextSize> = BTreeMap::new();
+    let mut nested_strings: Vec<SyntaxNode> = Vec::new();
+    let interpolated_tokens = node
+        .descendants_with_tokens()
+        .filter_map(|it| it.into_token())
+        .filter(|it| it.parent() != *node);
+    for token in interpolated_tokens {
+        let is_nested_string = token.kind() == TOKEN_STRING_CONTENT && !is_quoted(&token);
+        if is_nested_string && !nested_strings.contains(&token.parent()) {
+            let string = token.parent();
+            if let Some(base) = line_at(&lines, string.text_range().start()) {
+                let base = IndentLevel::from_len(base);
+                let string_lines = string_line_indents(&string, base.indent(), base);
+                let string_lines = string_lines.into_iter().flatten();
+                nested_lines.extend(string_lines.map(|(range, new_len)| (range.start(), new_len)));
+            }
+            nested_strings.push(string);
+        }
+        let text = token.text();
+        let mut line_starts = text.match_indices('\n').map(|(idx, _)| idx + 1).peekable();
+        while let Some(idx) = line_starts.next() {
+            let line = &text[idx..];
+            let start = token.text_range().start() + TextSize::of(&text[..idx]);
+            let indent = TextSize::of(line) - TextSize::of(line.trim_start_matches(' '));
+            let range = TextRange::at(start, indent);
+            let new_len = match token.kind() {
+                // Only the last line break of a whitespace starts a line which
+                // isn't blank.
+                TOKEN_WHITESPACE if line_starts.peek().is_none() => {
+                    interpolated_line_indent(&token, &lines)
+                }
+                // The `"` strings are left as is.
+                TOKEN_STRING_CONTENT if is_nested_string => nested_lines.get(&start).copied(),
+                _ => None,
+            };
+            match new_len {
+                Some(new_len) => {
+                    if new_len != range.len() {
+                        let insert = IndentLevel::from_len(new_len).into();
+                        model.raw_edit(AtomEdit { delete: range, insert })
+                    }
+                    lines.insert(start, new_len);
+                }
+                // The lines which are left as is, like the ones inside of `"`
+                // strings and comments, are indented like the line on which
+                // the token starts, as far as the code on them is concerned.
+                None => {
+                    let first_line = line_at(&lines, token.text_range().start());
+                    lines.extend(first_line.map(|it| (start, it)));
+                }
+            }
+        }
+    }
+}
 
-    let (first_indent, last_indent) = match (indent_ranges.first(), indent_ranges.last()) {
-        (Some(first), Some(last)) => (first, last),
-        _ => return,
-    };
+/// Computes the new indents of the literal lines of an indented string: the
+/// content is shifted to `content_indent`, keeping its relative indentation,
+/// and the closing `''` on a line of its own is put at `quote_indent`.
+///
+/// Returns `None` if the string doesn't start with a line break, or if the
+/// shift would change the value of the string.
+fn string_line_indents(
+    node: &SyntaxNode,
+    content_indent: IndentLevel,
+    quote_indent: IndentLevel,
+) -> Option<Vec<(TextRange, TextSize)>> {
+    // Only the literal lines of the string determine its common indentation.
+    // Lines inside of `${ ... }` are code and are handled separately.
+    let indent_ranges: Vec<TextRange> = literal_indent_ranges(node).collect();
+    let (first_indent, last_indent) = (indent_ranges.first()?, indent_ranges.last()?);
 
     let first_line_is_blank =
         first_indent.start() == node.text_range().start() + TextSize::of("''\n");
@@ -63,30 +150,114 @@ fn fix_string_indentation(
     let last_line_is_blank = last_indent.end() + TextSize::of("''") == node.text_range().end();
 
     if !first_line_is_blank {
-        return;
+        return None;
     }
 
     let content_ranges =
         if last_line_is_blank { &indent_ranges[..indent_ranges.len() - 1] } else { &indent_ranges };
 
-    let common_indent = match content_ranges.iter().map(|it| it.len()).min() {
-        Some(it) => it,
-        None => return,
-    };
-
-    if content_indent != IndentLevel::from_len(common_indent) {
+    let mut res = Vec::new();
+    // Without content, there is only the closing quote to move.
+    if let Some(common_indent) = content_ranges.iter().map(|it| it.len()).min() {
         for &range in content_ranges.iter() {
-            let delete = TextRange::at(range.start(), min(common_indent, range.len()));
-            model.raw_edit(AtomEdit { delete, insert: content_indent.into() })
+            res.push((range, content_indent.len() + range.len() - common_indent))
         }
+        // Whitespace-only lines don't affect the common indentation, but nix
+        // keeps the spaces in excess of it, so they have to be shifted too.
+        res.extend(literal_blank_line_ranges(node).into_iter().filter_map(|range| {
+            let new_len = if range.len() > common_indent {
+                content_indent.len() + range.len() - common_indent
+            } else if range.len() > content_indent.len() {
+                0.into()
+            } else {
+                return None;
+            };
+            Some((range, new_len))
+        }));
+    }
+    if last_line_is_blank {
+        res.push((*last_indent, quote_indent.len()))
+    }
+
+    // Check that the edits to the literal text don't change the value of the
+    // string.
+    let edits: Vec<AtomEdit> = res
+        .iter()
+        .map(|&(delete, new_len)| AtomEdit {
+            delete,
+            insert: IndentLevel::from_len(new_len).into(),
+        })
+        .collect();
+    if indented_string_value(node, &[]) != indented_string_value(node, &edits) {
+        return None;
     }
+    Some(res)
+}
+
+/// The new indent of a line inside of an interpolation, which starts after
+/// the `whitespace`: one level more than the line on which the innermost
+/// bracket around it is opened, or the same, if the line starts with the
+/// closing bracket.
+fn interpolated_line_indent(
+    whitespace: &SyntaxToken,
+    lines: &BTreeMap<TextSize, TextSize>,
+) -> Option<TextSize> {
+    let first = whitespace.next_token()?;
+    let bracket = first.parent().ancestors().find(|it| {
+        let is_bracket = matches!(
+            it.kind(),
+            NODE_STRING_INTERPOL | NODE_LIST | NODE_ATTR_SET | NODE_PAREN | NODE_LET_IN
+        );
+        is_bracket && it.first_token().as_ref() != Some(&first)
+    })?;
+    let closes = match bracket.kind() {
+        NODE_LET_IN => first.kind() == TOKEN_IN && first.parent() == bracket,
+        _ => bracket.last_token().as_ref() == Some(&first),
+    };
+    let opener_indent = line_at(lines, bracket.first_token()?.text_range().start())?;
+    let indent = IndentLevel::from_len(opener_indent);
+    Some(if closes { indent.len() } else { indent.indent().len() })
+}
+
+/// The new indent of the line which contains `offset`.
+fn line_at(lines: &BTreeMap<TextSize, TextSize>, offset: TextSize) -> Option<TextSize> {
+    lines.range(..=offset).next_back().map(|(_start, &it)| it)
+}
+
+/// Whether this content is a part of a `"` string.
+fn is_quoted(string_content: &SyntaxToken) -> bool {
+    string_content.parent().first_token().filter(|it| it.text() == "\"").is_some()
+}
 
-    if last_line_is_blank && last_indent.len() != quote_indent.len() {
-        model.raw_edit(AtomEdit { delete: *last_indent, insert: quote_indent.into() })
+/// Separates the text of a comment from the `#` or `/*` with a single space.
+/// Only the opening marker is padded, so a single-line block comment that
+/// hugs its closing `*/` (`/*two*/`) is left as written.
+///
+/// The shebang (`#!`), directives (`#nixfmt:`), `##` headings and `/**` doc
+/// comments are left alone.
+fn fix_comment_spacing(token: &SyntaxToken, model: &mut FmtModel) {
+    let text = token.text();
+    let is_block_comment = text.starts_with("/*");
+    let (opening, exceptions) = if is_block_comment { ("/*", "*") } else { ("#", "!#") };
+    let rest = &text[opening.len()..];
+    let needs_space = match rest.chars().next() {
+        Some(c) => !c.is_whitespace() && !exceptions.contains(c) && !rest.starts_with("nixfmt:"),
+        None => false,
+    };
+    let before_closing = text.strip_suffix("*/").unwrap_or(" ");
+    let unpadded_inline =
+        is_block_comment && !text.contains('\n') && !before_closing.ends_with(char::is_whitespace);
+    if !needs_space || unpadded_inline {
+        return;
     }
+    let offset = token.text_range().start() + TextSize::of(opening);
+    model.raw_edit(AtomEdit { delete: TextRange::empty(offset), insert: " ".into() });
 }
 
 /// If we indent multiline block comment, we should indent it's content as well.
+///
+/// All lines of the comment are shifted by the same amount as its first line,
+/// so that the layout inside of the comment is preserved.
 fn fix_comment_indentation(
     token: &SyntaxToken,
     model: &mut FmtModel,
@@ -103,45 +274,116 @@ fn fix_comment_indentation(
         return;
     }
 
-    let comment_indent = {
-        if block.text().contains('\n') {
-            IndentLevel::from_whitespace_block(block.text())
-        } else {
-            normal_indent
-        }
-    };
+    let starts_line = block.text().contains('\n');
+    let comment_indent =
+        if starts_line { IndentLevel::from_whitespace_block(block.text()) } else { normal_indent };
+    let old_indent = u32::from(original_line_indent(token).len());
+    let new_indent = u32::from(comment_indent.len());
+    // In doc comments which use leading `*`s, these are aligned with the one
+    // of `/**`.
+    let aligns_stars = starts_line
+        && token.text().starts_with("/**")
+        && token.text().lines().skip(1).any(|it| {
+            let it = it.trim_start();
+            it.starts_with('*') && !it.starts_with("*/")
+        });
 
-    let content_indent = comment_indent.indent();
     let mut curr_offset = token.text_range().start();
-    let mut first = true;
-    for line in token.text().lines() {
+    for (idx, line) in token.text().lines().enumerate() {
         let offset = curr_offset;
         curr_offset += TextSize::of(line) + TextSize::of('\n');
-        if first {
-            first = false;
+        if idx == 0 {
             continue;
         }
-        let last_line_only_end_block = line.ends_with("*/") || line.trim_start() == "*/";
-        let start_with_asterisk = line.trim_start().starts_with("*");
-        let current_indent = IndentLevel::get_whitespace_block(line);
         if let Some(ws_end) = line.find(|it| it != ' ') {
-            let delete =
-                TextRange::at(offset, TextSize::try_from(ws_end).expect("woah big number"));
-            if last_line_only_end_block || start_with_asterisk {
-                model.raw_edit(AtomEdit {
-                    delete,
-                    insert: comment_indent.add_alignment(current_indent).into(),
-                })
+            let len = if aligns_stars && line[ws_end..].starts_with('*') {
+                new_indent + 1
             } else {
-                model.raw_edit(AtomEdit {
-                    delete,
-                    insert: content_indent.adjust_alignment(current_indent).into(),
-                })
+                (ws_end as u32 + new_indent).saturating_sub(old_indent)
+            };
+            if len != ws_end as u32 {
+                let delete = TextRange::at(offset, TextSize::of(&line[..ws_end]));
+                let insert = IndentLevel::from_len(len.into()).into();
+                model.raw_edit(AtomEdit { delete, insert })
             }
         }
     }
 }
 
+/// Computes the indent of the line on which `token` starts, before formatting.
+fn original_line_indent(token: &SyntaxToken) -> IndentLevel {
+    let mut line = String::new();
+    for prev in successors(token.prev_token(), |it| it.prev_token()) {
+        match prev.text().rfind('\n') {
+            Some(idx) => {
+                line.insert_str(0, &prev.text()[idx + 1..]);
+                break;
+            }
+            None => line.insert_str(0, prev.text()),
+        }
+    }
+    IndentLevel::from_str(&line[..line.len() - line.trim_start().len()])
+}
+
+/// Computes the value of an indented string the way nix does, with each
+/// interpolation replaced by a placeholder character.
+///
+/// `edits` are applied to the literal parts of the string first, which allows
+/// to compare string values before and after reformatting.
+fn indented_string_value(indented_string: &SyntaxNode, edits: &[AtomEdit]) -> String {
+    let mut text = String::new();
+    for child in indented_string.children_with_tokens() {
+        match child {
+            NodeOrToken::Token(token) if token.kind() == TOKEN_STRING_CONTENT => {
+                let range = token.text_range();
+                let mut token_edits: Vec<&AtomEdit> =
+                    edits.iter().filter(|it| range.contains_range(it.delete)).collect();
+                token_edits.sort_by_key(|it| it.delete.start());
+                let mut prev = 0;
+                for edit in token_edits {
+                    let start = usize::from(edit.delete.start() - range.start());
+                    let end = usize::from(edit.delete.end() - range.start());
+                    text.push_str(&token.text()[prev..start]);
+                    text.push_str(&edit.insert);
+                    prev = end;
+                }
+                text.push_str(&token.text()[prev..]);
+            }
+            NodeOrToken::Node(node) if node.kind() == NODE_STRING_INTERPOL => text.push('\u{1}'),
+            _ => (),
+        }
+    }
+    strip_indentation(&text)
+}
+
+/// Strips the indentation of the literal text between `''` quotes.
+fn strip_indentation(text: &str) -> String {
+    fn indent_of(line: &str) -> usize {
+        line.len() - line.trim_start_matches(' ').len()
+    }
+
+    // Trailing spaces and the newline right after the opening quote are
+    // not a part of the string.
+    let text = match text.find(|c| c != ' ') {
+        Some(idx) if text[idx..].starts_with('\n') => &text[idx + 1..],
+        _ => text,
+    };
+    // Whitespace-only lines don't contribute to the common indentation.
+    let common_indent =
+        text.split('\n').filter(|line| indent_of(line) < line.len()).map(indent_of).min();
+    let common_indent = common_indent.unwrap_or(0);
+
+    let mut lines: Vec<&str> =
+        text.split('\n').map(|line| &line[min(indent_of(line), common_indent)..]).collect();
+    // And the whitespace before the closing quote isn't either.
+    if let Some(last) = lines.last_mut() {
+        if last.chars().all(|c| c == ' ') {
+            *last = "";
+        }
+    }
+    lines.join("\n")
+}
+
 /// For indented string like
 ///
 /// ```nix
@@ -152,12 +394,13 @@ fn fix_comment_indentation(
 /// ```
 ///
 /// returns the ranges, corresponding to indentation. That is `"  "` before
-/// hello, `"    "` before world and `""` before the last `''`.
-fn node_indent_ranges(indented_string: &SyntaxNode) -> impl Iterator<Item = TextRange> {
+/// hello, `"    "` before world and `""` before the last `''`. The lines
+/// inside of interpolations are skipped.
+fn literal_indent_ranges(indented_string: &SyntaxNode) -> impl Iterator<Item = TextRange> {
     indented_string
-        .descendants_with_tokens()
+        .children_with_tokens()
         .filter_map(|it| it.into_token())
-        .filter(|it| it.kind() == TOKEN_STRING_CONTENT || it.kind() == TOKEN_WHITESPACE)
+        .filter(|it| it.kind() == TOKEN_STRING_CONTENT)
         .flat_map(|string_bit| {
             let start_offset = string_bit.text_range().start();
             string_indent_ranges(string_bit.text())
@@ -166,24 +409,48 @@ fn node_indent_ranges(indented_string: &SyntaxNode) -> impl Iterator<Item = Text
         })
 }
 
+/// Returns the ranges of whitespace-only lines in the literal parts of an
+/// indented string.
+fn literal_blank_line_ranges(indented_string: &SyntaxNode) -> Vec<TextRange> {
+    let mut res = Vec::new();
+    for token in indented_string.children_with_tokens().filter_map(|it| it.into_token()) {
+        if token.kind() != TOKEN_STRING_CONTENT {
+            continue;
+        }
+        let start_offset = token.text_range().start();
+        let mut offset = start_offset;
+        let mut lines = token.text().split('\n').peekable();
+        // The first part is the tail of a line which started before.
+        if let Some(first) = lines.next() {
+            offset += TextSize::of(first) + TextSize::of('\n');
+        }
+        while let Some(line) = lines.next() {
+            // Only lines terminated within this token are blank.
+            if lines.peek().is_some() && !line.is_empty() && line.chars().all(|c| c == ' ') {
+                res.push(TextRange::at(offset, TextSize::of(line)));
+            }
+            offset += TextSize::of(line) + TextSize::of('\n');
+        }
+    }
+    res
+}
+
 fn string_indent_ranges(mut s: &str) -> Vec<TextRange> {
-    let mut offset = 0;
+    let mut offset = TextSize::from(0);
     std::iter::from_fn(move || loop {
         let indent_start = s.find('\n')? + 1;
+        offset += TextSize::of(&s[..indent_start]);
         s = &s[indent_start..];
-        offset += indent_start;
 
         let indent_len = s.find(|c| c != ' ').unwrap_or_else(|| s.len());
+        let indent = TextRange::at(offset, TextSize::of(&s[..indent_len]));
         s = &s[indent_len..];
-        offset += indent_len;
+        offset = indent.end();
         if s.starts_with('\n') {
             continue;
         }
 
-        return Some(TextRange::new(
-            TextSize::try_from(offset - indent_len).expect("woah big numbers"),
-            TextSize::try_from(offset).expect("woah big numbers"),
-        ));
+        return Some(indent);
     })
     .collect()
 }
@@ -193,7 +460,7 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_node_indent_ranges() {
+    fn test_literal_indent_ranges() {
         let text = r#"{
   python =
     ''
@@ -207,7 +474,7 @@ mod tests {
             .filter_map(|it| it.into_node())
             .find(|node| node.kind() == NODE_STRING)
             .unwrap();
-        let indent_ranges: Vec<TextRange> = node_indent_ranges(&node).collect();
+        let indent_ranges: Vec<TextRange> = literal_indent_ranges(&node).collect();
         assert_eq!(
             indent_ranges,
             vec![
@@ -230,7 +497,7 @@ mod tests {
             .filter_map(|it| it.into_node())
             .find(|node| node.kind() == NODE_STRING)
             .unwrap();
-        let indent_ranges: Vec<TextRange> = node_indent_ranges(&node).collect();
+        let indent_ranges: Vec<TextRange> = literal_indent_ranges(&node).collect();
         assert_eq!(
             indent_ranges,
             vec![
@@ -240,4 +507,25 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_strip_indentation() {
+        assert_eq!(strip_indentation("\n    foo\n      bar\n  "), "foo\n  bar\n");
+        assert_eq!(strip_indentation("  \n  foo\n\n   \n  \u{1}\n"), "foo\n\n \n\u{1}\n");
+        assert_eq!(strip_indentation("foo\n  bar"), "foo\n  bar");
+    }
+
+    #[test]
+    fn keeps_quoted_strings_in_interpolations() {
+        let before = "{\n    x = ''\n      foo ${\n        bar \"a\n  b\"\n      }\n    '';\n}\n";
+        let after = "{\n  x = ''\n    foo ${\n      bar \"a\n  b\"\n    }\n  '';\n}\n";
+        assert_eq!(crate::reformat_string(before), after);
+    }
+
+    #[test]
+    fn preserves_whitespace_only_lines() {
+        let before = "{\n      x = ''\n          foo\n             \n          bar\n      '';\n}\n";
+        let after = "{\n  x = ''\n    foo\n       \n    bar\n  '';\n}\n";
+        assert_eq!(crate::reformat_string(before), after);
+    }
 }
@@ -7,7 +7,13 @@ use rnix::{
 };
 use smol_str::SmolStr;
 
-use crate::{dsl::RuleName, engine::FmtDiff, tree_utils::preceding_tokens, AtomEdit};
+use crate::{
+    dsl::{RuleConflict, RuleName, SpaceValue},
+    engine::FmtDiff,
+    formatter::NodeHook,
+    tree_utils::preceding_tokens,
+    AtomEdit,
+};
 
 /// `FmtModel` is a data structure to which we apply formatting rules.
 ///
@@ -39,6 +45,10 @@ pub(super) struct FmtModel {
     by_end_offset: HashMap<TextSize, usize>,
     /// Arbitrary non-whitespace edits created by the last formatter phase.
     fixes: Vec<AtomEdit>,
+    /// Spacing rules with the same priority which disagreed.
+    pub(super) conflicts: Vec<RuleConflict>,
+    /// The rules which were applied, if requested.
+    pub(super) fired: Option<Vec<RuleName>>,
 }
 
 #[derive(Debug)]
@@ -51,6 +61,15 @@ pub(super) struct SpaceBlock {
     /// True for blocks after comments. The engine takes care to never remove
     /// newline, even if some interaction of rules asks us to do so.
     semantic_newline: bool,
+    /// If this block separates a trailing `#` comment from the code on its
+    /// line.
+    ///
+    /// Such blocks are always a single space, so that the comment stays
+    /// attached to the code it describes.
+    before_trailing_comment: bool,
+    /// The priority, the name and the requirement of the last spacing rule
+    /// for this block.
+    pub(super) claim: Option<(i32, Option<RuleName>, SpaceValue)>,
 }
 
 #[derive(Debug)]
@@ -89,24 +108,52 @@ impl SpaceBlock {
             }
             OriginalSpace::None { .. } => false,
         };
-        SpaceBlock { original, change: None, semantic_newline }
+        let before_trailing_comment = match &original {
+            OriginalSpace::Some(token) => {
+                !token.text().contains('\n')
+                    && token.prev_token().is_some()
+                    && is_line_comment(token.next_sibling_or_token())
+            }
+            OriginalSpace::None { .. } => false,
+        };
+        SpaceBlock {
+            original,
+            change: None,
+            semantic_newline,
+            before_trailing_comment,
+            claim: None,
+        }
     }
     pub(super) fn set_line_break_preserving_existing_newlines(&mut self, rule: Option<RuleName>) {
         if self.has_newline() {
             return;
         }
-        self.set_text("\n", rule);
+        self.set_line_breaks(1, rule);
+    }
+    /// Sets `count` line breaks, followed by the original indent of the line:
+    /// fixes which shift multiline tokens rely on it.
+    pub(super) fn set_line_breaks(&mut self, count: usize, rule: Option<RuleName>) {
+        let indent = match self.original_text().rfind('\n') {
+            Some(idx) => &self.original_text()[idx + 1..],
+            None => "",
+        };
+        let text = format!("{}{}", "\n".repeat(count), indent);
+        self.set_text(&text, rule);
     }
     pub(super) fn set_text(&mut self, text: &str, rule: Option<RuleName>) {
         if self.semantic_newline && !text.contains('\n') {
             return;
         }
+        let text = if self.before_trailing_comment { " " } else { text };
         self.change = match &self.original {
             OriginalSpace::Some(token) if token.text() == text => None,
             OriginalSpace::None { .. } if text.is_empty() => None,
             _ => Some(SpaceChange { new_text: text.into(), reason: rule }),
         }
     }
+    pub(super) fn original_range(&self) -> TextRange {
+        self.original.text_range()
+    }
     pub(super) fn text(&self) -> &str {
         if let Some(change) = &self.change {
             return change.new_text.as_str();
@@ -137,10 +184,26 @@ impl FmtModel {
             by_start_offset: HashMap::default(),
             by_end_offset: HashMap::default(),
             fixes: vec![],
+            conflicts: vec![],
+            fired: None,
         }
     }
 
-    pub(super) fn into_diff(self) -> FmtDiff {
+    pub(super) fn record_fired(&mut self, rule: Option<RuleName>) {
+        if let (Some(fired), Some(rule)) = (&mut self.fired, rule) {
+            fired.push(rule)
+        }
+    }
+
+    /// Records the rules which won the claims on the blocks. A rule which was
+    /// overridden by another one on the same block doesn't count.
+    pub(super) fn record_claims(&mut self) {
+        if let Some(fired) = &mut self.fired {
+            fired.extend(self.blocks.iter().filter_map(|it| it.claim.and_then(|(_, rule, _)| rule)))
+        }
+    }
+
+    pub(super) fn into_diff(self, hook: Option<&dyn NodeHook>) -> FmtDiff {
         let mut diff = FmtDiff { original_node: self.original_node.to_owned(), edits: vec![] };
         for block in self.blocks {
             if let Some(change) = block.change {
@@ -148,7 +211,7 @@ impl FmtModel {
             }
         }
         diff.edits.extend(self.fixes.into_iter().map(|edit| (edit, None)));
-        diff
+        diff.without_vetoed_edits(hook)
     }
 
     /// This method gets a `SpaceBlock` before or after element. It's pretty
@@ -235,7 +298,15 @@ impl FmtModel {
             },
         };
 
-        self.push_block(SpaceBlock::new(original_space))
+        let mut block = SpaceBlock::new(original_space);
+        if let OriginalSpace::None { .. } = block.original {
+            // Something like `a = 1;# comment`
+            block.before_trailing_comment = match position {
+                Before => is_line_comment(Some(element.clone())),
+                After => is_line_comment(original_token),
+            };
+        }
+        self.push_block(block)
     }
 
     /// Traverses tokens and space blocks that precede the given `node`.
@@ -1,13 +1,15 @@
 use std::{
     cmp::{Ord, Ordering, PartialOrd},
     fmt,
+    iter::successors,
 };
 
-use rnix::{SyntaxElement, SyntaxKind::*, SyntaxNode, SyntaxToken, TextSize};
+use rnix::{NodeOrToken, SyntaxElement, SyntaxKind::*, SyntaxNode, SyntaxToken, TextSize};
 use smol_str::SmolStr;
 
 use crate::{
-    dsl::{IndentRule, Modality, RuleName},
+    comments::{self, Attachment},
+    dsl::{IndentRule, IndentValue, Modality, RuleName},
     engine::{BlockPosition, FmtModel, SpaceBlock, SpaceBlockOrToken},
     pattern::{Pattern, PatternSet},
     tree_utils::prev_non_whitespace_token_sibling,
@@ -88,29 +90,6 @@ impl IndentLevel {
         IndentLevel { level: len / INDENT_SIZE, alignment: len % INDENT_SIZE }
     }
 
-    /// adjust `IndentLevel` based on whitespace provided
-    pub(super) fn adjust_alignment(self, new_indent: IndentLevel) -> IndentLevel {
-        if new_indent.len() > TextSize::from(5) {
-            return new_indent;
-        }
-        IndentLevel { level: self.level, alignment: new_indent.alignment }
-    }
-
-    /// adding alignment for multiline comment
-    pub(super) fn add_alignment(self, new_indent: IndentLevel) -> IndentLevel {
-        if self.level < new_indent.level {
-            return new_indent;
-        }
-        IndentLevel { level: self.level, alignment: new_indent.alignment }
-    }
-
-    pub(super) fn get_whitespace_block(s: &str) -> IndentLevel {
-        match s.find(|c: char| !c.is_whitespace()) {
-            None => IndentLevel::default(),
-            Some(idx) => IndentLevel::from_str(&s[..idx]),
-        }
-    }
-
     pub(super) fn from_whitespace_block(s: &str) -> IndentLevel {
         match s.rfind('\n') {
             None => IndentLevel::default(),
@@ -127,6 +106,12 @@ impl IndentLevel {
         IndentLevel { level: self.level + 1, alignment: self.alignment }
     }
 
+    /// Indent for the lines which continue the line with this indent, by
+    /// `width` spaces.
+    pub(super) fn continuation(self, width: u32) -> IndentLevel {
+        IndentLevel::from_len(self.len() + TextSize::from(width))
+    }
+
     pub(super) fn len(self) -> TextSize {
         (self.level * INDENT_SIZE + self.alignment).into()
     }
@@ -165,22 +150,68 @@ impl IndentRule {
         element: &SyntaxElement,
         model: &mut FmtModel,
         anchor_set: &PatternSet<&Pattern>,
+        continuation_indent: u32,
     ) {
         debug_assert!(self.matches(element));
-        let anchor_indent = match indent_anchor(element, model, anchor_set) {
+        let line_anchor = self.relative_to.as_ref().and_then(|it| line_anchor(element, it));
+        let anchor_indent = match line_anchor {
+            Some(anchor) => Some(model.line_indent(&anchor)),
+            None => self.anchor_indent(element, model, anchor_set),
+        };
+        let anchor_indent = match anchor_indent {
+            Some(it) => it,
+            None => {
+                default_indent(element, model, anchor_set);
+                return;
+            }
+        };
+        let indent = match self.indent_value {
+            IndentValue::Indent => anchor_indent.indent(),
+            IndentValue::Continuation => anchor_indent.continuation(continuation_indent),
+        };
+        let block = model.block_for(element, BlockPosition::Before);
+        block.set_indent(indent, self.name);
+        model.record_fired(Some(self.name));
+    }
+
+    /// The indent of the anchor, or `None` if the anchor doesn't match
+    /// `anchor_pattern`.
+    fn anchor_indent(
+        &self,
+        element: &SyntaxElement,
+        model: &mut FmtModel,
+        anchor_set: &PatternSet<&Pattern>,
+    ) -> Option<IndentLevel> {
+        let indent = match indent_anchor(element, model, anchor_set) {
             Some((anchor, indent)) => {
                 if let Some(p) = &self.anchor_pattern {
                     if !p.matches(&anchor.into()) {
-                        default_indent(element, model, anchor_set);
-                        return;
+                        return None;
                     }
                 }
                 indent
             }
             _ => IndentLevel::default(),
         };
-        let block = model.block_for(element, BlockPosition::Before);
-        block.set_indent(anchor_indent.indent(), self.name);
+        Some(indent)
+    }
+}
+
+/// Finds the nearest ancestor of `element`, or an element before one of the
+/// ancestors, which matches `pattern`.
+fn line_anchor(element: &SyntaxElement, pattern: &Pattern) -> Option<SyntaxElement> {
+    let mut current = element.clone();
+    loop {
+        let mut preceding =
+            successors(current.prev_sibling_or_token(), |it| it.prev_sibling_or_token());
+        if let Some(it) = preceding.find(|it| pattern.matches(it)) {
+            return Some(it);
+        }
+        let parent: SyntaxElement = current.parent()?.into();
+        if pattern.matches(&parent) {
+            return Some(parent);
+        }
+        current = parent;
     }
 }
 
@@ -228,6 +259,26 @@ pub(super) fn single_line_comment_indent(
     }
     return;
 }
+/// Indents a comment which starts a line like the node it describes.
+///
+/// Comments before punctuation, like the leading commas of lambda formals, are
+/// left to the usual rules.
+pub(super) fn indent_leading_comment(comment: &SyntaxToken, model: &mut FmtModel) {
+    let target = match comments::attachment(comment) {
+        Attachment::Leading(it) if it.as_node().is_some() => it,
+        _ => return,
+    };
+    let target_block = model.block_for(&target, BlockPosition::Before);
+    if !target_block.has_newline() {
+        return;
+    }
+    let indent = target_block.indent();
+    let block = model.block_for(&comment.clone().into(), BlockPosition::Before);
+    if block.has_newline() {
+        block.set_indent(indent, RuleName::new("Indent comment with the code it describes"));
+    }
+}
+
 /// Computes an anchoring element, together with its indent.
 ///
 /// By default, the anchor is an ancestor of `element` which itself is the first
@@ -246,6 +297,9 @@ pub(super) fn indent_anchor(
         if block.has_newline() {
             return Some((node.clone(), block.indent()));
         }
+        if let Some(indent) = leading_operator_indent(&node, model) {
+            return Some((node, indent));
+        }
         if anchor_set.matching(node.clone().into()).next().is_some() {
             let indent = model.indent_of(&node);
             return Some((node, indent));
@@ -259,7 +313,58 @@ pub(super) fn indent_anchor(
     None
 }
 
+/// If `node` is the right operand of a binary operator which begins the line,
+/// returns the indent of that line.
+///
+/// In
+///
+/// ```nix
+/// foo
+///   ++ optionals bar [
+///     baz
+///   ]
+/// ```
+///
+/// `optionals bar [ ... ]` doesn't start the line itself, but its contents
+/// should be indented relative to the `++` line.
+fn leading_operator_indent(node: &SyntaxNode, model: &mut FmtModel) -> Option<IndentLevel> {
+    if node.parent()?.kind() != NODE_BIN_OP {
+        return None;
+    }
+    let operator = prev_non_whitespace_token_sibling(&node.clone().into())?;
+    let block = model.block_for(&operator.into(), BlockPosition::Before);
+    if block.has_newline() {
+        Some(block.indent())
+    } else {
+        None
+    }
+}
+
 impl FmtModel {
+    /// The indent of the line on which `element` starts.
+    fn line_indent(&mut self, element: &SyntaxElement) -> IndentLevel {
+        let mut token = match element {
+            NodeOrToken::Node(it) => it.first_token(),
+            NodeOrToken::Token(it) => Some(it.clone()),
+        };
+        while let Some(it) = token {
+            let block = self.block_for(&it.clone().into(), BlockPosition::Before);
+            if block.has_newline() {
+                return block.indent();
+            }
+            token = successors(it.prev_token(), |it| it.prev_token())
+                .find(|it| it.kind() != TOKEN_WHITESPACE);
+            if let Some(prev) = &token {
+                // The last line of a multiline string or comment.
+                if let Some(idx) = prev.text().rfind('\n') {
+                    let line = &prev.text()[idx + 1..];
+                    return IndentLevel::from_str(&line[..line.len() - line.trim_start().len()]);
+                }
+            }
+        }
+        IndentLevel::default()
+    }
+
     /// Calculates current indent level for node.
     fn indent_of(&mut self, node: &SyntaxNode) -> IndentLevel {
         // The impl is tricky: we need to account for whitespace in `model`, which
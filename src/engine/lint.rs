@@ -0,0 +1,25 @@
+//! Checks the `LintRule`s. Unlike the rest of the engine, this only reports
+//! the problems, and never changes the text.
+use rnix::SyntaxNode;
+
+use crate::{
+    dsl::{LintDsl, LintWarning},
+    pattern::PatternSet,
+    tree_utils::walk,
+};
+
+/// Returns the warnings sorted by their ranges.
+pub(crate) fn lint(lint_dsl: &LintDsl, node: &SyntaxNode) -> Vec<LintWarning> {
+    let lint_rule_set = PatternSet::new(lint_dsl.rules.iter());
+    let mut res = Vec::new();
+    for element in walk(node) {
+        for rule in lint_rule_set.matching(element.clone()) {
+            let warnings = (rule.ranges)(&element)
+                .into_iter()
+                .map(|range| LintWarning { range, rule: rule.name.as_str() });
+            res.extend(warnings)
+        }
+    }
+    res.sort_by_key(|it| (it.range.start(), it.range.end()));
+    res
+}
@@ -0,0 +1,99 @@
+//! The file header and regions between `# nixfmt: off` and `# nixfmt: on`
+//! comments are left exactly as they were written.
+use rnix::{
+    NodeOrToken,
+    SyntaxKind::{TOKEN_COMMENT, TOKEN_WHITESPACE},
+    SyntaxNode, TextRange,
+};
+
+use crate::{edits::TextEdit, formatter::NodeHook, FmtDiff};
+
+/// Computes the ranges of text which must not be changed by formatting.
+///
+/// These are the comments before the first expression of the file (license
+/// headers and the like), and the regions which start right after
+/// `# nixfmt: off` and end right before the next `# nixfmt: on`, or at the end
+/// of file.
+pub(crate) fn protected_ranges(root: &SyntaxNode) -> Vec<TextRange> {
+    let mut res: Vec<TextRange> = file_header(root).into_iter().collect();
+    let mut start = None;
+    let comments = root
+        .descendants_with_tokens()
+        .filter_map(|it| it.into_token())
+        .filter(|it| it.kind() == TOKEN_COMMENT);
+    for comment in comments {
+        match (directive(comment.text()), start) {
+            (Some(false), None) => start = Some(comment.text_range().end()),
+            (Some(true), Some(off)) => {
+                res.push(TextRange::new(off, comment.text_range().start()));
+                start = None;
+            }
+            _ => (),
+        }
+    }
+    if let Some(off) = start {
+        res.push(TextRange::new(off, root.text_range().end()));
+    }
+    res
+}
+
+fn file_header(root: &SyntaxNode) -> Option<TextRange> {
+    let mut header = root
+        .children_with_tokens()
+        .take_while(|it| it.kind() == TOKEN_COMMENT || it.kind() == TOKEN_WHITESPACE)
+        .filter(|it| it.kind() == TOKEN_COMMENT)
+        .map(|it| it.text_range());
+    let first = header.next()?;
+    Some(header.fold(first, |acc, it| acc.cover(it)))
+}
+
+/// Returns `Some(true)` for `# nixfmt: on` and `Some(false)` for
+/// `# nixfmt: off`.
+fn directive(comment: &str) -> Option<bool> {
+    let text = comment.strip_prefix('#')?.trim();
+    match text.strip_prefix("nixfmt:")?.trim() {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    }
+}
+
+impl FmtDiff {
+    /// Drops the edits which would change protected regions.
+    fn without_protected_edits(mut self) -> FmtDiff {
+        let protected = protected_ranges(&self.original_node);
+        if !protected.is_empty() {
+            self.edits.retain(|(edit, _reason)| {
+                let delete = edit.delete;
+                protected.iter().all(|range| {
+                    let overlaps = delete.start() < range.end() && range.start() < delete.end();
+                    let inserts_inside = delete.is_empty()
+                        && range.start() < delete.start()
+                        && delete.start() < range.end();
+                    !overlaps && !inserts_inside
+                })
+            });
+        }
+        self
+    }
+
+    /// Drops the protected edits and the ones vetoed by the `hook`. All the
+    /// phases of formatting pass their edits through here.
+    pub(super) fn without_vetoed_edits(self, hook: Option<&dyn NodeHook>) -> FmtDiff {
+        let mut diff = self.without_protected_edits();
+        let hook = match hook {
+            Some(it) => it,
+            None => return diff,
+        };
+        let root = diff.original_node.clone();
+        diff.edits.retain(|(edit, _reason)| {
+            let node = match root.covering_element(edit.delete) {
+                NodeOrToken::Node(it) => it,
+                NodeOrToken::Token(it) => it.parent(),
+            };
+            let text_edit = TextEdit { range: edit.delete, new_text: edit.insert.to_string() };
+            node.ancestors().all(|it| hook.before_node(&it)) && hook.on_edit(&node, &text_edit)
+        });
+        diff
+    }
+}
@@ -0,0 +1,156 @@
+//! Re-wraps runs of `#` comments which don't fit into
+//! `Config::comment_width`.
+//!
+//! Only prose is reflowed: blank comment lines separate paragraphs, and lines
+//! which look like code (indented blocks, URLs) are kept as is.
+use rnix::{SyntaxKind::TOKEN_COMMENT, SyntaxKind::TOKEN_WHITESPACE, SyntaxNode, SyntaxToken};
+
+use crate::{engine::wrapping::line_width, formatter::NodeHook, AtomEdit, FmtDiff};
+
+pub(super) fn reflow_comments(
+    root: &SyntaxNode,
+    comment_width: u32,
+    hook: Option<&dyn NodeHook>,
+) -> SyntaxNode {
+    let mut edits = Vec::new();
+    for group in comment_groups(root) {
+        let indent = line_indent(&group[0]);
+        let lines: Vec<&str> = group.iter().map(|it| it.text()).collect();
+        let reflowed = reflow(&lines, comment_width.saturating_sub(line_width(&indent)));
+        if reflowed == lines {
+            continue;
+        }
+        let delete = group[0].text_range().cover(group[group.len() - 1].text_range());
+        let insert = reflowed.join(&format!("\n{}", indent));
+        edits.push((AtomEdit { delete, insert: insert.into() }, None));
+    }
+    FmtDiff { original_node: root.clone(), edits }.without_vetoed_edits(hook).to_node()
+}
+
+/// Finds the runs of `#` comments, each on its own line, which follow each
+/// other without blank lines in between.
+fn comment_groups(root: &SyntaxNode) -> Vec<Vec<SyntaxToken>> {
+    let mut res: Vec<Vec<SyntaxToken>> = Vec::new();
+    let comments = root
+        .descendants_with_tokens()
+        .filter_map(|it| it.into_token())
+        .filter(|it| it.kind() == TOKEN_COMMENT && it.text().starts_with('#'));
+    for comment in comments {
+        let ws = comment.prev_token().filter(|it| it.kind() == TOKEN_WHITESPACE);
+        match &ws {
+            Some(ws) if !ws.text().contains('\n') => continue,
+            None if comment.prev_token().is_some() => continue,
+            _ => (),
+        }
+        let continues_group = match (&ws, res.last()) {
+            (Some(ws), Some(group)) => {
+                ws.text().matches('\n').count() == 1
+                    && ws.prev_token().as_ref() == group.last()
+                    && line_indent(&comment) == line_indent(&group[0])
+            }
+            _ => false,
+        };
+        match res.last_mut() {
+            Some(group) if continues_group => group.push(comment),
+            _ => res.push(vec![comment]),
+        }
+    }
+    res
+}
+
+/// Indentation of the comment which starts its line.
+fn line_indent(comment: &SyntaxToken) -> String {
+    match comment.prev_token() {
+        Some(ws) if ws.kind() == TOKEN_WHITESPACE => {
+            let text = ws.text();
+            text[text.rfind('\n').map_or(0, |it| it + 1)..].to_string()
+        }
+        _ => String::new(),
+    }
+}
+
+/// Re-wraps the paragraphs of `comments` which have lines longer than `width`.
+fn reflow<'a>(comments: &[&'a str], width: u32) -> Vec<String> {
+    let mut res = Vec::new();
+    let mut paragraph: Vec<&'a str> = Vec::new();
+    for &comment in comments {
+        let text = comment_text(comment);
+        let continues_paragraph = match (text, paragraph.first()) {
+            (Some(text), Some(&first)) => {
+                !is_list_item(text)
+                    && !text.contains("://")
+                    && text_indent(text) == list_item_indent(first).unwrap_or(0)
+            }
+            _ => false,
+        };
+        if !continues_paragraph {
+            flush(&mut paragraph, width, &mut res);
+        }
+        match text {
+            Some(text) if continues_paragraph || !is_verbatim(text) => paragraph.push(text),
+            _ => res.push(comment.to_string()),
+        }
+    }
+    flush(&mut paragraph, width, &mut res);
+    res
+}
+
+fn flush(paragraph: &mut Vec<&str>, width: u32, res: &mut Vec<String>) {
+    if paragraph.is_empty() {
+        return;
+    }
+    let too_long = paragraph.iter().any(|it| line_width(it) + 2 > width);
+    if !too_long {
+        res.extend(paragraph.iter().map(|it| format!("# {}", it)));
+        paragraph.clear();
+        return;
+    }
+    let hanging = " ".repeat(list_item_indent(paragraph[0]).unwrap_or(0) as usize);
+    let mut line = String::new();
+    for word in paragraph.iter().flat_map(|it| it.split_whitespace()) {
+        if line.is_empty() {
+            line = format!("# {}", word);
+            continue;
+        }
+        if line_width(&line) + 1 + line_width(word) > width {
+            res.push(line);
+            line = format!("# {}{}", hanging, word);
+        } else {
+            line.push(' ');
+            line.push_str(word);
+        }
+    }
+    res.push(line);
+    paragraph.clear();
+}
+
+/// The text of the comment after `# `, if the comment is prose.
+fn comment_text(comment: &str) -> Option<&str> {
+    let text = comment.strip_prefix('#')?;
+    if text.starts_with('!') || text.starts_with('#') || text.trim_start().starts_with("nixfmt:") {
+        return None;
+    }
+    let text = text.strip_prefix(' ').unwrap_or(text).trim_end();
+    if text.is_empty() {
+        return None;
+    }
+    Some(text)
+}
+
+/// Indented blocks and URLs would be mangled by reflowing.
+fn is_verbatim(text: &str) -> bool {
+    text_indent(text) > 0 || text.contains("://")
+}
+
+fn is_list_item(text: &str) -> bool {
+    list_item_indent(text).is_some()
+}
+
+/// For `- item`, the indent of the item's continuation lines.
+fn list_item_indent(text: &str) -> Option<u32> {
+    ["- ", "* ", "+ "].iter().find(|it| text.starts_with(*it)).map(|_| 2)
+}
+
+fn text_indent(text: &str) -> u32 {
+    (text.len() - text.trim_start().len()) as u32
+}
@@ -1,7 +1,7 @@
 use rnix::SyntaxElement;
 
 use crate::{
-    dsl::{RuleName, SpaceLoc, SpaceValue, SpacingRule},
+    dsl::{RuleConflict, RuleName, SpaceLoc, SpaceValue, SpacingRule, FORCE_BREAK_PRIORITY},
     engine::{BlockPosition, FmtModel, SpaceBlock},
     tree_utils::has_newline,
 };
@@ -12,12 +12,53 @@ impl SpacingRule {
             return;
         }
         if self.space.loc.is_before() {
-            let block = model.block_for(element, BlockPosition::Before);
-            ensure_space(element, block, self.space.value, self.name);
+            self.apply_to_block(element, model, BlockPosition::Before)
         }
         if self.space.loc.is_after() {
-            let block = model.block_for(element, BlockPosition::After);
+            self.apply_to_block(element, model, BlockPosition::After)
+        }
+    }
+
+    /// Whether the rule only limits the line breaks, so it should be applied
+    /// after the others.
+    pub(super) fn is_limit(&self) -> bool {
+        matches!(self.space.value, SpaceValue::MaxBlankLines(_) | SpaceValue::SameLine)
+    }
+
+    /// A limit only removes the line breaks set by the other rules, so it
+    /// ignores and doesn't take the claims.
+    ///
+    /// Rules with lower priority don't override the block, and the rules with
+    /// the same priority do, but record a conflict if they disagree.
+    fn apply_to_block(&self, element: &SyntaxElement, model: &mut FmtModel, pos: BlockPosition) {
+        let block = model.block_for(element, pos);
+        if self.is_limit() {
+            let forced = matches!(block.claim, Some((FORCE_BREAK_PRIORITY, ..)));
+            if forced && self.space.value == SpaceValue::SameLine {
+                return;
+            }
             ensure_space(element, block, self.space.value, self.name);
+            return model.record_fired(self.name);
+        }
+        let prev = match block.claim {
+            Some((priority, ..)) if priority > self.priority => return,
+            Some((priority, name, value)) if priority == self.priority => {
+                Some((name, value, block.text().to_string()))
+            }
+            _ => None,
+        };
+        ensure_space(element, block, self.space.value, self.name);
+        block.claim = Some((self.priority, self.name, self.space.value));
+        if let Some((prev_name, prev_value, prev_text)) = prev {
+            if block.text() != prev_text {
+                let conflict = RuleConflict {
+                    range: block.original_range(),
+                    rules: [prev_name.map(RuleName::as_str), self.name.map(RuleName::as_str)],
+                    values: [prev_value, self.space.value],
+                    texts: [prev_text, block.text().to_string()],
+                };
+                model.conflicts.push(conflict)
+            }
         }
     }
 }
@@ -56,6 +97,18 @@ fn ensure_space(
                 block.set_text("\n", rule_name)
             }
         }
+        SpaceValue::SingleNewline => block.set_line_breaks(1, rule_name),
+        SpaceValue::BlankLines(count) => block.set_line_breaks(line_breaks(count), rule_name),
+        SpaceValue::MaxBlankLines(count) => {
+            if block.text().matches('\n').count() > line_breaks(count) {
+                block.set_line_breaks(line_breaks(count), rule_name)
+            }
+        }
+        SpaceValue::SameLine => {
+            if block.has_newline() {
+                block.set_text(" ", rule_name)
+            }
+        }
         SpaceValue::None => block.set_text("", rule_name),
         SpaceValue::NoneOptionalNewline => {
             if !block.has_newline() {
@@ -80,3 +133,8 @@ fn ensure_space(
         }
     }
 }
+
+/// The number of line breaks which leave `blank_lines` blank lines.
+fn line_breaks(blank_lines: u32) -> usize {
+    blank_lines as usize + 1
+}
@@ -0,0 +1,251 @@
+//! Breaks the lines which don't fit into `Config::max_width`, and joins the
+//! ones which do.
+//!
+//! The lines are broken at the break points defined by `BreakRule`s (or
+//! joined according to `JoinRule`s), and then the whole tree is laid out
+//! again, so that spacing and indentation rules could adjust to the new line
+//! breaks.
+use rnix::{
+    SyntaxElement,
+    SyntaxKind::{NODE_ERROR, TOKEN_COMMENT, TOKEN_WHITESPACE},
+    SyntaxNode, TextRange, TextSize,
+};
+
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+use crate::{
+    comments,
+    dsl::{BreakRule, JoinRule, RuleName, WrapDsl},
+    error::FmtError,
+    formatter::NodeHook,
+    tree_utils::{has_newline, walk_formattable, walk_tokens},
+    AtomEdit, FmtDiff,
+};
+
+/// Joins the nodes which fit into `max_width` onto a single line.
+///
+/// Unlike `wrap`, this takes the tree *before* layout: the newlines which were
+/// written by the author are a hint for `JoinRule`s, and spacing rules add
+/// many more of them.
+pub(super) fn join(
+    wrap_dsl: &WrapDsl,
+    max_width: u32,
+    hook: Option<&dyn NodeHook>,
+    node: SyntaxNode,
+    layout: &mut dyn FnMut(&SyntaxNode) -> Result<SyntaxNode, FmtError>,
+    fired: &mut Vec<RuleName>,
+) -> Result<SyntaxNode, FmtError> {
+    let mut input = node;
+    let mut laid_out = layout(&input)?;
+    let mut excess = total_excess(&laid_out, max_width);
+    // Joining a node doesn't move the nodes which start before it, so we can
+    // remember the rejected nodes by their offsets.
+    let mut rejected: Vec<TextSize> = Vec::new();
+    'join: loop {
+        for element in walk_formattable(&input) {
+            let target = match element.as_node() {
+                Some(it) if !rejected.contains(&it.text_range().start()) => it,
+                _ => continue,
+            };
+            let rule = match wrap_dsl.joins.iter().find(|it| it.pattern.matches(&element)) {
+                Some(it) => it,
+                None => continue,
+            };
+            if !has_newline(target) || !can_join(target) {
+                continue;
+            }
+            let joined = join_lines(&input, rule, target, hook);
+            let candidate = layout(&joined)?;
+            let candidate_excess = total_excess(&candidate, max_width);
+            if candidate_excess <= excess && candidate.to_string() != laid_out.to_string() {
+                input = joined;
+                laid_out = candidate;
+                excess = candidate_excess;
+                fired.push(rule.name);
+            } else {
+                rejected.push(target.text_range().start());
+            }
+            continue 'join;
+        }
+        break;
+    }
+    Ok(laid_out)
+}
+
+/// Newlines in strings and after comments are significant.
+fn can_join(node: &SyntaxNode) -> bool {
+    walk_tokens(node).all(|token| match token.kind() {
+        TOKEN_WHITESPACE => true,
+        TOKEN_COMMENT => false,
+        _ => !token.text().contains('\n'),
+    }) && node.descendants().all(|it| it.kind() != NODE_ERROR)
+}
+
+/// Replaces each line break inside the `node` with a space.
+fn join_lines(
+    root: &SyntaxNode,
+    rule: &JoinRule,
+    node: &SyntaxNode,
+    hook: Option<&dyn NodeHook>,
+) -> SyntaxNode {
+    let edits = walk_tokens(node)
+        .filter(|it| it.kind() == TOKEN_WHITESPACE && it.text().contains('\n'))
+        .map(|it| (AtomEdit { delete: it.text_range(), insert: " ".into() }, Some(rule.name)))
+        .collect();
+    FmtDiff { original_node: root.clone(), edits }.without_vetoed_edits(hook).to_node()
+}
+
+pub(super) fn wrap(
+    wrap_dsl: &WrapDsl,
+    max_width: u32,
+    hook: Option<&dyn NodeHook>,
+    node: SyntaxNode,
+    layout: &mut dyn FnMut(&SyntaxNode) -> Result<SyntaxNode, FmtError>,
+    fired: &mut Vec<RuleName>,
+) -> Result<SyntaxNode, FmtError> {
+    let mut node = node;
+    let mut excess = total_excess(&node, max_width);
+    // Each accepted break strictly decreases the total excess, so this
+    // terminates.
+    'wrap: while excess > 0 {
+        for (rule, break_points) in candidates(wrap_dsl, max_width, &node) {
+            let candidate = layout(&break_lines(&node, rule, &break_points, hook))?;
+            let candidate_excess = total_excess(&candidate, max_width);
+            if candidate_excess < excess {
+                node = candidate;
+                excess = candidate_excess;
+                fired.push(rule.name);
+                continue 'wrap;
+            }
+        }
+        break;
+    }
+    Ok(node)
+}
+
+/// Finds the ways to break the lines which are too long.
+///
+/// Rules are tried in order. For each rule, the nodes which cross the width
+/// limit go first, and outer nodes go before inner ones.
+fn candidates<'a>(
+    wrap_dsl: &'a WrapDsl,
+    max_width: u32,
+    root: &SyntaxNode,
+) -> Vec<(&'a BreakRule, Vec<SyntaxElement>)> {
+    let long_lines = long_lines(root, max_width);
+    let mut res = Vec::new();
+    for rule in wrap_dsl.rules.iter() {
+        let mut crossing = Vec::new();
+        let mut other = Vec::new();
+        for element in walk_formattable(root) {
+            let node = match element.as_node() {
+                Some(it) if rule.pattern.matches(&element) => it,
+                _ => continue,
+            };
+            let break_points: Vec<SyntaxElement> = (rule.break_points)(node)
+                .into_iter()
+                .map(with_leading_comments)
+                .filter(|it| !starts_line(it))
+                .collect();
+            let helps = break_points.iter().any(|point| {
+                long_lines.iter().any(|line| line.range.contains(point.text_range().start()))
+            });
+            if !helps {
+                continue;
+            }
+            if long_lines.iter().any(|line| node.text_range().contains(line.limit)) {
+                crossing.push((rule, break_points))
+            } else {
+                other.push((rule, break_points))
+            }
+        }
+        res.extend(crossing);
+        res.extend(other);
+    }
+    res
+}
+
+/// Breaking a line right before an element would separate it from the comments
+/// which describe it, like in `/* comment */ value`, so the comments move to
+/// the new line as well.
+fn with_leading_comments(element: SyntaxElement) -> SyntaxElement {
+    match comments::leading_comments(&element).into_iter().next() {
+        Some(comment) => comment.into(),
+        None => element,
+    }
+}
+
+/// Inserts a newline before each of the `break_points`.
+fn break_lines(
+    root: &SyntaxNode,
+    rule: &BreakRule,
+    break_points: &[SyntaxElement],
+    hook: Option<&dyn NodeHook>,
+) -> SyntaxNode {
+    let edits = break_points
+        .iter()
+        .map(|point| {
+            let delete = match whitespace_before(point) {
+                Some(ws) => ws.text_range(),
+                None => TextRange::empty(point.text_range().start()),
+            };
+            (AtomEdit { delete, insert: "\n".into() }, Some(rule.name))
+        })
+        .collect();
+    FmtDiff { original_node: root.clone(), edits }.without_vetoed_edits(hook).to_node()
+}
+
+fn whitespace_before(element: &SyntaxElement) -> Option<rnix::SyntaxToken> {
+    let first_token = match element {
+        SyntaxElement::Node(it) => it.first_token()?,
+        SyntaxElement::Token(it) => it.clone(),
+    };
+    first_token.prev_token().filter(|it| it.kind() == TOKEN_WHITESPACE)
+}
+
+fn starts_line(element: &SyntaxElement) -> bool {
+    match whitespace_before(element) {
+        Some(ws) => ws.text().contains('\n'),
+        None => element.text_range().start() == TextSize::from(0),
+    }
+}
+
+struct LongLine {
+    range: TextRange,
+    /// Offset of the first character beyond the width limit.
+    limit: TextSize,
+}
+
+fn long_lines(root: &SyntaxNode, max_width: u32) -> Vec<LongLine> {
+    let text = root.to_string();
+    let mut res = Vec::new();
+    let mut offset = TextSize::from(0);
+    for line in text.split('\n') {
+        let range = TextRange::at(offset, TextSize::of(line));
+        if line_width(line) > max_width {
+            let mut width = 0;
+            let limit = line
+                .char_indices()
+                .find(|&(_, c)| {
+                    width += c.width().unwrap_or(0) as u32;
+                    width > max_width
+                })
+                .map_or(0, |(idx, _)| idx);
+            let limit = offset + TextSize::of(&line[..limit]);
+            res.push(LongLine { range, limit })
+        }
+        offset = range.end() + TextSize::of("\n");
+    }
+    res
+}
+
+/// The total number of columns by which lines exceed the `max_width`.
+fn total_excess(root: &SyntaxNode, max_width: u32) -> u32 {
+    root.to_string().split('\n').map(|line| line_width(line).saturating_sub(max_width)).sum()
+}
+
+/// Width of the line in terminal columns: CJK characters take two columns,
+/// combining characters take none.
+pub(super) fn line_width(line: &str) -> u32 {
+    line.width() as u32
+}
@@ -0,0 +1,104 @@
+use std::{
+    fmt,
+    panic::{self, AssertUnwindSafe},
+};
+
+use rnix::{parser::ParseError, TextRange, TextSize};
+
+/// The reason why `reformat` failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FmtError {
+    /// The input is not valid Nix.
+    Parse(Vec<SyntaxError>),
+    /// The formatter broke the code. This is a bug.
+    Internal(String),
+}
+
+/// A syntax error in the input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxError {
+    pub range: TextRange,
+    pub message: String,
+}
+
+impl SyntaxError {
+    pub(crate) fn new(error: &ParseError, text_len: TextSize) -> SyntaxError {
+        let eof = TextRange::empty(text_len);
+        let (range, message) = match error {
+            ParseError::Unexpected(range) => (*range, "unexpected code".to_string()),
+            ParseError::UnexpectedExtra(range) => (*range, "unexpected token".to_string()),
+            ParseError::UnexpectedWanted(got, range, wanted) => {
+                (*range, format!("unexpected {:?}, wanted any of {:?}", got, wanted))
+            }
+            ParseError::UnexpectedDoubleBind(range) => (*range, "duplicate binding".to_string()),
+            ParseError::UnexpectedEOF => (eof, "unexpected end of file".to_string()),
+            ParseError::UnexpectedEOFWanted(wanted) => {
+                (eof, format!("unexpected end of file, wanted any of {:?}", wanted))
+            }
+            _ => (TextRange::up_to(text_len), error.to_string()),
+        };
+        SyntaxError { range, message }
+    }
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at {}..{}",
+            self.message,
+            usize::from(self.range.start()),
+            usize::from(self.range.end())
+        )
+    }
+}
+
+impl fmt::Display for FmtError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FmtError::Parse(errors) => {
+                write!(f, "syntax error")?;
+                for (idx, error) in errors.iter().enumerate() {
+                    write!(f, "{} {}", if idx == 0 { ":" } else { "," }, error)?;
+                }
+                Ok(())
+            }
+            FmtError::Internal(message) => write!(f, "internal error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for FmtError {}
+
+/// Runs `f`, turning a panic into `FmtError::Internal`, so that a bug in the
+/// rules doesn't take down the embedder.
+///
+/// The panic hook still runs, and by default prints the panic to stderr. It
+/// is process-global, so setting it is left to the embedder.
+///
+/// The errors the rules can run into are returned by `f` instead, as with
+/// `panic = "abort"`, like on wasm, this can't help.
+pub(crate) fn catch_internal<T>(f: impl FnOnce() -> Result<T, FmtError>) -> Result<T, FmtError> {
+    // The inputs are immutable trees, and whatever was built before the panic
+    // is thrown away, so nothing broken is observed afterwards.
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        let message = match payload.downcast_ref::<&str>() {
+            Some(it) => it.to_string(),
+            None => payload.downcast_ref::<String>().cloned().unwrap_or_default(),
+        };
+        Err(FmtError::Internal(format!("the formatter panicked: {}", message)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catches_panics() {
+        assert_eq!(catch_internal(|| Ok(92)), Ok(92));
+        let err =
+            catch_internal(|| -> Result<u32, FmtError> { panic!("boom {}", 92) }).unwrap_err();
+        assert_eq!(err, FmtError::Internal("the formatter panicked: boom 92".to_string()));
+    }
+}
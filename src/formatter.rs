@@ -0,0 +1,267 @@
+use std::{fmt, sync::Arc};
+
+use rnix::{SyntaxNode, TextRange};
+
+use crate::{
+    config::Config,
+    dsl::{IndentDsl, RuleConflict, RuleName, SpacingDsl, WrapDsl},
+    edits::TextEdit,
+    engine,
+    error::{catch_internal, FmtError},
+    rules, FmtDiff,
+};
+
+/// A formatter with some of the rule groups turned off, for example, to only
+/// normalize the indentation.
+///
+/// Building the rule tables is not free, so build a `Formatter` once and reuse
+/// it. It is `Send + Sync`, and can be shared between threads.
+///
+/// ```
+/// let formatter = nixpkgs_fmt::Formatter::builder().spacing(false).build();
+/// assert_eq!(formatter.format("{\nfoo =1;\n}\n"), "{\n  foo =1;\n}\n");
+/// ```
+pub struct Formatter {
+    pub(crate) config: Config,
+    pub(crate) spacing: SpacingDsl,
+    pub(crate) indentation: Option<IndentDsl>,
+    pub(crate) wrapping: WrapDsl,
+    pub(crate) hook: Option<Hook>,
+}
+
+impl Formatter {
+    /// A formatter with all the rules.
+    pub fn new(config: Config) -> Formatter {
+        Formatter::builder().config(config).build()
+    }
+
+    pub fn builder() -> FormatterBuilder {
+        FormatterBuilder {
+            config: Config::default(),
+            spacing: true,
+            indentation: true,
+            wrapping: true,
+            extra_spacing: Vec::new(),
+            extra_indentation: Vec::new(),
+            hook: None,
+        }
+    }
+
+    /// Formats `text` like `reformat_string_with_config` does.
+    pub fn format(&self, text: &str) -> String {
+        crate::reformat_with_formatter(text, self).text
+    }
+
+    /// Like `reformat_changed`, but reuses the rules of this formatter.
+    pub fn format_changed(&self, text: &str, changed: &[TextRange]) -> String {
+        crate::incremental::reformat_changed(text, changed, self)
+    }
+
+    /// Checks whether `format` would leave `text` as is, like `is_formatted`.
+    pub fn is_formatted(&self, text: &str) -> bool {
+        crate::is_formatted_with_formatter(text, self)
+    }
+
+    /// Lists the places in `text` where spacing rules with the same priority
+    /// disagree, and so the outcome depends on the order of the rules. This
+    /// is a debugging aid for rule authors. Empty if the formatter fails.
+    pub fn rule_conflicts(&self, text: &str) -> Vec<RuleConflict> {
+        let node = rnix::parse(text).node();
+        catch_internal(|| Ok(engine::spacing_conflicts(&self.spacing, &node))).unwrap_or_default()
+    }
+
+    /// Lists the names of the spacing and indentation rules which decide some
+    /// of the whitespace in `text`, whether or not they change it. A rule
+    /// which is overridden by another one doesn't count. With
+    /// `Config::max_width`, this also lists the wrapping rules which break or
+    /// join some lines. Together with `builtin_rules`, this finds the rules
+    /// which never apply. Empty if the formatter fails.
+    pub fn fired_rules(&self, text: &str) -> Vec<&'static str> {
+        let node = rnix::parse(text).node();
+        let indentation = self.indentation.as_ref();
+        let fired = catch_internal(|| {
+            engine::fired_rules(&self.spacing, indentation, &self.wrapping, &self.config, &node)
+        });
+        let mut res: Vec<&'static str> =
+            fired.unwrap_or_default().into_iter().map(RuleName::as_str).collect();
+        res.sort_unstable();
+        res.dedup();
+        res
+    }
+
+    /// Leaves `node` as is if the formatter fails.
+    pub(crate) fn reformat_node(&self, node: &SyntaxNode) -> SyntaxNode {
+        self.try_reformat_node(node).unwrap_or_else(|_| node.clone())
+    }
+
+    pub(crate) fn try_reformat_diff(&self, node: &SyntaxNode) -> Result<FmtDiff, FmtError> {
+        catch_internal(|| {
+            engine::reformat_diff(
+                &self.spacing,
+                self.indentation.as_ref(),
+                &self.wrapping,
+                &self.config,
+                self.hook.as_ref().map(|it| &*it.0),
+                node,
+            )
+        })
+    }
+
+    pub(crate) fn try_reformat_node(&self, node: &SyntaxNode) -> Result<SyntaxNode, FmtError> {
+        catch_internal(|| {
+            engine::reformat(
+                &self.spacing,
+                self.indentation.as_ref(),
+                &self.wrapping,
+                &self.config,
+                self.hook.as_ref().map(|it| &*it.0),
+                node,
+                None,
+            )
+        })
+    }
+}
+
+/// Builds a `Formatter`. All the rule groups are on by default.
+#[derive(Debug, Clone)]
+pub struct FormatterBuilder {
+    config: Config,
+    spacing: bool,
+    indentation: bool,
+    wrapping: bool,
+    extra_spacing: Vec<fn(&mut SpacingDsl)>,
+    extra_indentation: Vec<fn(&mut IndentDsl)>,
+    hook: Option<Hook>,
+}
+
+/// Lets an embedder observe the edits made by formatting, and veto some of
+/// them.
+///
+/// Formatting is done in several phases, and each phase passes its edits
+/// through the hook, with the ranges in the text before that phase. So the
+/// hook may see several edits for the same place.
+///
+/// The hook is shared by the threads which use the `Formatter`, hence
+/// `Send + Sync`.
+///
+/// ```
+/// use nixpkgs_fmt::{Formatter, NodeHook};
+/// use rnix::{SyntaxKind::NODE_LIST, SyntaxNode};
+///
+/// struct KeepLists;
+///
+/// impl NodeHook for KeepLists {
+///     fn before_node(&self, node: &SyntaxNode) -> bool {
+///         node.kind() != NODE_LIST
+///     }
+/// }
+///
+/// let formatter = Formatter::builder().hook(KeepLists).build();
+/// assert_eq!(formatter.format("{a=[1  2];}"), "{ a = [1  2]; }\n");
+/// ```
+pub trait NodeHook: Send + Sync {
+    /// Whether formatting may change the text inside `node`. This is asked
+    /// for each ancestor of every edit.
+    fn before_node(&self, _node: &SyntaxNode) -> bool {
+        true
+    }
+
+    /// Called for each edit which is about to be applied, with the smallest
+    /// node containing it. Returning `false` vetoes the edit.
+    fn on_edit(&self, _node: &SyntaxNode, _edit: &TextEdit) -> bool {
+        true
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Hook(pub(crate) Arc<dyn NodeHook>);
+
+impl fmt::Debug for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Hook(..)")
+    }
+}
+
+impl FormatterBuilder {
+    pub fn config(mut self, config: Config) -> FormatterBuilder {
+        self.config = config;
+        self
+    }
+
+    /// The rules for the spaces and line breaks between tokens.
+    pub fn spacing(mut self, enabled: bool) -> FormatterBuilder {
+        self.spacing = enabled;
+        self
+    }
+
+    /// The rules for the indentation of lines, including the re-indentation
+    /// of comments and multiline strings.
+    pub fn indentation(mut self, enabled: bool) -> FormatterBuilder {
+        self.indentation = enabled;
+        self
+    }
+
+    /// The rules for breaking and joining lines to fit into
+    /// `Config::max_width`.
+    pub fn wrapping(mut self, enabled: bool) -> FormatterBuilder {
+        self.wrapping = enabled;
+        self
+    }
+
+    /// Adds spacing rules on top of the built-in ones, for example, the house
+    /// rules of a project. They are applied after the built-in rules, even if
+    /// the latter are turned off.
+    ///
+    /// ```
+    /// use nixpkgs_fmt::{Formatter, SpacingDsl};
+    /// use rnix::SyntaxKind::{NODE_LIST, TOKEN_SQUARE_B_OPEN};
+    ///
+    /// fn house_rules(dsl: &mut SpacingDsl) {
+    ///     dsl.rule("No space after [").inside(NODE_LIST).after(TOKEN_SQUARE_B_OPEN).no_space();
+    /// }
+    ///
+    /// let formatter = Formatter::builder().spacing_rules(house_rules).build();
+    /// assert_eq!(formatter.format("[ 1 2 ]"), "[1 2 ]\n");
+    /// ```
+    pub fn spacing_rules(mut self, rules: fn(&mut SpacingDsl)) -> FormatterBuilder {
+        self.extra_spacing.push(rules);
+        self
+    }
+
+    /// Adds indentation rules on top of the built-in ones. Where an added rule
+    /// applies, it takes precedence over the built-in rules.
+    pub fn indentation_rules(mut self, rules: fn(&mut IndentDsl)) -> FormatterBuilder {
+        self.extra_indentation.push(rules);
+        self
+    }
+
+    /// Routes all the edits through the `hook`. This replaces the previously
+    /// set hook.
+    pub fn hook(mut self, hook: impl NodeHook + 'static) -> FormatterBuilder {
+        self.hook = Some(Hook(Arc::new(hook)));
+        self
+    }
+
+    pub fn build(self) -> Formatter {
+        let mut spacing =
+            if self.spacing { rules::spacing(&self.config) } else { SpacingDsl::default() };
+        for add_rules in self.extra_spacing.iter() {
+            add_rules(&mut spacing)
+        }
+        let indentation = if self.indentation {
+            let mut indentation = rules::indentation(&self.config);
+            let mut extra = IndentDsl::default();
+            for add_rules in self.extra_indentation.iter() {
+                add_rules(&mut extra)
+            }
+            indentation.extra_rules = extra.rules;
+            indentation.anchors.extend(extra.anchors);
+            Some(indentation)
+        } else {
+            None
+        };
+        let wrapping =
+            if self.wrapping { rules::wrapping(&self.config) } else { WrapDsl::default() };
+        Formatter { config: self.config, spacing, indentation, wrapping, hook: self.hook }
+    }
+}
@@ -0,0 +1,230 @@
+//! Reformatting after a small edit, without touching the rest of the file.
+//!
+//! Only the top-level entries which contain the edit, or the changes since the
+//! last save, are formatted. Each of them is formatted on its own, and then
+//! shifted to the indentation of the line it starts on. The rest of the text,
+//! and after an edit also its syntax tree, is reused as is, so it should be
+//! the result of a previous formatting.
+use rnix::{
+    SyntaxKind,
+    SyntaxKind::{
+        NODE_APPLY, NODE_ATTR_SET, NODE_INHERIT, NODE_KEY_VALUE, NODE_LAMBDA, NODE_LET_IN,
+        NODE_STRING, TOKEN_STRING_CONTENT,
+    },
+    SyntaxNode, TextRange, TextSize,
+};
+
+use crate::{config::Config, edits::TextEdit, tree_utils::walk_tokens, Formatter};
+
+/// Returns the new text with its syntax tree.
+pub(crate) fn reformat(
+    text: &str,
+    tree: &SyntaxNode,
+    edit: &TextEdit,
+    formatter: &Formatter,
+) -> (String, SyntaxNode) {
+    let range = std::ops::Range::<usize>::from(edit.range);
+    // An edit which cuts a char in half can't be applied.
+    if text.get(range.clone()).is_none() {
+        let res = formatter.format(text);
+        let tree = rnix::parse(&res).node();
+        return (res, tree);
+    }
+    if let Some(it) = reformat_around_edit(text, tree, edit, formatter) {
+        return it;
+    }
+    let mut new_text = text.to_string();
+    new_text.replace_range(range, &edit.new_text);
+    let res = formatter.format(&new_text);
+    let tree = rnix::parse(&res).node();
+    (res, tree)
+}
+
+/// Returns `None` if the edit needs the whole file to be reformatted.
+///
+/// Only the top-level entry around the edit is formatted, like
+/// `reformat_changed` does. The formatted entry replaces the old one in
+/// `tree`, so the rest of the file is neither parsed nor formatted again.
+fn reformat_around_edit(
+    text: &str,
+    tree: &SyntaxNode,
+    edit: &TextEdit,
+    formatter: &Formatter,
+) -> Option<(String, SyntaxNode)> {
+    if needs_whole_file(&formatter.config, text)
+        || needs_whole_file(&formatter.config, &edit.new_text)
+        || tree.text_range() != TextRange::up_to(TextSize::of(text))
+    {
+        return None;
+    }
+    let entries = top_level_entries(tree);
+    let entry = entries.iter().find(|it| it.text_range().contains_range(edit.range))?;
+    let range = entry.text_range();
+    let mut entry_text = text[range].to_string();
+    let edit_range = std::ops::Range::<usize>::from(edit.range - range.start());
+    entry_text.replace_range(edit_range, &edit.new_text);
+    let formatted = format_entry(text, range.start(), &entry_text, entry.kind(), formatter)?;
+    let mut res = String::with_capacity(text.len() + formatted.len() - usize::from(range.len()));
+    res.push_str(&text[..usize::from(range.start())]);
+    res.push_str(&formatted);
+    res.push_str(&text[usize::from(range.end())..]);
+    let new_entry = parse_entry(&formatted, entry.kind())?;
+    let tree = SyntaxNode::new_root(entry.replace_with(new_entry.green().to_owned()));
+    Some((res, tree))
+}
+
+/// Formats the top-level entries of `text` which contain the `changed`
+/// ranges. If some range is not inside of a single entry, the whole `text`
+/// is formatted.
+pub(crate) fn reformat_changed(text: &str, changed: &[TextRange], formatter: &Formatter) -> String {
+    match reformat_entries(text, changed, formatter) {
+        Some(it) => it,
+        None => formatter.format(text),
+    }
+}
+
+fn reformat_entries(text: &str, changed: &[TextRange], formatter: &Formatter) -> Option<String> {
+    if needs_whole_file(&formatter.config, text) {
+        return None;
+    }
+    let parse = rnix::parse(text);
+    if !parse.errors().is_empty() {
+        return None;
+    }
+    let entries = top_level_entries(&parse.node());
+    let mut touched: Vec<&SyntaxNode> = Vec::new();
+    for &range in changed {
+        let entry = entries.iter().find(|it| it.text_range().contains_range(range))?;
+        if !touched.contains(&entry) {
+            touched.push(entry)
+        }
+    }
+    touched.sort_by_key(|it| it.text_range().start());
+    let mut res = String::with_capacity(text.len());
+    let mut prev = 0;
+    for entry in touched {
+        let range = entry.text_range();
+        res.push_str(&text[prev..usize::from(range.start())]);
+        let entry_text = &text[range];
+        res.push_str(&format_entry(text, range.start(), entry_text, entry.kind(), formatter)?);
+        prev = usize::from(range.end());
+    }
+    res.push_str(&text[prev..]);
+    Some(res)
+}
+
+/// The bindings of the outermost attribute sets and `let`s, looking through
+/// the function headers and applications, like `{ lib }: mkDerivation { .. }`.
+fn top_level_entries(root: &SyntaxNode) -> Vec<SyntaxNode> {
+    let mut res = Vec::new();
+    let mut node = root.first_child();
+    while let Some(it) = node {
+        node = match it.kind() {
+            NODE_LAMBDA | NODE_APPLY | NODE_LET_IN => it.last_child(),
+            _ => None,
+        };
+        if it.kind() == NODE_ATTR_SET || it.kind() == NODE_LET_IN {
+            let entries = it.children().filter(|it| it.kind() == NODE_KEY_VALUE);
+            res.extend(entries.chain(it.children().filter(|it| it.kind() == NODE_INHERIT)))
+        }
+    }
+    res
+}
+
+/// Formats `entry`, an entry of the kind `kind` which starts at `start` in
+/// `text`, inside of an otherwise empty attribute set. Returns `None` if the
+/// entry doesn't start a line, as then the set is not laid out one entry per
+/// line.
+fn format_entry(
+    text: &str,
+    start: TextSize,
+    entry: &str,
+    kind: SyntaxKind,
+    formatter: &Formatter,
+) -> Option<String> {
+    let indent = line_indent(text, start);
+    let line_start = usize::from(start) - indent.len();
+    if line_start > 0 && !text[..line_start].ends_with('\n') {
+        return None;
+    }
+    parse_entry(entry, kind)?;
+    let wrapped = format!("{{\n{}\n}}\n", entry);
+    let formatted = formatter.format(&wrapped);
+    // The whole set is re-indented, so that the strings in the entry are
+    // recognized.
+    let formatted = reindent_lines(formatted.strip_suffix('\n')?, "  ", indent)?;
+    let entry_start = format!("{{\n{}", indent);
+    let entry_end = format!("\n{}}}", indent);
+    Some(formatted.strip_prefix(&entry_start)?.strip_suffix(&entry_end)?.to_string())
+}
+
+/// Parses `entry` as the only entry of an attribute set, and returns `None`
+/// if it is not a single valid entry of the kind `kind`.
+fn parse_entry(entry: &str, kind: SyntaxKind) -> Option<SyntaxNode> {
+    let parse = rnix::parse(&format!("{{\n{}\n}}", entry));
+    let set = parse.node().first_child().filter(|it| it.kind() == NODE_ATTR_SET)?;
+    let mut children = set.children();
+    let res = children.next().filter(|it| it.kind() == kind && it.text() == entry)?;
+    if !parse.errors().is_empty() || children.next().is_some() {
+        return None;
+    }
+    Some(res)
+}
+
+/// These depend on the columns or on the whole file, which change when a part
+/// is formatted on its own.
+fn needs_whole_file(config: &Config, text: &str) -> bool {
+    config.max_width.is_some()
+        || config.comment_width.is_some()
+        || config.align_trailing_comments
+        // Line endings and protected regions are only handled for the whole file.
+        || text.contains('\r')
+        || text.contains("nixfmt:")
+}
+
+/// The whitespace at the start of the line which contains `offset`.
+fn line_indent(text: &str, offset: TextSize) -> &str {
+    let line_start = text[..usize::from(offset)].rfind('\n').map_or(0, |it| it + 1);
+    let indent_len = text[line_start..].len() - text[line_start..].trim_start().len();
+    &text[line_start..line_start + indent_len]
+}
+
+/// Replaces `old_indent` with `new_indent` on each non-empty line but the
+/// first, except for the lines inside `"` strings, which would change their
+/// value.
+///
+/// Returns `None` if a line inside a `''` string doesn't start with
+/// `old_indent`, as the lines of the string would be shifted unevenly.
+fn reindent_lines(text: &str, old_indent: &str, new_indent: &str) -> Option<String> {
+    let root = rnix::parse(text).node();
+    // The ranges of the string contents, and whether they are in `"` strings.
+    let strings: Vec<(TextRange, bool)> = walk_tokens(&root)
+        .filter(|it| it.kind() == TOKEN_STRING_CONTENT)
+        .map(|it| {
+            let string = it.ancestors().find(|it| it.kind() == NODE_STRING);
+            let quoted = string.and_then(|it| it.first_token()).filter(|it| it.text() == "\"");
+            (it.text_range(), quoted.is_some())
+        })
+        .collect();
+    let string_at = |offset| strings.iter().find(|(range, _)| range.contains(offset));
+    let mut res = String::with_capacity(text.len());
+    let mut offset = TextSize::from(0);
+    for (idx, line) in text.split('\n').enumerate() {
+        let mut rest = line;
+        if idx > 0 {
+            res.push('\n');
+            let string = string_at(offset);
+            if !line.is_empty() && !matches!(string, Some((_, true))) {
+                res.push_str(new_indent);
+                rest = match line.strip_prefix(old_indent) {
+                    Some(it) => it,
+                    None if string.is_some() => return None,
+                    None => line,
+                };
+            }
+        }
+        res.push_str(rest);
+        offset += TextSize::of(line) + TextSize::of("\n");
+    }
+    Some(res)
+}
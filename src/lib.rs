@@ -1,17 +1,43 @@
 #[macro_use]
 mod dsl;
+mod comments;
+mod config;
+mod edits;
 mod engine;
+mod error;
+mod formatter;
+mod incremental;
 mod rules;
 mod tree_utils;
 mod pattern;
+#[cfg(feature = "capi")]
+pub mod capi;
+#[cfg(feature = "python")]
+mod python;
 
-use std::{borrow::Cow, fmt, fmt::Formatter};
+use std::{borrow::Cow, fmt, io};
 
-use rnix::{SyntaxNode, TextRange, TextSize};
+use rnix::{
+    SyntaxKind::{TOKEN_COMMENT, TOKEN_WHITESPACE},
+    SyntaxNode, SyntaxToken, TextRange, TextSize,
+};
+use rowan::GreenToken;
 use smol_str::SmolStr;
 
 use crate::dsl::RuleName;
 
+pub use crate::{
+    config::{Config, StyleVersion},
+    dsl::{
+        IndentDsl, IndentRuleBuilder, IndentValue, LintWarning, RuleConflict, RuleGroup, RuleInfo,
+        SpaceValue, SpacingDsl, SpacingRuleBuilder,
+    },
+    edits::{OutputToken, SourceMap, TextEdit},
+    error::{FmtError, SyntaxError},
+    formatter::{Formatter, FormatterBuilder, NodeHook},
+    pattern::{is_first_child, is_last_child, not, p, with_text, Pattern, PatternExt},
+};
+
 /// The result of formatting.
 ///
 /// From this Diff, you can get either the resulting `String`, or the
@@ -29,42 +55,12 @@ pub(crate) struct AtomEdit {
 }
 
 impl fmt::Display for FmtDiff {
-    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        // TODO: don't copy strings all over the place
-        let old_text = self.original_node.to_string();
-
-        let mut total_len = old_text.len();
-        let mut edits = self.text_diff();
-        edits.sort_by_key(|edit| edit.delete.start());
-
-        for atom in edits.iter() {
-            total_len += atom.insert.len();
-            total_len -= u32::from(atom.delete.end() - atom.delete.start()) as usize;
-        }
-
-        let mut buf = String::with_capacity(total_len);
-        let mut prev = 0;
-        for atom in edits.iter() {
-            let start = u32::from(atom.delete.start()) as usize;
-            let end = u32::from(atom.delete.end()) as usize;
-            if start > prev {
-                buf.push_str(&old_text[prev..start]);
-            }
-            buf.push_str(&atom.insert);
-            prev = end;
-        }
-        buf.push_str(&old_text[prev..]);
-        assert_eq!(buf.len(), total_len);
-        write!(f, "{}", buf)
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_to(f)
     }
 }
 
 impl FmtDiff {
-    /// Get the diff of deletes and inserts
-    pub(crate) fn text_diff(&self) -> Vec<AtomEdit> {
-        self.edits.iter().map(|(edit, _reason)| edit.clone()).collect()
-    }
-
     /// Whether or not formatting did caused any changes
     pub(crate) fn has_changes(&self) -> bool {
         !self.edits.is_empty()
@@ -72,45 +68,502 @@ impl FmtDiff {
 
     /// Apply the formatting suggestions and return the new node
     pub(crate) fn to_node(&self) -> SyntaxNode {
-        if self.has_changes() {
-            rnix::parse(&self.to_string()).node()
-        } else {
-            self.original_node.clone()
+        if !self.has_changes() {
+            return self.original_node.clone();
+        }
+        if let Some(node) = self.replace_whitespace() {
+            return node;
+        }
+        let len = self.edits.iter().fold(self.original_node.text().len(), |len, (edit, _)| {
+            len + TextSize::of(edit.insert.as_str()) - edit.delete.len()
+        });
+        let mut text = String::with_capacity(usize::from(len));
+        // unwrap justification: writing to a `String` doesn't fail
+        self.write_to(&mut text).unwrap();
+        rnix::parse(&text).node()
+    }
+
+    /// Applies the edits to the whitespace tokens of the tree, so that only
+    /// the nodes above the changed tokens are rebuilt, and the text isn't
+    /// parsed again.
+    ///
+    /// Returns `None` if a whitespace token would be added or removed: the
+    /// parser decides which node a new token belongs to, and removing a
+    /// whitespace may join its neighbours into a single token. Likewise, the
+    /// newline which ends a `#` comment must stay, or the comment would go on.
+    fn replace_whitespace(&self) -> Option<SyntaxNode> {
+        if self.original_node.parent().is_some() {
+            return None;
+        }
+        let mut changed: Vec<(SyntaxToken, Vec<&AtomEdit>)> = Vec::new();
+        for edit in self.sorted_edits() {
+            let is_space = |c: char| c == ' ' || c == '\t' || c == '\r' || c == '\n';
+            if !edit.insert.chars().all(is_space) {
+                return None;
+            }
+            match changed.last_mut() {
+                Some((token, edits)) if token.text_range().contains_range(edit.delete) => {
+                    edits.push(edit)
+                }
+                _ => {
+                    let token = self
+                        .original_node
+                        .token_at_offset(edit.delete.start())
+                        .filter(|it| it.kind() == TOKEN_WHITESPACE)
+                        .find(|it| it.text_range().contains_range(edit.delete))?;
+                    changed.push((token, vec![edit]))
+                }
+            }
         }
+        let mut root = self.original_node.clone();
+        // From the end, so that the ranges of the tokens before stay the same.
+        for (token, edits) in changed.into_iter().rev() {
+            let start = token.text_range().start();
+            let edits = edits.into_iter().map(|it| (it.delete - start, it.insert.as_str()));
+            let mut text = String::new();
+            let mut writer = edits::EditWriter::new(&mut text, edits);
+            // unwrap justification: writing to a `String` doesn't fail
+            writer.push(token.text()).and_then(|()| writer.finish()).unwrap();
+            let ends_comment = token
+                .prev_token()
+                .is_some_and(|it| it.kind() == TOKEN_COMMENT && it.text().starts_with('#'));
+            if text.is_empty() || (ends_comment && !text.starts_with('\n')) {
+                return None;
+            }
+            let token = root.covering_element(token.text_range()).into_token()?;
+            let green = token.replace_with(GreenToken::new(token.green().kind(), &text));
+            root = SyntaxNode::new_root(green);
+        }
+        Some(root)
+    }
+
+    /// The edits, sorted by their ranges.
+    fn sorted_edits(&self) -> Vec<&AtomEdit> {
+        let mut edits: Vec<&AtomEdit> = self.edits.iter().map(|(edit, _reason)| edit).collect();
+        edits.sort_by_key(|edit| edit.delete.start());
+        edits
+    }
+
+    /// Writes the text of the original node with the edits applied, token by
+    /// token, without copying the original text first.
+    fn write_to(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        let edits = self.sorted_edits().into_iter().map(|it| (it.delete, it.insert.as_str()));
+        let mut writer = edits::EditWriter::new(out, edits);
+        for token in tree_utils::walk_tokens(&self.original_node) {
+            writer.push(token.text())?;
+        }
+        writer.finish()
     }
 }
 
+/// Formats an already parsed file, and returns the root of the formatted one.
+///
+/// Unlike `reformat_string`, this leaves tabs and `\r\n` line endings as is.
 pub fn reformat_node(node: &SyntaxNode) -> SyntaxNode {
-    let spacing = rules::spacing();
-    let indentation = rules::indentation();
-    engine::reformat(&spacing, &indentation, node, None)
+    reformat_node_with_config(node, &Config::default())
+}
+
+/// Formats a part of a larger file, like the expression under the cursor in an
+/// editor, and returns the text to replace `node` with.
+///
+/// The whole file is formatted, so that `node` is indented the way it would be
+/// in the formatted file, but only the `node` itself is returned.
+pub fn reformat_subtree(node: &SyntaxNode) -> String {
+    let root = node.ancestors().last().unwrap_or_else(|| node.clone());
+    let formatted = reformat_node(&root);
+    // Formatting only changes whitespace and comments, so the formatted tree
+    // has the same shape.
+    match tree_utils::node_at_path(&formatted, &tree_utils::path_from_root(node)) {
+        Some(it) if it.kind() == node.kind() => it.to_string(),
+        _ => node.to_string(),
+    }
+}
+
+/// Like `reformat_node`, but with the formatting options from `config`.
+pub fn reformat_node_with_config(node: &SyntaxNode, config: &Config) -> SyntaxNode {
+    Formatter::new(config.clone()).reformat_node(node)
 }
 
 pub fn reformat_string(text: &str) -> String {
+    reformat_string_with_config(text, &Config::default())
+}
+
+/// Like `reformat_string`, but with the formatting options from `config`.
+///
+/// This is a best-effort version of `reformat`: the code around syntax errors
+/// is still formatted, and if the formatter fails, the text is left as is.
+pub fn reformat_string_with_config(text: &str, config: &Config) -> String {
+    reformat_with_diagnostics(text, config).text
+}
+
+/// Formats `text`, or fails if it is not valid Nix.
+pub fn reformat(text: &str, config: &Config) -> Result<String, FmtError> {
+    let errors = syntax_errors(text);
+    if !errors.is_empty() {
+        return Err(FmtError::Parse(errors));
+    }
+    reformat_text(text, &Formatter::new(config.clone()), true)
+}
+
+/// The result of `reformat_with_diagnostics`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Formatted {
+    pub text: String,
+    /// The syntax errors in the input. The code around them is formatted
+    /// anyway.
+    pub diagnostics: Vec<SyntaxError>,
+}
+
+/// Like `reformat_string_with_config`, but also returns the syntax errors, so
+/// that the caller could decide whether to use the result.
+pub fn reformat_with_diagnostics(text: &str, config: &Config) -> Formatted {
+    reformat_with_formatter(text, &Formatter::new(config.clone()))
+}
+
+pub(crate) fn reformat_with_formatter(text: &str, formatter: &Formatter) -> Formatted {
+    let diagnostics = syntax_errors(text);
+    let formatted = reformat_text(text, formatter, diagnostics.is_empty());
+    Formatted { text: formatted.unwrap_or_else(|_| text.to_string()), diagnostics }
+}
+
+fn syntax_errors(text: &str) -> Vec<SyntaxError> {
+    let len = TextSize::of(text);
+    rnix::parse(text).errors().iter().map(|it| SyntaxError::new(it, len)).collect()
+}
+
+/// Formats `text`. If `check_output` is set, makes sure that the result is
+/// valid Nix, which makes sense only if the input is valid.
+fn reformat_text(
+    text: &str,
+    formatter: &Formatter,
+    check_output: bool,
+) -> Result<String, FmtError> {
+    let (diff, line_endings) = reformat_unix_text(text, formatter)?;
+    let res = formatted_text(&diff, check_output)?;
+    let res = match line_endings {
+        LineEndings::Unix => res,
+        LineEndings::Dos => convert_to_dos_line_endings(res),
+    };
+    Ok(res)
+}
+
+/// The text of `diff`. If `check_output` is set, fails if it is not valid Nix.
+///
+/// The whole text is checked, as the code may have been broken by any phase
+/// before the one of `diff`.
+fn formatted_text(diff: &FmtDiff, check_output: bool) -> Result<String, FmtError> {
+    let res = diff.to_string();
+    if check_output {
+        if let Some(error) = syntax_errors(&res).first() {
+            return Err(FmtError::Internal(format!("formatted code is invalid: {}", error)));
+        }
+    }
+    Ok(res)
+}
+
+/// Like `reformat_string_with_config`, but writes the result to `out`
+/// instead of returning it as a `String`.
+///
+/// The result is the same, including the check that the formatted code of
+/// valid input is valid. For that check, the formatted text of valid input is
+/// still built in memory. Only the text of invalid input, which isn't
+/// checked, is written while the edits of the last phase are applied. Either
+/// way, the trees of the input and of every phase but the last are kept in
+/// memory until the end.
+pub fn reformat_to<W: io::Write>(text: &str, config: &Config, out: &mut W) -> io::Result<()> {
+    reformat_to_with_formatter(text, &Formatter::new(config.clone()), out)
+}
+
+fn reformat_to_with_formatter<W: io::Write>(
+    text: &str,
+    formatter: &Formatter,
+    out: &mut W,
+) -> io::Result<()> {
+    let check_output = syntax_errors(text).is_empty();
+    let checked = reformat_unix_text(text, formatter).and_then(|(diff, line_endings)| {
+        let res = if check_output { Some(formatted_text(&diff, true)?) } else { None };
+        Ok((diff, res, line_endings))
+    });
+    let (diff, res, line_endings) = match checked {
+        Ok(it) => it,
+        Err(_) => {
+            out.write_all(text.as_bytes())?;
+            return out.flush();
+        }
+    };
+    let mut writer = IoWriter { out: &mut *out, line_endings, error: None };
+    let res = match res {
+        Some(res) => fmt::Write::write_str(&mut writer, &res),
+        None => diff.write_to(&mut writer),
+    };
+    if res.is_err() {
+        // `IoWriter` fails only if `out` does.
+        return Err(writer.error.unwrap_or_else(|| io::ErrorKind::Other.into()));
+    }
+    out.flush()
+}
+
+/// Adapts an `io::Write` for `FmtDiff::write_to`, converting the line endings
+/// on the fly.
+struct IoWriter<W> {
+    out: W,
+    line_endings: LineEndings,
+    /// The error of `out`, as `fmt::Error` can't carry it.
+    error: Option<io::Error>,
+}
+
+impl<W: io::Write> fmt::Write for IoWriter<W> {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        let res = match self.line_endings {
+            LineEndings::Unix => self.out.write_all(text.as_bytes()),
+            LineEndings::Dos => text.split('\n').enumerate().try_for_each(|(idx, line)| {
+                if idx > 0 {
+                    self.out.write_all(b"\r\n")?;
+                }
+                self.out.write_all(line.as_bytes())
+            }),
+        };
+        res.map_err(|err| {
+            self.error = Some(err);
+            fmt::Error
+        })
+    }
+}
+
+/// Formats `text` with the line endings converted to `\n`, and returns the
+/// line endings to convert the result back to.
+///
+/// The edits of the last phase are left unapplied, so that the result could be
+/// written out without building the formatted tree.
+fn reformat_unix_text(
+    text: &str,
+    formatter: &Formatter,
+) -> Result<(FmtDiff, LineEndings), FmtError> {
     let (mut text, line_endings) = convert_to_unix_line_endings(text);
 
     // Forcibly convert tabs to spaces as a pre-pass
     if text.contains('\t') {
-        text = Cow::Owned(text.replace('\t', "  "))
+        text = Cow::Owned(expand_tabs(&text))
     }
 
     let ast = rnix::parse(&*text);
     let root_node = ast.node();
-    let res = reformat_node(&root_node).to_string();
-    match line_endings {
-        LineEndings::Unix => res,
-        LineEndings::Dos => convert_to_dos_line_endings(res),
+    Ok((formatter.try_reformat_diff(&root_node)?, line_endings))
+}
+
+/// Checks whether `reformat_string_with_config` would leave `text` as is.
+///
+/// This is cheaper than formatting and comparing, as the tree of the formatted
+/// text is never built.
+pub fn is_formatted(text: &str, config: &Config) -> bool {
+    is_formatted_with_formatter(text, &Formatter::new(config.clone()))
+}
+
+pub(crate) fn is_formatted_with_formatter(text: &str, formatter: &Formatter) -> bool {
+    let (unix_text, line_endings) = convert_to_unix_line_endings(text);
+    if let LineEndings::Dos = line_endings {
+        // Lone `\n`s would be converted to `\r\n`.
+        if text.matches('\n').count() != text.matches("\r\n").count() {
+            return false;
+        }
+    }
+    let text = unix_text;
+    if text.contains('\t') && expand_tabs(&text) != *text {
+        return false;
     }
+    let node = rnix::parse(&text).node();
+    // If the formatter fails, it leaves the text as is.
+    error::catch_internal(|| {
+        engine::is_formatted(
+            &formatter.spacing,
+            formatter.indentation.as_ref(),
+            &formatter.wrapping,
+            &formatter.config,
+            formatter.hook.as_ref().map(|it| &*it.0),
+            &node,
+        )
+    })
+    .unwrap_or(true)
 }
 
-pub fn explain(text: &str) -> String {
+/// Like `reformat_string_with_config`, but returns the changes to the `text`
+/// instead of the formatted text.
+///
+/// The edits don't overlap, and are sorted by their ranges.
+pub fn reformat_edits(text: &str, config: &Config) -> Vec<TextEdit> {
+    edits::diff(text, &reformat_string_with_config(text, config))
+}
+
+/// Like `reformat_string_with_config`, but also returns the mapping from the
+/// offsets in `text` to the offsets in the result.
+pub fn reformat_with_source_map(text: &str, config: &Config) -> (String, SourceMap) {
+    let res = reformat_string_with_config(text, config);
+    let source_map = SourceMap::new(edits::diff(text, &res));
+    (res, source_map)
+}
+
+/// Fixes the spacing within lines, like `a  ==b`, but leaves the line breaks
+/// and the indentation as they are.
+///
+/// This is meant for adopting the formatter gradually, without re-indenting
+/// whole files at once.
+pub fn reformat_whitespace(text: &str, config: &Config) -> String {
+    let formatter =
+        Formatter::builder().config(config.clone()).indentation(false).wrapping(false).build();
+    let edits: Vec<TextEdit> = edits::diff(text, &formatter.format(text))
+        .into_iter()
+        .filter(|edit| !edits::changes_lines(text, edit))
+        .collect();
+    edits::apply(text, &edits)
+}
+
+/// Like `reformat_string_with_config`, but also returns the tokens of the
+/// result, so that highlighters and the like don't need to parse it again.
+pub fn reformat_with_tokens(text: &str, config: &Config) -> (String, Vec<OutputToken>) {
+    let res = reformat_string_with_config(text, config);
+    let tokens = edits::output_tokens(text, &res);
+    (res, tokens)
+}
+
+/// Formats `text` after applying `edit` to it, for example, on each keystroke
+/// in an editor. `tree` is the syntax tree of `text`, which should already be
+/// formatted.
+///
+/// Only the top-level entry around the edit is formatted, like
+/// `reformat_changed` does, and the rest of the text is left as is. Neither
+/// the rest of the text nor its part of `tree` is processed again. If the
+/// edit is not inside of a single entry, or with `Config::max_width` and
+/// other column-dependent options, the whole file is formatted. An `edit`
+/// which doesn't fit into `text` is ignored.
+///
+/// Returns the new text with its syntax tree, for the next edit.
+pub fn reformat_incremental(
+    text: &str,
+    tree: &SyntaxNode,
+    edit: &TextEdit,
+    config: &Config,
+) -> (String, SyntaxNode) {
+    incremental::reformat(text, tree, edit, &Formatter::new(config.clone()))
+}
+
+/// Formats `text`, where only the `changed` ranges differ from a previous
+/// formatting, for example, on each save in an editor.
+///
+/// Only the top-level entries around the changes are formatted, which are
+/// the bindings of the outermost attribute set or `let`, through function
+/// headers and applications like `{ lib }: mkDerivation { .. }`. If a range
+/// is not inside of a single entry, or with `Config::max_width` and other
+/// column-dependent options, the whole file is formatted.
+pub fn reformat_changed(text: &str, changed: &[TextRange], config: &Config) -> String {
+    Formatter::new(config.clone()).format_changed(text, changed)
+}
+
+/// Like `reformat_edits`, but only returns the edits which touch `range`,
+/// expanded to whole lines.
+///
+/// This is meant for formatting a selection in an editor. Multiline tokens,
+/// like strings, which `range` touches, are formatted completely.
+pub fn reformat_range(text: &str, range: TextRange, config: &Config) -> Vec<TextEdit> {
+    let range = edits::expand_range(text, range);
+    reformat_edits(text, config)
+        .into_iter()
+        .filter(|edit| {
+            range.contains_range(edit.range)
+                || (edit.range.start() < range.end() && range.start() < edit.range.end())
+        })
+        .collect()
+}
+
+/// Lists the named built-in rules, for documentation generators and style
+/// analysis tools.
+pub fn builtin_rules() -> Vec<RuleInfo> {
+    let mut res: Vec<RuleInfo> = Vec::new();
+    let mut add = |group: RuleGroup, name: RuleName| {
+        let info = RuleInfo { name: name.as_str(), group };
+        // `between` rules are stored as two rules with the same name.
+        if !res.contains(&info) {
+            res.push(info)
+        }
+    };
+    for name in rules::spacing(&Config::default()).rules.iter().filter_map(|it| it.name) {
+        add(RuleGroup::Spacing, name)
+    }
+    for rule in rules::indentation(&Config::default()).rules.iter() {
+        add(RuleGroup::Indentation, rule.name)
+    }
+    let wrapping = rules::wrapping(&Config::default());
+    for name in
+        wrapping.rules.iter().map(|it| it.name).chain(wrapping.joins.iter().map(|it| it.name))
+    {
+        add(RuleGroup::Wrapping, name)
+    }
+    for rule in rules::lints(&Config::default()).rules.iter() {
+        add(RuleGroup::Lint, rule.name)
+    }
+    res
+}
+
+/// Flags the code which formatting doesn't fix: lines longer than
+/// `Config::max_width` (100 columns if it is not set), deeply nested
+/// attribute sets and indentation with both tabs and spaces.
+///
+/// The warnings are sorted by their ranges, which are in `text` as is. If the
+/// linter fails, there are none.
+///
+/// ```
+/// let warnings = nixpkgs_fmt::lint("{\n\t a = 1;\n}\n", &nixpkgs_fmt::Config::default());
+/// assert_eq!(warnings[0].rule, "Indentation mixes tabs and spaces");
+/// ```
+pub fn lint(text: &str, config: &Config) -> Vec<LintWarning> {
+    let lints = rules::lints(config);
+    let node = rnix::parse(text).node();
+    error::catch_internal(|| Ok(engine::lint(&lints, &node))).unwrap_or_default()
+}
+
+/// An edit made by formatting, with the rule which made it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExplainedEdit {
+    pub edit: TextEdit,
+    /// The name of the rule, as in `builtin_rules`. `None` for the edits which
+    /// are not made by a named rule.
+    pub rule: Option<&'static str>,
+}
+
+/// Returns the edits behind `explain`, with the rules which made them.
+///
+/// These are the edits of the spacing rules or, if there are none, of the
+/// indentation rules. The ranges are in `text` with line endings converted
+/// to `\n`.
+pub fn explain_edits(text: &str) -> Vec<ExplainedEdit> {
     let (text, _line_endings) = convert_to_unix_line_endings(text);
     let ast = rnix::parse(&*text);
-    let spacing = rules::spacing();
-    let indentation = rules::indentation();
+    let spacing = rules::spacing(&Config::default());
+    let indentation = rules::indentation(&Config::default());
+    let wrapping = rules::wrapping(&Config::default());
     let mut explanation = Vec::new();
-    engine::reformat(&spacing, &indentation, &ast.node(), Some(&mut explanation));
+    let _ = error::catch_internal(|| {
+        engine::reformat(
+            &spacing,
+            Some(&indentation),
+            &wrapping,
+            &Config::default(),
+            None,
+            &ast.node(),
+            Some(&mut explanation),
+        )
+    });
+    explanation
+        .into_iter()
+        .map(|(edit, reason)| ExplainedEdit {
+            edit: TextEdit { range: edit.delete, new_text: edit.insert.to_string() },
+            rule: reason.map(RuleName::as_str),
+        })
+        .collect()
+}
 
+pub fn explain(text: &str) -> String {
+    let explanation = explain_edits(text);
+    let (text, _line_endings) = convert_to_unix_line_endings(text);
     let mut buf = String::new();
     let mut line_start: TextSize = 0.into();
     for line in text.to_string().lines() {
@@ -119,8 +572,8 @@ pub fn explain(text: &str) -> String {
 
         buf.push_str(line);
         let mut first = true;
-        for (edit, reason) in explanation.iter() {
-            if line_range.contains(edit.delete.end()) {
+        for ExplainedEdit { edit, rule } in explanation.iter() {
+            if line_range.contains(edit.range.end()) {
                 if first {
                     first = false;
                     buf.push_str("  # ")
@@ -129,11 +582,11 @@ pub fn explain(text: &str) -> String {
                 }
                 buf.push_str(&format!(
                     "[{}; {}): ",
-                    usize::from(edit.delete.start()),
-                    usize::from(edit.delete.end())
+                    usize::from(edit.range.start()),
+                    usize::from(edit.range.end())
                 ));
-                match reason {
-                    Some(reason) => buf.push_str(&reason.to_string()),
+                match rule {
+                    Some(rule) => buf.push_str(rule),
                     None => buf.push_str("unnamed rule"),
                 }
             }
@@ -145,6 +598,22 @@ pub fn explain(text: &str) -> String {
     buf
 }
 
+/// Replaces tabs with spaces everywhere except the regions where formatting is
+/// turned off.
+fn expand_tabs(text: &str) -> String {
+    let protected = engine::protected_ranges(&rnix::parse(text).node());
+    let mut buf = String::with_capacity(text.len());
+    let mut prev = 0;
+    for range in protected {
+        let (start, end) = (usize::from(range.start()), usize::from(range.end()));
+        buf.push_str(&text[prev..start].replace('\t', "  "));
+        buf.push_str(&text[start..end]);
+        prev = end;
+    }
+    buf.push_str(&text[prev..].replace('\t', "  "));
+    buf
+}
+
 enum LineEndings {
     Unix,
     Dos,
@@ -171,11 +640,378 @@ mod tests {
         assert_eq!(&reformat_string("{foo = 92;\r\n}"), "{\r\n  foo = 92;\r\n}\r\n")
     }
 
+    #[test]
+    fn replaces_whitespace_in_tree() {
+        let node = rnix::parse("{\n    a  = 1;\n}\n").node();
+        let diff = |edits: &[(u32, u32, &str)]| {
+            let edits = edits.iter().map(|&(start, end, insert)| {
+                let delete = TextRange::new(start.into(), end.into());
+                (AtomEdit { delete, insert: insert.into() }, None)
+            });
+            FmtDiff { original_node: node.clone(), edits: edits.collect() }
+        };
+        let check = |diff: FmtDiff, in_place: bool, after: &str| {
+            assert_eq!(diff.replace_whitespace().is_some(), in_place);
+            let node = diff.to_node();
+            assert_eq!(node.to_string(), after);
+            assert_eq!(format!("{:#?}", node), format!("{:#?}", rnix::parse(after).node()));
+        };
+        check(diff(&[(1, 6, "\n  "), (7, 9, " ")]), true, "{\n  a = 1;\n}\n");
+        check(diff(&[(8, 9, ""), (13, 13, "\n")]), true, "{\n    a = 1;\n\n}\n");
+        // A new whitespace token, and a removed one.
+        check(diff(&[(12, 12, " ")]), false, "{\n    a  = 1 ;\n}\n");
+        check(diff(&[(7, 9, "")]), false, "{\n    a= 1;\n}\n");
+        // The comment would take in `b`.
+        let delete = TextRange::new(5.into(), 8.into());
+        let edits = vec![(AtomEdit { delete, insert: " ".into() }, None)];
+        check(FmtDiff { original_node: rnix::parse("a # c\n  b").node(), edits }, false, "a # c b");
+    }
+
     #[test]
     fn converts_tabs_to_spaces() {
         assert_eq!(&reformat_string("{\n\tfoo = 92;\t}\n"), "{\n  foo = 92;\n}\n");
     }
 
+    #[test]
+    fn continuation_indent_is_configurable() {
+        let config = Config { continuation_indent: 4, ..Config::default() };
+        let input = "{\n  foo = bar\n  baz\n  quux;\n  xs = a\n  ++ b;\n}\n";
+        assert_eq!(
+            reformat_string_with_config(input, &config),
+            "{\n  foo = bar\n      baz\n      quux;\n  xs = a\n      ++ b;\n}\n"
+        );
+    }
+
+    #[test]
+    fn formatting_off_region_is_not_wrapped() {
+        let config = Config { max_width: Some(24), canonical_layout: true, ..Config::default() };
+        let input = "{\n  # nixfmt: off\n  a = [ 1\t2 3 4 5 6 7 8 ];\n  # nixfmt: on\n  b = [ 1 2 3 4 5 6 7 8 ];\n}\n";
+        assert_eq!(
+            reformat_string_with_config(input, &config),
+            "{\n  # nixfmt: off\n  a = [ 1\t2 3 4 5 6 7 8 ];\n  # nixfmt: on\n  b = [\n    1\n    2\n    3\n    4\n    5\n    6\n    7\n    8\n  ];\n}\n"
+        );
+    }
+
+    #[test]
+    fn reflows_long_comments() {
+        let config = Config { comment_width: Some(30), ..Config::default() };
+        let input = "{
+  # This comment is too long to fit into the comment width,
+  # so it is re-wrapped.
+  #
+  # A short paragraph.
+  #   indented = code;
+  # See https://example.com/a/very/long/url/which/does/not/fit
+  # - a list item which is also too long for the width
+  a = 1;
+}
+";
+        assert_eq!(
+            reformat_string_with_config(input, &config),
+            "{
+  # This comment is too long
+  # to fit into the comment
+  # width, so it is
+  # re-wrapped.
+  #
+  # A short paragraph.
+  #   indented = code;
+  # See https://example.com/a/very/long/url/which/does/not/fit
+  # - a list item which is
+  #   also too long for the
+  #   width
+  a = 1;
+}
+"
+        );
+    }
+
+    #[test]
+    fn aligns_trailing_comments() {
+        let config = Config { align_trailing_comments: true, ..Config::default() };
+        let input = "{
+  a = 1; # one
+  foo = \"bar\";  # two
+  b = 2;        # three
+
+  c = 3;    # not in the run
+}
+";
+        assert_eq!(
+            reformat_string_with_config(input, &config),
+            "{
+  a = 1;       # one
+  foo = \"bar\"; # two
+  b = 2;       # three
+
+  c = 3; # not in the run
+}
+"
+        );
+    }
+
+    #[test]
+    fn reformats_subtree_in_context() {
+        let root = rnix::parse("{\n  a = 1;\n    b = {x=1;\n  y = [1 2];};\n}\n").node();
+        let key_value =
+            root.descendants().filter(|it| it.kind() == rnix::SyntaxKind::NODE_KEY_VALUE).nth(1);
+        assert_eq!(
+            reformat_subtree(&key_value.unwrap()),
+            "b = {\n    x = 1;\n    y = [ 1 2 ];\n  };"
+        );
+    }
+
+    #[test]
+    fn returns_edits() {
+        let edits = reformat_edits("{a=1; }", &Config::default());
+        let edits: Vec<(usize, usize, &str)> = edits
+            .iter()
+            .map(|it| (it.range.start().into(), it.range.end().into(), it.new_text.as_str()))
+            .collect();
+        assert_eq!(edits, vec![(1, 1, " "), (2, 2, " "), (3, 3, " "), (7, 7, "\n")]);
+    }
+
+    #[test]
+    fn reports_syntax_errors() {
+        let config = Config::default();
+        assert_eq!(reformat("{a=1;}", &config), Ok("{ a = 1; }\n".to_string()));
+        let errors = match reformat("{\r\n\ta = ;\r\n}", &config) {
+            Err(FmtError::Parse(errors)) => errors,
+            res => panic!("expected a syntax error, got {:?}", res),
+        };
+        assert_eq!(errors[0].range, TextRange::new(8.into(), 9.into()));
+        assert_eq!(reformat_string_with_config("{a = ;}", &config), "{ a = ;}\n");
+    }
+
+    #[test]
+    fn returns_diagnostics_with_output() {
+        let res = reformat_with_diagnostics("{a=1; b = ;}", &Config::default());
+        assert_eq!(res.text, "{ a = 1; b = ;}\n");
+        assert_eq!(res.diagnostics[0].range, TextRange::new(10.into(), 11.into()));
+    }
+
+    #[test]
+    fn writes_to_io_write() {
+        let mut buf = Vec::new();
+        reformat_to("{a=1;\r\nb=2;}", &Config::default(), &mut buf).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), "{\r\n  a = 1;\r\n  b = 2;\r\n}\r\n");
+        let mut full = [0u8; 4];
+        let err = reformat_to("{a=1;}", &Config::default(), &mut &mut full[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+    }
+
+    #[test]
+    fn checks_output_written_to_io_write() {
+        fn house_rules(dsl: &mut SpacingDsl) {
+            // `thenb` is an identifier, so this breaks the code.
+            dsl.rule("No space after then")
+                .inside(rnix::SyntaxKind::NODE_IF_ELSE)
+                .after(rnix::SyntaxKind::TOKEN_THEN)
+                .priority(2)
+                .no_space();
+        }
+        let formatter = Formatter::builder().spacing_rules(house_rules).build();
+        for text in ["if a then b else c", "if a then b else c\r\n", "if a then b else c; x"].iter()
+        {
+            let mut buf = Vec::new();
+            reformat_to_with_formatter(text, &formatter, &mut buf).unwrap();
+            assert_eq!(String::from_utf8(buf).unwrap(), formatter.format(text));
+        }
+        assert_eq!(formatter.format("if a then b else c"), "if a then b else c");
+    }
+
+    #[test]
+    fn reformats_parsed_tree() {
+        let root = rnix::parse("[1 2]").node();
+        let config = Config { max_width: Some(4), ..Config::default() };
+        assert_eq!(reformat_node_with_config(&root, &config).to_string(), "[\n  1\n  2\n]\n");
+    }
+
+    #[test]
+    fn checks_if_formatted() {
+        let config = Config::default();
+        for text in ["{ a = 1; }\n", "{\r\n  a = 1;\r\n}\r\n"].iter() {
+            assert!(is_formatted(text, &config));
+        }
+        for text in ["{ a = 1; }", "{a = 1; }\n", "{\n\ta = 1;\n}\n", "{\r\n  a = 1;\n}\r\n"].iter()
+        {
+            assert!(!is_formatted(text, &config));
+        }
+        let config = Config { max_width: Some(8), ..config };
+        assert!(!is_formatted("[ 1 2 3 4 ]\n", &config));
+    }
+
+    #[test]
+    fn formats_with_rule_groups_disabled() {
+        let input = "{\n      foo =1;  bar = [1\n2];\n}\n";
+        let formatter = Formatter::builder().indentation(false).build();
+        assert_eq!(formatter.format(input), "{\n      foo = 1;\nbar = [\n1\n2\n];\n}\n");
+        let formatter = Formatter::builder().spacing(false).build();
+        assert_eq!(formatter.format(input), "{\n  foo =1;  bar = [1\n  2];\n}\n");
+    }
+
+    #[test]
+    fn applies_extra_indentation_rules() {
+        use rnix::SyntaxKind::{NODE_LIST, TOKEN_SQUARE_B_CLOSE};
+        fn house_rules(dsl: &mut IndentDsl) {
+            dsl.rule("Continuation indent in lists")
+                .inside(NODE_LIST)
+                .not_matching(TOKEN_SQUARE_B_CLOSE)
+                .set(IndentValue::Continuation);
+        }
+        let config = Config { continuation_indent: 4, ..Config::default() };
+        let formatter = Formatter::builder().config(config).indentation_rules(house_rules).build();
+        assert_eq!(formatter.format("[\n1\n2\n]"), "[\n    1\n    2\n]\n");
+    }
+
+    #[test]
+    fn fails_on_overlapping_indent_rules() {
+        use rnix::SyntaxKind::NODE_LIST;
+        fn house_rules(dsl: &mut IndentDsl) {
+            dsl.rule("Indent in lists").inside(NODE_LIST).set(IndentValue::Indent);
+        }
+        let mut formatter = Formatter::builder().indentation_rules(house_rules).build();
+        let indentation = formatter.indentation.as_mut().unwrap();
+        let extra: Vec<_> = indentation.extra_rules.drain(..).collect();
+        indentation.rules.extend(extra);
+        let node = rnix::parse("[\n1\n]").node();
+        match formatter.try_reformat_node(&node) {
+            Err(FmtError::Internal(message)) => assert!(message.contains("both match")),
+            res => panic!("expected an internal error, got {:?}", res),
+        }
+        assert_eq!(formatter.format("[\n1\n]"), "[\n1\n]");
+    }
+
+    #[test]
+    fn routes_edits_through_hook() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
+        struct NoNewlines(Arc<AtomicUsize>);
+
+        impl NodeHook for NoNewlines {
+            fn on_edit(&self, _node: &rnix::SyntaxNode, edit: &TextEdit) -> bool {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                !edit.new_text.contains('\n')
+            }
+        }
+
+        let seen = Arc::new(AtomicUsize::new(0));
+        let config = Config { max_width: Some(10), ..Config::default() };
+        let formatter =
+            Formatter::builder().config(config).hook(NoNewlines(Arc::clone(&seen))).build();
+        assert_eq!(formatter.format("{ a=1; b=2; c=3; }"), "{ a = 1; b = 2; c = 3; }");
+        assert!(seen.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    fn shares_formatter_between_threads() {
+        let formatter = std::sync::Arc::new(Formatter::new(Config::default()));
+        let threads: Vec<_> = (0..4)
+            .map(|idx| {
+                let formatter = std::sync::Arc::clone(&formatter);
+                std::thread::spawn(move || formatter.format(&format!("[{}]", idx)))
+            })
+            .collect();
+        for (idx, thread) in threads.into_iter().enumerate() {
+            assert_eq!(thread.join().unwrap(), format!("[ {} ]\n", idx));
+        }
+    }
+
+    #[test]
+    fn reformats_incrementally() {
+        let text = "{\n  a = {\n    b = 1;\n  };\n  c = \"x\";\n}\n";
+        let tree = rnix::parse(text).node();
+        let edit = TextEdit { range: TextRange::empty(20.into()), new_text: "d=[2 3];".into() };
+        let (res, new_tree) = reformat_incremental(text, &tree, &edit, &Config::default());
+        assert_eq!(res, "{\n  a = {\n    b = 1;\n    d = [ 2 3 ];\n  };\n  c = \"x\";\n}\n");
+        assert_eq!(new_tree.to_string(), res);
+        // The other entries are reused, not parsed again.
+        let last_entry = |tree: &SyntaxNode| tree.first_child()?.last_child();
+        let (old, new) = (last_entry(&tree).unwrap(), last_entry(&new_tree).unwrap());
+        assert!(std::ptr::eq(old.green(), new.green()));
+        // The body of the lambda is indented the way it is in the whole file.
+        let text = "{}:\n{\n  foo = x:\n    92;\n}\n";
+        let edit = TextEdit { range: TextRange::empty(22.into()), new_text: " ".into() };
+        let (res, _tree) =
+            reformat_incremental(text, &rnix::parse(text).node(), &edit, &Config::default());
+        assert_eq!(res, "{}:\n{\n  foo = x:\n    9 2;\n}\n");
+    }
+
+    #[test]
+    fn reformats_changed_entries() {
+        let text =
+            "{ lib }:\nmkDerivation {\n  a =  1;\n  b = [1\n2];\n  c = ''\n    x\n  '';\n}\n";
+        let changed = |start: u32, end: u32| [TextRange::new(start.into(), end.into())];
+        // The unchanged `a` is left as is.
+        assert_eq!(
+            reformat_changed(text, &changed(40, 44), &Config::default()),
+            "{ lib }:\nmkDerivation {\n  a =  1;\n  b = [\n    1\n    2\n  ];\n  c = ''\n    x\n  '';\n}\n"
+        );
+        // A change between the entries needs the whole file.
+        assert_eq!(
+            reformat_changed(text, &changed(34, 35), &Config::default()),
+            reformat_string(text)
+        );
+    }
+
+    #[test]
+    fn reformats_only_whitespace_within_lines() {
+        let text = "{\n      x = a  ==b;\n  c = [1\n  2];}";
+        assert_eq!(
+            reformat_whitespace(text, &Config::default()),
+            "{\n      x = a == b;\n  c = [1\n  2];}"
+        );
+    }
+
+    #[test]
+    fn explains_edits() {
+        let edits = explain_edits("{\nfoo =1;\n}\n");
+        assert_eq!(
+            edits,
+            vec![ExplainedEdit {
+                edit: TextEdit { range: TextRange::empty(7.into()), new_text: " ".to_string() },
+                rule: Some("Space after ="),
+            }]
+        );
+    }
+
+    #[test]
+    fn lints_without_edits() {
+        let config = Config { max_width: Some(12), ..Config::default() };
+        let text = "{\r\n  a = \"too long\";\r\n\t b = 1;\r\n}\r\n";
+        let warnings: Vec<(&str, &str)> =
+            lint(text, &config).into_iter().map(|it| (&text[it.range], it.rule)).collect();
+        assert_eq!(
+            warnings,
+            vec![("ong\";", "Line too long"), ("\t ", "Indentation mixes tabs and spaces")]
+        );
+        assert!(lint("{ a = 1; }", &config).is_empty());
+    }
+
+    #[test]
+    fn lists_builtin_rules() {
+        let rules = builtin_rules();
+        let space_after_eq = RuleInfo { name: "Space after =", group: RuleGroup::Spacing };
+        assert_eq!(rules.iter().filter(|it| **it == space_after_eq).count(), 1);
+        for group in [RuleGroup::Spacing, RuleGroup::Indentation, RuleGroup::Wrapping].iter() {
+            assert!(rules.iter().any(|it| it.group == *group));
+        }
+    }
+
+    #[test]
+    fn reformats_range() {
+        let text = "{\n a =1;\n    b =2;\n   c =3;\n}\n";
+        let edits = reformat_range(text, TextRange::new(12.into(), 13.into()), &Config::default());
+        let edits: Vec<(usize, usize, &str)> = edits
+            .iter()
+            .map(|it| (it.range.start().into(), it.range.end().into(), it.new_text.as_str()))
+            .collect();
+        assert_eq!(edits, vec![(11, 13, ""), (16, 16, " ")]);
+    }
+
     #[test]
     fn explain_smoke_test() {
         let input = "{\nfoo =1;\n}\n";
@@ -1,21 +1,27 @@
 use std::{
+    collections::HashSet,
+    env,
     fmt::Write,
     fs,
     io::{stdin, Read},
     path::{Path, PathBuf},
+    sync::Arc,
     thread,
 };
 
 use clap::{App, Arg};
-use crossbeam_channel::{unbounded, Receiver, Sender};
+use crossbeam_channel::{bounded, unbounded, Sender};
+use nixpkgs_fmt::{Config, Formatter};
 use rnix::types::TypedNode;
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
-type FormatResult = (PathBuf, FormatStatus);
 enum FormatStatus {
     Change,
-    NoChange,
+    /// The file is formatted, and has the content hash `hash`.
+    NoChange {
+        hash: u128,
+    },
 }
 
 fn main() {
@@ -29,12 +35,18 @@ fn main() {
 struct Args {
     src: Src,
     operation: Operation,
+    /// The number of threads for formatting several files.
+    jobs: usize,
+    /// Where to remember the files which are already formatted, `None` to not
+    /// remember them.
+    cache_dir: Option<PathBuf>,
 }
 
 #[derive(Debug)]
 enum Operation {
     Fmt { write_changes: bool, fail_on_changes: bool },
     Explain,
+    Lint,
     Parse { output_format: OutputFormat },
 }
 
@@ -61,6 +73,12 @@ fn parse_args() -> Result<Args> {
                 .conflicts_with("explain")
                 .help("File to reformat in place. If no file is passed, read from stdin."),
         )
+        .arg(
+            Arg::with_name("lint")
+                .long("lint")
+                .conflicts_with_all(&["parse", "explain", "check"])
+                .help("Report the problems which formatting doesn't fix, without changing files"),
+        )
         .arg(
             Arg::with_name("parse")
                 .long("parse")
@@ -91,6 +109,27 @@ fn parse_args() -> Result<Args> {
                 .conflicts_with("explain")
                 .help("Only test if the formatter would produce differences"),
         )
+        .arg(
+            Arg::with_name("jobs")
+                .short("j")
+                .long("jobs")
+                .value_name("N")
+                .takes_value(true)
+                .help("Format up to N files at once [default: the number of CPUs]"),
+        )
+        .arg(
+            Arg::with_name("cache-dir")
+                .long("cache-dir")
+                .value_name("DIR")
+                .takes_value(true)
+                .help("Remember the formatted files in DIR [default: ~/.cache/nixpkgs-fmt]"),
+        )
+        .arg(
+            Arg::with_name("no-cache")
+                .long("no-cache")
+                .conflicts_with("cache-dir")
+                .help("Check all the files, even if they were formatted on the previous run"),
+        )
         .get_matches_safe()?;
 
     let src = match matches.values_of("srcs") {
@@ -103,6 +142,8 @@ fn parse_args() -> Result<Args> {
             _ => OutputFormat::Rnix,
         };
         Operation::Parse { output_format }
+    } else if matches.is_present("lint") {
+        Operation::Lint
     } else if matches.is_present("explain") {
         Operation::Explain
     } else if matches.is_present("check") {
@@ -111,7 +152,21 @@ fn parse_args() -> Result<Args> {
         Operation::Fmt { write_changes: true, fail_on_changes: false }
     };
 
-    Ok(Args { operation, src })
+    let jobs = match matches.value_of("jobs") {
+        Some(jobs) => match jobs.parse::<usize>() {
+            Ok(it) if it > 0 => it,
+            _ => return Err(format!("error: invalid number of jobs: {}", jobs).into()),
+        },
+        None => thread::available_parallelism().map_or(1, |it| it.get()),
+    };
+
+    let cache_dir = if matches.is_present("no-cache") {
+        None
+    } else {
+        matches.value_of("cache-dir").map(PathBuf::from).or_else(default_cache_dir)
+    };
+
+    Ok(Args { operation, src, jobs, cache_dir })
 }
 
 fn try_main(args: Args) -> Result<()> {
@@ -129,46 +184,46 @@ fn try_main(args: Args) -> Result<()> {
                 }
             }
             Src::Paths(paths) => {
-                let (sender, receiver): (Sender<FormatResult>, Receiver<FormatResult>) =
-                    unbounded();
-
-                // Reducer, collect all the paths and statuses that have been seen
-                let reducer = thread::spawn(move || {
-                    let mut files_count = 0;
-                    let mut files_changed = 0;
-                    for (file_path, status) in receiver {
-                        files_count += 1;
-                        if let FormatStatus::Change = status {
+                let config = Config::default();
+                let cache = Arc::new(Cache::load(args.cache_dir.as_deref(), &config));
+                let formatter = Formatter::new(config);
+                let job_cache = Arc::clone(&cache);
+                let results = run_parallel(paths, args.jobs, move |path| {
+                    reformat_file(path, write_changes, &formatter, &job_cache)
+                });
+                cache.save(results.iter().filter_map(|(_path, status)| match status {
+                    Ok(FormatStatus::NoChange { hash }) => Some(*hash),
+                    _ => None,
+                }));
+
+                let files_count = results.len();
+                let mut files_changed = 0;
+                let mut files_failed = 0;
+                for (file_path, status) in results.iter() {
+                    match status {
+                        Ok(FormatStatus::Change) => {
                             files_changed += 1;
                             println!("{}", file_path.display());
                         }
-                    }
-                    (files_count, files_changed)
-                });
-
-                // Start formatting
-                for path in paths {
-                    if path.is_dir() {
-                        reformat_dir_in_place(path, write_changes, &sender)?;
-                    } else {
-                        let status = reformat_file(path, write_changes)?;
-                        // unwrap justification: the channel only fails if it's closed on either
-                        // end. The drop() happens below.
-                        sender.send((path.clone(), status)).unwrap()
+                        Ok(FormatStatus::NoChange { .. }) => {}
+                        Err(err) => {
+                            files_failed += 1;
+                            eprintln!("error: {}: {}", file_path.display(), err);
+                        }
                     }
                 }
 
-                // Time to collect the results
-                drop(sender);
-                // unwrap justification: the reducer code has no exceptions
-                let (files_count, files_changed) = reducer.join().unwrap();
-
                 let text = if write_changes {
                     "have been reformatted"
                 } else {
                     "would have been reformatted"
                 };
                 eprintln!("{} / {} {}", files_changed, files_count, text);
+                if files_failed > 0 {
+                    return Err(
+                        format!("error: {} files could not be formatted", files_failed).into()
+                    );
+                }
                 if fail_on_changes && files_changed > 0 {
                     return Err("error: fail on changes".into());
                 }
@@ -190,6 +245,28 @@ fn try_main(args: Args) -> Result<()> {
             };
             print!("{}", res)
         }
+        Operation::Lint => {
+            let results = match &args.src {
+                Src::Stdin => {
+                    let text = read_stdin_to_string()?;
+                    vec![(PathBuf::from("<stdin>"), Ok(lint_text(&text)))]
+                }
+                Src::Paths(paths) => {
+                    run_parallel(paths, args.jobs, |path| Ok(lint_text(&fs::read_to_string(path)?)))
+                }
+            };
+            let mut warnings_count = 0;
+            for (path, warnings) in results {
+                let warnings = warnings.map_err(|err| format!("{}: {}", path.display(), err))?;
+                for (line, column, rule) in warnings {
+                    warnings_count += 1;
+                    println!("{}:{}:{}: {}", path.display(), line, column, rule);
+                }
+            }
+            if warnings_count > 0 {
+                return Err(format!("error: {} lint warnings", warnings_count).into());
+            }
+        }
         Operation::Explain => {
             let input = read_stdin_to_string()?;
             let output = nixpkgs_fmt::explain(&input);
@@ -219,58 +296,209 @@ fn read_single_source(src: &Src) -> Result<String> {
     Ok(res)
 }
 
-fn reformat_dir_in_place(
-    dir: &Path,
-    write_changes: bool,
-    sender: &Sender<FormatResult>,
-) -> Result<()> {
-    let nix_file_types = {
-        let mut builder = ignore::types::TypesBuilder::new();
-        builder.add_defaults();
-        // unwrap justification: this would be a bug in the code, logic error
-        builder.add("nix", "*.nix").unwrap();
-        builder.select("nix");
-        // unwrap justification: this would be a bug in the code, logic error
-        builder.build().unwrap()
-    };
+/// Lints `text`, with the warnings at one-based lines and columns.
+fn lint_text(text: &str) -> Vec<(usize, usize, &'static str)> {
+    nixpkgs_fmt::lint(text, &Config::default())
+        .into_iter()
+        .map(|warning| {
+            let (line, column) = line_column(text, warning.range.start().into());
+            (line, column, warning.rule)
+        })
+        .collect()
+}
+
+/// One-based line and column of a byte `offset` in `text`.
+fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |it| it + 1);
+    (before.matches('\n').count() + 1, before[line_start..].chars().count() + 1)
+}
 
-    ignore::WalkBuilder::new(dir).types(nix_file_types).threads(8).build_parallel().run(
+fn nix_file_types() -> ignore::types::Types {
+    let mut builder = ignore::types::TypesBuilder::new();
+    builder.add_defaults();
+    // unwrap justification: this would be a bug in the code, logic error
+    builder.add("nix", "*.nix").unwrap();
+    builder.select("nix");
+    // unwrap justification: this would be a bug in the code, logic error
+    builder.build().unwrap()
+}
+
+/// Runs `job` on each of `paths`, and on the `.nix` files in the directories
+/// among them, on `jobs` threads.
+///
+/// The directories are walked in parallel with the jobs, and the idle threads
+/// take the next file from a shared queue, so that a few big files don't hold
+/// up the rest. The results are sorted by path, so the output doesn't depend
+/// on the scheduling.
+fn run_parallel<R, F>(paths: &[PathBuf], jobs: usize, job: F) -> Vec<(PathBuf, JobResult<R>)>
+where
+    R: Send + 'static,
+    F: Fn(&Path) -> Result<R> + Send + Sync + 'static,
+{
+    // Bounded, so that the walk doesn't run far ahead of the jobs.
+    let (path_sender, path_receiver) = bounded::<PathBuf>(jobs * 4);
+    let (result_sender, result_receiver) = unbounded();
+    let job = Arc::new(job);
+    let workers: Vec<thread::JoinHandle<()>> = (0..jobs)
+        .map(|_| {
+            let (paths, results) = (path_receiver.clone(), result_sender.clone());
+            let job = Arc::clone(&job);
+            thread::spawn(move || {
+                for path in paths {
+                    let res = job(&path).map_err(|err| err.to_string());
+                    // unwrap justification: the receiver outlives the workers
+                    results.send((path, res)).unwrap()
+                }
+            })
+        })
+        .collect();
+    drop((path_receiver, result_sender));
+
+    for path in paths {
+        if path.is_dir() {
+            walk_nix_files(path, jobs, &path_sender);
+        } else {
+            // unwrap justification: the workers only stop once the sender is dropped
+            path_sender.send(path.clone()).unwrap()
+        }
+    }
+    drop(path_sender);
+
+    let mut res: Vec<(PathBuf, JobResult<R>)> = result_receiver.iter().collect();
+    for worker in workers {
+        // unwrap justification: a panic in a job is a bug, let it through
+        worker.join().unwrap()
+    }
+    res.sort_by(|(lhs, _), (rhs, _)| lhs.cmp(rhs));
+    res
+}
+
+/// Errors are sent between threads as text.
+type JobResult<R> = std::result::Result<R, String>;
+
+fn walk_nix_files(dir: &Path, jobs: usize, sender: &Sender<PathBuf>) {
+    ignore::WalkBuilder::new(dir).types(nix_file_types()).threads(jobs).build_parallel().run(
         move || {
             let s = sender.clone();
             Box::new(move |entry| {
-                match reformat_dir_entry(entry, write_changes, &s) {
+                match entry {
                     Err(err) => eprintln!("error: {}", err),
-                    Ok(()) => {}
+                    Ok(entry) if entry.path().is_file() => {
+                        // unwrap justification: the workers only stop once the sender is dropped
+                        s.send(entry.into_path()).unwrap()
+                    }
+                    Ok(_) => {}
                 }
                 ignore::WalkState::Continue
             })
         },
     );
-    Ok(())
 }
 
-fn reformat_dir_entry(
-    entry: std::result::Result<ignore::DirEntry, ignore::Error>,
+fn reformat_file(
+    file: &Path,
     write_changes: bool,
-    sender: &Sender<FormatResult>,
-) -> Result<()> {
-    let path = entry?.into_path();
-    if !path.is_file() {
-        return Ok(());
+    formatter: &Formatter,
+    cache: &Cache,
+) -> Result<FormatStatus> {
+    let input = fs::read_to_string(file)?;
+    let hash = content_hash(input.as_bytes());
+    if cache.contains(hash) {
+        return Ok(FormatStatus::NoChange { hash });
     }
-    let status = reformat_file(&path, write_changes)?;
-    sender.send((path, status))?;
-    Ok(())
+    if !write_changes {
+        let formatted = formatter.is_formatted(&input);
+        return Ok(if formatted { FormatStatus::NoChange { hash } } else { FormatStatus::Change });
+    }
+    let output = formatter.format(&input);
+    if input != output {
+        fs::write(file, &output)?;
+        return Ok(FormatStatus::Change);
+    }
+    Ok(FormatStatus::NoChange { hash })
 }
 
-fn reformat_file(file: &Path, write_changes: bool) -> Result<FormatStatus> {
-    let input = fs::read_to_string(file)?;
-    let output = nixpkgs_fmt::reformat_string(&input);
-    if input != output {
-        if write_changes {
-            fs::write(file, &output)?;
+/// Remembers the content hashes of the files which were found to be
+/// formatted, so that the next run can skip them.
+///
+/// There is a separate cache file for each version of nixpkgs-fmt and each
+/// `Config`, as either can change the formatting. Only the files which were
+/// left as is are remembered, so a file which was just reformatted is checked
+/// once more. The cache is only an optimization, so the errors of reading and
+/// writing it are ignored.
+///
+/// The hashes are saved from the most recently seen one on, and only
+/// `MAX_CACHE_ENTRIES` of them are kept, so the hashes of the files which
+/// were changed or removed since are dropped eventually.
+struct Cache {
+    path: Option<PathBuf>,
+    formatted: HashSet<u128>,
+    /// The hashes in the order of the file.
+    order: Vec<u128>,
+}
+
+/// Enough for several checkouts of nixpkgs.
+const MAX_CACHE_ENTRIES: usize = 200_000;
+
+impl Cache {
+    fn load(dir: Option<&Path>, config: &Config) -> Cache {
+        let path = dir.map(|dir| {
+            // `Debug` lists every field, so a new option changes the key too.
+            let config_hash = content_hash(format!("{:?}", config).as_bytes());
+            let key = format!("{}-{:032x}", clap::crate_version!(), config_hash);
+            dir.join(format!("cache-{}", key))
+        });
+        let text = path.as_ref().and_then(|it| fs::read_to_string(it).ok()).unwrap_or_default();
+        let order: Vec<u128> =
+            text.lines().filter_map(|it| u128::from_str_radix(it, 16).ok()).collect();
+        Cache { path, formatted: order.iter().copied().collect(), order }
+    }
+
+    fn contains(&self, hash: u128) -> bool {
+        self.formatted.contains(&hash)
+    }
+
+    /// Saves the hashes of the files which were found to be formatted in this
+    /// run, `seen`, before the older ones.
+    fn save(&self, seen: impl Iterator<Item = u128>) {
+        let path = match &self.path {
+            Some(it) => it,
+            None => return,
+        };
+        let mut seen: Vec<u128> = seen.collect();
+        if seen.iter().all(|it| self.contains(*it)) {
+            return;
         }
-        return Ok(FormatStatus::Change);
+        let mut unique: HashSet<u128> = HashSet::new();
+        seen.retain(|it| unique.insert(*it));
+        let older = self.order.iter().filter(|it| !unique.contains(it));
+        let mut text = String::new();
+        for hash in seen.iter().chain(older).take(MAX_CACHE_ENTRIES) {
+            writeln!(text, "{:032x}", hash).unwrap();
+        }
+        // Written to a temporary file first, so that a concurrent run never
+        // sees a partially written cache.
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        let _ = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| fs::write(&tmp, text))
+            .and_then(|()| fs::rename(&tmp, path));
     }
-    Ok(FormatStatus::NoChange)
+}
+
+fn default_cache_dir() -> Option<PathBuf> {
+    let cache_home = env::var_os("XDG_CACHE_HOME").map(PathBuf::from).filter(|it| it.is_absolute());
+    let cache_home =
+        cache_home.or_else(|| Some(PathBuf::from(env::var_os("HOME")?).join(".cache")));
+    Some(cache_home?.join("nixpkgs-fmt"))
+}
+
+/// The 128-bit FNV-1a hash of `bytes`. Unlike `DefaultHasher`, it is the same
+/// in every Rust release, so it can be stored in the cache.
+fn content_hash(bytes: &[u8]) -> u128 {
+    const OFFSET_BASIS: u128 = 0x6c62_272e_07bb_0142_62b8_2175_6295_c58d;
+    const PRIME: u128 = 0x0000_0000_0100_0000_0000_0000_0000_013b;
+    bytes.iter().fold(OFFSET_BASIS, |hash, &byte| (hash ^ u128::from(byte)).wrapping_mul(PRIME))
 }
@@ -9,10 +9,72 @@ use rnix::{SyntaxElement, SyntaxKind};
 
 /// A convenience function to convert something a pattern for use with `&` and
 /// `|` operators
-pub(crate) fn p(p: impl Into<Pattern>) -> Pattern {
+pub fn p(p: impl Into<Pattern>) -> Pattern {
     p.into()
 }
 
+/// Matches the elements which `p` doesn't match, like `!p(..)`.
+pub fn not(p: impl Into<Pattern>) -> Pattern {
+    !p.into()
+}
+
+/// Matches the elements of `kind` with exactly `text`, for example, a call of
+/// a specific builtin, `with_text(NODE_IDENT, "import")`. Only the elements of
+/// `kind` have their text compared, so this is as cheap as matching the kind
+/// for the rest.
+pub fn with_text(kind: SyntaxKind, text: &'static str) -> Pattern {
+    Pattern::new(Some(iter::once(kind).collect()), move |element| match element {
+        SyntaxElement::Node(node) => node.text() == text,
+        SyntaxElement::Token(token) => token.text() == text,
+    })
+}
+
+/// Whether `element` is the first child node of its parent. Tokens, like
+/// brackets, comments and whitespace, are not counted.
+pub fn is_first_child(element: &SyntaxElement) -> bool {
+    element.parent().is_some()
+        && iter::successors(element.prev_sibling_or_token(), |it| it.prev_sibling_or_token())
+            .all(|it| it.as_node().is_none())
+}
+
+/// Whether `element` is the last child node of its parent. Tokens, like
+/// brackets, comments and whitespace, are not counted.
+pub fn is_last_child(element: &SyntaxElement) -> bool {
+    element.parent().is_some()
+        && iter::successors(element.next_sibling_or_token(), |it| it.next_sibling_or_token())
+            .all(|it| it.as_node().is_none())
+}
+
+/// Method versions of the `&` and `|` operators, which also work for plain
+/// functions, so that conditions can be combined without wrapping each of
+/// them in `p`:
+///
+/// ```
+/// use nixpkgs_fmt::{not, PatternExt};
+/// use rnix::SyntaxElement;
+///
+/// fn is_token(element: &SyntaxElement) -> bool {
+///     element.as_token().is_some()
+/// }
+/// fn is_first(element: &SyntaxElement) -> bool {
+///     element.prev_sibling_or_token().is_none()
+/// }
+///
+/// let pattern = is_token.and(not(is_first));
+/// let root: SyntaxElement = rnix::parse("[ 1 ]").node().into();
+/// assert!(!pattern.matches(&root));
+/// ```
+pub trait PatternExt: Into<Pattern> + Sized {
+    fn and(self, other: impl Into<Pattern>) -> Pattern {
+        self.into() & other.into()
+    }
+    fn or(self, other: impl Into<Pattern>) -> Pattern {
+        self.into() | other.into()
+    }
+}
+
+impl<T: Into<Pattern>> PatternExt for T {}
+
 /// Pattern is boolean function on `SyntaxElement`.
 ///
 /// It is like `Box<dyn Fn(SyntaxElement)> -> bool`, but with additional
@@ -23,9 +85,9 @@ pub(crate) fn p(p: impl Into<Pattern>) -> Pattern {
 /// Currently, we liberally box predicates inside of `Pattern`s, as there's only
 /// a constant amount of patterns.
 #[derive(Clone)]
-pub(crate) struct Pattern {
+pub struct Pattern {
     kinds: Option<HashSet<SyntaxKind>>,
-    pred: Arc<dyn (Fn(&SyntaxElement) -> bool)>,
+    pred: Arc<dyn Fn(&SyntaxElement) -> bool + Send + Sync>,
 }
 
 impl AsRef<Pattern> for Pattern {
@@ -43,7 +105,7 @@ impl fmt::Debug for Pattern {
 impl Pattern {
     fn new(
         kinds: Option<HashSet<SyntaxKind>>,
-        pred: impl Fn(&SyntaxElement) -> bool + 'static,
+        pred: impl Fn(&SyntaxElement) -> bool + Send + Sync + 'static,
     ) -> Pattern {
         Pattern { kinds, pred: Arc::new(pred) }
     }
@@ -62,7 +124,7 @@ impl Pattern {
     }
 
     /// Checks if this pattern matches an element
-    pub(crate) fn matches(&self, element: &SyntaxElement) -> bool {
+    pub fn matches(&self, element: &SyntaxElement) -> bool {
         if let Some(kinds) = self.kinds.as_ref() {
             if !kinds.contains(&element.kind()) {
                 return false;
@@ -72,6 +134,14 @@ impl Pattern {
     }
 }
 
+/// Matches the nodes which match `ancestor`, or have an ancestor which does.
+pub(crate) fn self_or_ancestor(ancestor: Pattern) -> Pattern {
+    Pattern::from(move |element: &SyntaxElement| match element.as_node() {
+        Some(node) => node.ancestors().any(|it| ancestor.matches(&it.into())),
+        None => false,
+    })
+}
+
 /// `pat1 & pat2` operator
 impl ops::BitAnd for Pattern {
     type Output = Pattern;
@@ -90,20 +160,30 @@ impl ops::BitAnd for Pattern {
 impl ops::BitOr for Pattern {
     type Output = Pattern;
     fn bitor(self, other: Pattern) -> Pattern {
-        let kinds = match (self.kinds, other.kinds) {
-            (Some(lhs), Some(rhs)) => Some(lhs.union(&rhs).cloned().collect::<HashSet<_>>()),
-            (Some(it), None) | (None, Some(it)) => Some(it),
-            (None, None) => None,
+        let kinds = match (&self.kinds, &other.kinds) {
+            (Some(lhs), Some(rhs)) => Some(lhs.union(rhs).cloned().collect::<HashSet<_>>()),
+            // An unconstrained side can match an element of any kind
+            _ => None,
         };
-        let (p1, p2) = (self.pred, other.pred);
-        Pattern::new(kinds, move |element| p1(element) || p2(element))
+        // Each side keeps checking its own kinds, as the union is only a
+        // pre-filter.
+        Pattern::new(kinds, move |element| self.matches(element) || other.matches(element))
+    }
+}
+
+/// `!pat` operator
+impl ops::Not for Pattern {
+    type Output = Pattern;
+    fn not(self) -> Pattern {
+        // The complement can match an element of any kind
+        Pattern::new(None, move |element| !self.matches(element))
     }
 }
 
 /// Construct pattern from closure.
 impl<F> From<F> for Pattern
 where
-    F: for<'a> Fn(&SyntaxElement) -> bool + 'static,
+    F: for<'a> Fn(&SyntaxElement) -> bool + Send + Sync + 'static,
 {
     fn from(f: F) -> Pattern {
         Pattern::new(None, f)
@@ -0,0 +1,50 @@
+//! Python bindings, enabled by the `python` feature:
+//!
+//! ```python
+//! import nixfmt
+//!
+//! nixfmt.format("{a=1;}", max_width=80, canonical_layout=True)
+//! ```
+//!
+//! The keyword arguments are the fields of `Config`, the omitted ones keep
+//! their defaults.
+use pyo3::{prelude::*, wrap_pyfunction};
+
+use crate::Config;
+
+#[pyfunction(
+    "*",
+    continuation_indent = "None",
+    max_width = "None",
+    canonical_layout = "None",
+    comment_width = "None",
+    align_trailing_comments = "None",
+    trailing_commas = "None"
+)]
+fn format(
+    source: &str,
+    continuation_indent: Option<u32>,
+    max_width: Option<u32>,
+    canonical_layout: Option<bool>,
+    comment_width: Option<u32>,
+    align_trailing_comments: Option<bool>,
+    trailing_commas: Option<bool>,
+) -> String {
+    let default = Config::default();
+    let config = Config {
+        continuation_indent: continuation_indent.unwrap_or(default.continuation_indent),
+        max_width: max_width.or(default.max_width),
+        canonical_layout: canonical_layout.unwrap_or(default.canonical_layout),
+        comment_width: comment_width.or(default.comment_width),
+        align_trailing_comments: align_trailing_comments.unwrap_or(default.align_trailing_comments),
+        trailing_commas: trailing_commas.unwrap_or(default.trailing_commas),
+        ..default
+    };
+    crate::reformat_string_with_config(source, &config)
+}
+
+#[pymodule]
+fn nixfmt(_py: Python, module: &PyModule) -> PyResult<()> {
+    module.add_function(wrap_pyfunction!(format, module)?)?;
+    Ok(())
+}
@@ -1,13 +1,23 @@
 //! This module contains specific `super::dsl` rules for formatting nix language.
+//!
+//! The output for a released `StyleVersion` is frozen. A change to the output
+//! only applies from a new version on, so it goes behind a check like
+//! `config.style_version >= StyleVersion::V2`, and `test_style_v1_snapshot`
+//! makes sure the older versions are untouched.
+use std::iter::successors;
+
 use rnix::{
-    types::{Lambda, LetIn, TypedNode, With},
+    types::{Apply, Assert, BinOp, BinOpKind, KeyValue, Lambda, LetIn, TypedNode, With},
     NodeOrToken, SyntaxElement, SyntaxKind,
     SyntaxKind::*,
-    T,
+    SyntaxNode, TextRange, TextSize, T,
 };
+use unicode_width::UnicodeWidthChar;
 
 use crate::{
-    dsl::{self, IndentDsl, IndentValue::*, SpacingDsl},
+    comments::{self, Attachment},
+    config::Config,
+    dsl::{self, IndentDsl, IndentValue::*, LintDsl, SpacingDsl, WrapDsl},
     pattern::p,
     tree_utils::{
         has_newline, next_non_whitespace_sibling, next_sibling, not_on_top_level, on_top_level,
@@ -16,23 +26,50 @@ use crate::{
 };
 
 #[rustfmt::skip]
-pub(crate) fn spacing() -> SpacingDsl {
+pub(crate) fn spacing(config: &Config) -> SpacingDsl {
     let mut dsl = SpacingDsl::default();
 
+    rule!(dsl, "Space before =":
+        inside(NODE_KEY_VALUE).before(T![=]).single_space();
+        "{ a=92; }" => "{ a = 92; }",
+        "{ a  = 92; }" => "{ a = 92; }",
+    );
+
+    rule!(dsl, "Space after =":
+        inside(NODE_KEY_VALUE).after(T![=]).single_space_or_optional_newline();
+        "{ a =92; }" => "{ a = 92; }",
+    );
+
+    rule!(dsl, "Keep doc comment attached to the binding":
+        inside([NODE_ATTR_SET, NODE_LET_IN]).between(p(TOKEN_COMMENT) & p(is_doc_comment), [NODE_KEY_VALUE, NODE_INHERIT]).priority(2).single_newline();
+        "{\n  /** Doc */\n\n  a = 1;\n}" => "{\n  /** Doc */\n  a = 1;\n}",
+        "let\n  /** Doc */\n\n  a = 1;\nin a" => "let\n  /** Doc */\n  a = 1;\nin\na",
+    );
+
     dsl
-        .test("{ a=92; }", "{ a = 92; }")
-        .rule("Space before =")
-        .inside(NODE_KEY_VALUE).before(T![=]).single_space()
+        .test("let\n  ## Doc\n  ## more\n\n  a = 1;\nin a", "let\n  ## Doc\n  ## more\n\n  a = 1;\nin\na");
 
-        .rule("Space after =")
-        .inside(NODE_KEY_VALUE).after(T![=]).single_space_or_optional_newline()
+    rule!(dsl, "Keep comment after =":
+        inside(NODE_KEY_VALUE).after(T![=]).when(followed_by_line_comment).priority(1).single_space();
+        "{\n  foo =   # temporary\n  bar;\n}" => "{\n  foo = # temporary\n    bar;\n}",
+        "{\n  foo =\n  # temporary\n  bar;\n}" => "{\n  foo =\n    # temporary\n    bar;\n}",
+    );
 
+    dsl
         .test("{ a = 92 ; }", "{ a = 92; }")
         .inside(NODE_KEY_VALUE).before(T![;]).no_space_or_optional_newline()
-        .inside(NODE_KEY_VALUE).before(T![;]).when(after_literal).no_space()
-        .inside(NODE_KEY_VALUE).before(NODE_IF_ELSE).when(not_inline_if).single_space_or_newline()
+        .inside(NODE_KEY_VALUE).before(T![;]).when(after_literal).priority(1).no_space()
+        .test("{\n  a = 92\n  ;\n}", "{\n  a = 92;\n}")
+        .inside(NODE_KEY_VALUE).before(T![;]).when(after_single_line_value).priority(1).no_space()
+        .inside(NODE_KEY_VALUE).before(NODE_IF_ELSE).when(not_inline_if).priority(1).single_space_or_newline()
         .inside(NODE_KEY_VALUE).before(NODE_LET_IN).when(inline_let_in).single_space_or_newline()
 
+        .test("{ foo . ${bar}= 1; }", "{ foo.${bar} = 1; }")
+        .test("{ foo.${ bar }.baz  =1; }", "{ foo.${bar}.baz = 1; }")
+        .inside(NODE_KEY).around(T![.]).no_space()
+        .inside(NODE_DYNAMIC).after(TOKEN_DYNAMIC_START).no_space_or_optional_newline()
+        .inside(NODE_DYNAMIC).before(TOKEN_DYNAMIC_END).no_space_or_optional_newline()
+
         .test("a++\nb", "a ++\nb")
         .test("a==  b", "a == b")
         .test("a!=  b", "a != b")
@@ -41,60 +78,94 @@ pub(crate) fn spacing() -> SpacingDsl {
         .test("a  -   b", "a - b")
         .test("a*  b", "a * b")
         .test("a/  b", "a / b")
-        .inside(NODE_BIN_OP).around(BIN_OPS).single_space_or_optional_newline()
+        .inside(NODE_BIN_OP).around(BIN_OPS).single_space_or_optional_newline();
+
+    rule!(dsl, "No space after logical not":
+        inside(NODE_UNARY_OP).after(T![!]).when(inverts_paren_or_not).no_space();
+        "! (a && b)" => "!(a && b)",
+        "! !x" => "!!x",
+        "a && ! (b || ! !c)" => "a && !(b || !!c)",
+    );
 
+    dsl
         .test("foo . bar . baz", "foo.bar.baz")
         .inside(NODE_SELECT).around(T![.]).no_space()
         .test("{} :92", "{}: 92")
         .inside(NODE_LAMBDA).before(T![:]).no_space()
         .inside(NODE_LAMBDA).after(T![:]).single_space_or_optional_newline()
-        .inside(NODE_LAMBDA).before(NODE_IF_ELSE).when(not_inline_if).single_space_or_newline()
-        .inside(NODE_LAMBDA).before(NODE_LET_IN).single_space_or_newline()
+        .inside(NODE_LAMBDA).before(NODE_IF_ELSE).when(not_inline_if).priority(1).single_space_or_newline()
+        .inside(NODE_LAMBDA).before(NODE_LET_IN).priority(1).single_space_or_newline()
 
         .test("[1 2 3]", "[ 1 2 3 ]")
         .inside(NODE_LIST).after(T!["["]).single_space_or_newline()
         .inside(NODE_LIST).before(T!["]"]).single_space_or_newline()
-        .inside(NODE_LIST).after(T!["["]).when(inline_with_attr_set).no_space()
-        .inside(NODE_LIST).before(T!["]"]).when(inline_with_attr_set).no_space()
+        .inside(NODE_LIST).after(T!["["]).when(inline_with_attr_set).priority(1).no_space()
+        .inside(NODE_LIST).before(T!["]"]).when(inline_with_attr_set).priority(1).no_space()
         .test("[]", "[ ]")
         .inside(NODE_LIST).between(T!["["], T!["]"]).single_space_or_optional_newline()
         .inside(NODE_LIST).between(VALUES, VALUES).single_space_or_newline()
         .inside(NODE_LIST).between(VALUES, TOKEN_COMMENT).single_space_or_optional_newline()
-        .inside(NODE_LIST).between(TOKEN_COMMENT, VALUES).single_space_or_newline()
+        .inside(NODE_LIST).between(TOKEN_COMMENT, VALUES).single_space_or_newline();
+
+    rule!(dsl, "Keep comment on the opening line":
+        inside(NODE_LIST).after(T!["["]).when(followed_by_line_comment).priority(1).single_space();
+        "[ # comment\n1\n]" => "[ # comment\n  1\n]",
+    );
 
+    dsl
         .test("( 92 )", "(92)")
 
         .inside(NODE_PAREN).after(T!["("]).no_space_or_optional_newline()
         .inside(NODE_PAREN).before(T![")"]).no_space_or_optional_newline()
-        .inside(NODE_PAREN).after(T!["("]).when(has_no_brackets).no_space_or_newline()
-        .inside(NODE_PAREN).before(T![")"]).when(has_no_brackets).no_space_or_newline()
+        .inside(NODE_PAREN).after(T!["("]).when(has_no_brackets).priority(1).no_space_or_newline()
+        .inside(NODE_PAREN).before(T![")"]).when(has_no_brackets).priority(1).no_space_or_newline()
 
         .test("{foo = 92;}", "{ foo = 92; }")
         .inside(NODE_ATTR_SET).after(T!["{"]).single_space_or_newline()
-        .inside(NODE_ATTR_SET).before(T!["}"]).single_space_or_newline()
+        .inside(NODE_ATTR_SET).before(T!["}"]).priority(1).single_space_or_newline()
+        .test("rec  {a=1;}", "rec { a = 1; }")
+        .test("rec\n{\n  a = 1;\n}", "rec {\n  a = 1;\n}")
+        .inside(NODE_ATTR_SET).after(T![rec]).single_space()
         .test("{}", "{ }")
-        .inside(NODE_ATTR_SET).between(T!["{"], T!["}"]).single_space()
-        .inside(NODE_ATTR_SET).before(NODE_KEY_VALUE).single_space_or_optional_newline()
+        .inside(NODE_ATTR_SET).between(T!["{"], T!["}"]).priority(2).single_space()
+        .inside(NODE_ATTR_SET).before(NODE_KEY_VALUE).priority(1).single_space_or_optional_newline()
         .inside(NODE_ATTR_SET).between(NODE_KEY_VALUE, NODE_KEY_VALUE).single_space_or_newline()
-        .inside(NODE_ATTR_SET).between(NODE_INHERIT, [NODE_INHERIT, TOKEN_COMMENT]).single_space_or_optional_newline()
-        .inside(NODE_ATTR_SET).between(NODE_KEY_VALUE, TOKEN_COMMENT).single_space_or_optional_newline()
+        .inside(NODE_ATTR_SET).between(NODE_INHERIT, [NODE_INHERIT, TOKEN_COMMENT]).priority(1).single_space_or_optional_newline()
+        .inside(NODE_ATTR_SET).between(NODE_KEY_VALUE, TOKEN_COMMENT).single_space_or_optional_newline();
+
+    rule!(dsl, "Keep comment on the opening line":
+        inside(NODE_ATTR_SET).after(T!["{"]).when(followed_by_line_comment).priority(1).single_space();
+        "{ # comment\na = 1;\n}" => "{ # comment\n  a = 1;\n}",
+    );
 
+    dsl
         .test("{arg}: 92", "{ arg }: 92")
-        .inside(NODE_PATTERN).after(T!["{"]).single_space()
-        .inside(NODE_PATTERN).between(T!["{"], TOKEN_COMMENT).single_space_or_newline()
+        .inside(NODE_PATTERN).between(T!["{"], TOKEN_COMMENT).priority(1).single_space_or_newline()
         .inside(NODE_PATTERN).before(T!["}"]).single_space_or_newline()
         .test("{ }: 92", "{}: 92")
-        .inside(NODE_PATTERN).between(T!["{"], T!["}"]).no_space()
+        .inside(NODE_PATTERN).between(T!["{"], T!["}"]).priority(1).no_space()
 
         .test("{ foo,bar }: 92", "{ foo, bar }: 92")
-        .inside(NODE_PATTERN).after(T![,]).single_space()
-        .inside(NODE_PATTERN).before(T![,]).no_space_or_newline()
-
+    ;
+    if config.trailing_commas {
+        dsl
+            .inside(NODE_PATTERN).after(T!["{"]).single_space_or_newline()
+            .inside(NODE_PATTERN).after(T![,]).single_space_or_newline()
+            .inside(NODE_PATTERN).before(T![,]).no_space()
+        ;
+    } else {
+        dsl
+            .inside(NODE_PATTERN).after(T!["{"]).single_space()
+            .inside(NODE_PATTERN).after(T![,]).single_space()
+            .inside(NODE_PATTERN).before(T![,]).no_space_or_newline()
+        ;
+    }
+    dsl
         .test("{ inherit( x )  y  z  ; }", "{ inherit (x) y z; }")
         .inside(NODE_INHERIT).around(NODE_INHERIT_FROM).single_space_or_optional_newline()
         .inside(NODE_INHERIT).around(T![;]).no_space_or_optional_newline()
-        .inside(NODE_INHERIT).before(NODE_IDENT).single_space_or_optional_newline()
-        .inside(NODE_INHERIT).before(NODE_OR_DEFAULT).single_space_or_optional_newline()
+        .inside(NODE_INHERIT).before(NODE_IDENT).priority(1).single_space_or_optional_newline()
+        .inside(NODE_INHERIT).before(NODE_OR_DEFAULT).priority(1).single_space_or_optional_newline()
         .inside(NODE_INHERIT).after(NODE_IDENT).no_space_or_optional_newline()
         .inside(NODE_INHERIT_FROM).after(T!["("]).no_space()
         .inside(NODE_INHERIT_FROM).before(T![")"]).no_space()
@@ -105,21 +176,35 @@ pub(crate) fn spacing() -> SpacingDsl {
         .inside(NODE_LET_IN).after(T![let]).single_space_or_optional_newline()
         .inside(NODE_LET_IN).around(T![in]).single_space_or_optional_newline()
         .inside(NODE_LET_IN).after(NODE_KEY_VALUE).single_space_or_optional_newline()
-        .inside(NODE_LET_IN).before(NODE_KEY_VALUE).when(let_header_has_newline).newline()
-        .inside(NODE_LET_IN).around(T![in]).when(let_header_has_newline).newline()
+        .inside(NODE_LET_IN).before(NODE_KEY_VALUE).when(let_header_has_newline).priority(1).newline()
+        .inside(NODE_LET_IN).around(T![in]).when(let_header_has_newline).priority(1).newline();
 
-        .test("{a?3}: a", "{ a ? 3 }: a")
-        .inside(NODE_PAT_ENTRY).around(T![?]).single_space()
+    rule!(dsl, "Space around ?":
+        inside(NODE_PAT_ENTRY).around(T![?]).single_space();
+        "{a?3}: a" => "{ a ? 3 }: a",
+    );
 
+    dsl
         .test("f  x", "f x")
         .inside(NODE_APPLY).between(VALUES, VALUES).single_space_or_optional_newline()
-        .inside(NODE_APPLY).before(VALUES).when(should_be_newline).single_space_or_newline()
+        .inside(NODE_APPLY).before(VALUES).when(should_be_newline).priority(1).single_space_or_newline()
 
         .test("if  cond  then  tru  else  fls", "if cond then tru else fls")
+        .named_test("If-else chain on several lines", r#"
+            if foo then  bar
+            else  if baz then
+              quux
+            else   null
+        "#, r#"
+            if foo then bar
+            else if baz then
+              quux
+            else null
+        "#)
         .inside(NODE_IF_ELSE).after(T![if]).single_space_or_optional_newline()
         .inside(NODE_IF_ELSE).around([T![else],T![then]]).single_space_or_optional_newline()
-        .inside(NODE_IF_ELSE).after(T![then]).when(has_expression_node).single_space_or_newline()
-        .inside(NODE_IF_ELSE).after(T![else]).when(has_expression_node).single_space_or_newline()
+        .inside(NODE_IF_ELSE).after(T![then]).when(has_expression_node).priority(1).single_space_or_newline()
+        .inside(NODE_IF_ELSE).after(T![else]).when(has_expression_node).priority(1).single_space_or_newline()
         
         // special-case to force a linebreak before `=` in
         //
@@ -134,20 +219,44 @@ pub(crate) fn spacing() -> SpacingDsl {
         .add_rule(dsl::SpacingRule {
             name: None,
             pattern: p(T![=]) & (p(next_sibling_is_multiline_lambda_pattern) | p(next_sibling_is_multiline_letin_pattern)) ,
-            space: dsl::Space { loc: dsl::SpaceLoc::After, value: dsl::SpaceValue::Newline }
+            space: dsl::Space { loc: dsl::SpaceLoc::After, value: dsl::SpaceValue::Newline },
+            priority: 1,
+        })
+
+        .test("{\n  a = 1;    # why\n}", "{\n  a = 1; # why\n}")
+        .test("f {\n  x = 1;\n}     # why", "f {\n  x = 1;\n} # why")
+        .add_rule(dsl::SpacingRule {
+            name: None,
+            pattern: p(TOKEN_COMMENT) & p(is_trailing_comment),
+            space: dsl::Space { loc: dsl::SpaceLoc::Before, value: dsl::SpaceValue::Single },
+            priority: 0,
+        })
+
+        // special-cased rule for the file header: it is kept as written, and
+        // separated from the code by a blank line
+        .test("# Copyright\n#   Someone\n{ }", "# Copyright\n#   Someone\n\n{ }")
+        .test("/* License */\n\n\n{ }", "/* License */\n\n{ }")
+        .test("/** Doc */\nx: x", "/** Doc */\n\nx: x")
+        .add_rule(dsl::SpacingRule {
+            name: None,
+            pattern: p(after_file_header),
+            space: dsl::Space { loc: dsl::SpaceLoc::Before, value: dsl::SpaceValue::BlankLines(1) },
+            priority: 0,
         })
 
         // special-cased rules for leading and trailing whitespace
         .add_rule(dsl::SpacingRule {
             name: None,
             pattern: NODE_ROOT.into(),
-            space: dsl::Space { loc: dsl::SpaceLoc::Before, value: dsl::SpaceValue::None }
+            space: dsl::Space { loc: dsl::SpaceLoc::Before, value: dsl::SpaceValue::None },
+            priority: 0,
         })
 
         .add_rule(dsl::SpacingRule {
             name: None,
             pattern: NODE_ROOT.into(),
-            space: dsl::Space { loc: dsl::SpaceLoc::After, value: dsl::SpaceValue::Newline }
+            space: dsl::Space { loc: dsl::SpaceLoc::After, value: dsl::SpaceValue::Newline },
+            priority: 0,
         })
 
         ;
@@ -155,6 +264,24 @@ pub(crate) fn spacing() -> SpacingDsl {
     dsl
 }
 
+/// Is this element preceded by a value which fits on a single line?
+///
+/// Multi-line values keep the `;` on a line of its own, and pulling anything up
+/// to a `#` comment would comment it out.
+fn after_single_line_value(element: &SyntaxElement) -> bool {
+    let value = match prev_sibling(element) {
+        Some(it) => it,
+        None => return false,
+    };
+    if has_newline(&value) {
+        return false;
+    }
+    let last_token =
+        successors(element.as_token().and_then(|it| it.prev_token()), |it| it.prev_token())
+            .find(|it| it.kind() != TOKEN_WHITESPACE);
+    last_token.map(|it| it.kind() == TOKEN_COMMENT && it.text().starts_with('#')) != Some(true)
+}
+
 fn after_literal(element: &SyntaxElement) -> bool {
     fn is_literal(kind: SyntaxKind) -> bool {
         kind == NODE_ATTR_SET || kind == NODE_LIST
@@ -168,6 +295,63 @@ fn after_literal(element: &SyntaxElement) -> bool {
     };
 }
 
+/// Is this element followed by a `#` comment on the same line?
+fn followed_by_line_comment(element: &SyntaxElement) -> bool {
+    comments::trailing_comment(element).map(|it| it.text().starts_with('#')) == Some(true)
+}
+
+/// Is this a `#` comment which follows some code on the same line?
+fn is_trailing_comment(element: &SyntaxElement) -> bool {
+    match element.as_token() {
+        Some(it) if it.text().starts_with('#') => {
+            matches!(comments::attachment(it), Attachment::Trailing(_))
+        }
+        _ => false,
+    }
+}
+
+/// Is this the first expression of the file, preceded by comments?
+///
+/// All of these comments are the header, the same as for `protected_ranges`,
+/// including a doc comment right before the expression: only the doc comments
+/// of bindings are attached to them.
+fn after_file_header(element: &SyntaxElement) -> bool {
+    let node = match element.as_node() {
+        Some(it) if it.parent().map(|it| it.kind()) == Some(NODE_ROOT) => it,
+        _ => return false,
+    };
+    let mut prev = successors(node.prev_sibling_or_token(), |it| it.prev_sibling_or_token());
+    node.prev_sibling().is_none() && prev.any(|it| it.kind() == TOKEN_COMMENT)
+}
+
+/// Is this a `/** ... */` documentation comment?
+///
+/// The `##` comments are often section headings, so they keep the blank line
+/// after them, like the other comments.
+fn is_doc_comment(element: &SyntaxElement) -> bool {
+    match element.as_token() {
+        Some(it) if it.kind() == TOKEN_COMMENT => {
+            it.text().starts_with("/**") && it.text() != "/**/"
+        }
+        _ => false,
+    }
+}
+
+/// Is this `!` applied to a parenthesized expression or to another `!`?
+///
+/// The operand is the next sibling inside of the `NODE_UNARY_OP`, so `!` in
+/// `a && !b` is never confused with the surrounding binary operator.
+fn inverts_paren_or_not(element: &SyntaxElement) -> bool {
+    match next_non_whitespace_sibling(element) {
+        Some(NodeOrToken::Node(operand)) => match operand.kind() {
+            NODE_PAREN => true,
+            NODE_UNARY_OP => operand.first_token().map(|it| it.kind() == T![!]) == Some(true),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
 fn has_no_brackets(element: &SyntaxElement) -> bool {
     let parent = match element.parent() {
         None => return false,
@@ -371,286 +555,892 @@ fn let_header_has_newline(element: &SyntaxElement) -> bool {
         .unwrap_or(false)
 }
 
+/// The rules depend on the `config`, so they should only be used with it.
 #[rustfmt::skip]
-pub(crate) fn indentation() -> IndentDsl {
-    let mut dsl = IndentDsl::default();
-    dsl
-        .anchor([NODE_PAT_ENTRY, NODE_PATTERN])
+pub(crate) fn indentation(config: &Config) -> IndentDsl {
+    let mut dsl = IndentDsl { continuation_indent: config.continuation_indent, ..IndentDsl::default() };
+    dsl.anchor([NODE_PAT_ENTRY, NODE_PATTERN]);
+
+    rule!(dsl, "Indent binops":
+        inside(p(NODE_BIN_OP) & p(after_concat_is_newline) & p(binop_chain_not_on_top_level))
+            .set(Continuation);
+        r#"
+            {
+            foo = bar ++
+            [ baz ];
+            }
+        "# => r#"
+            {
+              foo = bar ++
+                [ baz ];
+            }
+        "#,
+    );
+
+    rule!(dsl, "Indent binops top level":
+        inside(p(NODE_BIN_OP) & p(binop_chain_on_top_level))
+            .not_matching(p(T![++]) | p(VALUES))
+            .set(Continuation);
+        r#"
+            srcs
+              ++ patches
+              ++ extraFiles
+        "# => r#"
+            srcs
+            ++ patches
+            ++ extraFiles
+        "#,
+        r#"
+            a
+            + b
+        "# => r#"
+            a
+              + b
+        "#,
+        r#"
+            {
+            foo = bar ++
+            [ baz ];
+            }
+        "# => r#"
+            {
+              foo = bar ++
+                [ baz ];
+            }
+        "#,
+    );
+
+    rule!(dsl, "Indent list content":
+        inside(NODE_LIST)
+            .not_matching([T!["["], T!["]"]])
+            .set(Indent);
+        r#"
+            [
+            92
+            ]
+        "# => r#"
+            [
+              92
+            ]
+        "#,
+        r#"
+            [
+            92
+            # trailing comment
+            ]
+        "# => r#"
+            [
+              92
+              # trailing comment
+            ]
+        "#,
+    );
+
+    rule!(dsl, "Indent parenthesized expressions":
+        inside(NODE_PAREN)
+            .not_matching([T!["("],T![")"]])
+            .set(Indent);
+        r#"
+            (
+            92
+            )
+        "# => r#"
+            (
+              92
+            )
+        "#,
+        r#"
+            (
+            92
+                # trailing comment
+            )
+        "# => r#"
+            (
+              92
+              # trailing comment
+            )
+        "#,
+    );
+
+    rule!(dsl, "Indent attribute set content":
+        inside(NODE_ATTR_SET)
+            .not_matching([T!["{"], T!["}"]])
+            .set(Indent);
+        r#"
+            {
+            foo = bar;
+            }
+        "# => r#"
+            {
+              foo = bar;
+            }
+        "#,
+        r#"
+            {
+            foo = bar;
+            # trailing comment
+            }
+        "# => r#"
+            {
+              foo = bar;
+              # trailing comment
+            }
+        "#,
+        r#"
+            {
+              foo = {
+            # the only comment
+              };
+            }
+        "# => r#"
+            {
+              foo = {
+                # the only comment
+              };
+            }
+        "#,
+    );
+
+    rule!(dsl, "Indent newline let bindings ":
+        inside(p(NODE_LET_IN) & p(newline_let))
+            .not_matching([T![let], T![in]])
+            .set(Indent);
+    );
+
+    rule!(dsl, "Indent let bindings after key value":
+        inside(p(NODE_LET_IN) & p(no_newline_let))
+            .not_matching(p([T![let], T![in], NODE_WITH, NODE_ASSERT]) | p(VALUES))
+            .set(Indent);
+        r#"
+            (
+              let
+              x = 1;
+              inherit z;
+              in
+              x
+            )
+        "# => r#"
+            (
+              let
+                x = 1;
+                inherit z;
+              in
+              x
+            )
+        "#,
+    );
+
+    rule!(dsl, "Indent attribute value":
+        inside(NODE_KEY_VALUE)
+            .not_matching(T![;])
+            .set(Indent);
+        r#"
+            {
+              foo =
+              92;
+            }
+        "# => r#"
+            {
+              foo =
+                92;
+            }
+        "#,
+    );
+
+    rule!(dsl, "Indent lambda parameters":
+        inside(NODE_PATTERN)
+            .not_matching([T!["{"], T!["}"], T![,]])
+            .set(Indent);
+        r#"
+            {
+            # comment
+            foo ? bar
+            , baz
+            }: foo
+        "# => r#"
+            {
+              # comment
+              foo ? bar
+            , baz
+            }: foo
+        "#,
+    );
+
+    rule!(dsl, "Indent lambda body":
+        inside(p(NODE_LAMBDA) & p(not_on_top_level) & p(pattern_not_newline))
+            .set(Indent);
+    );
+
+    rule!(dsl, "Indent newline lambda body":
+        inside(p(NODE_LAMBDA) & p(not_on_top_level) & p(pattern_newline) & p(lambda_inside_node_pattern))
+            .not_matching(p(TOKEN_COMMENT))
+            .set(Indent);
+        r#"
+            {
+              f =
+                { g ? x:
+                y
+                }:
+                g;
+            }
+        "# => r#"
+            {
+              f =
+                { g ? x:
+                    y
+                }:
+                g;
+            }
+        "#,
+    );
+
+    rule!(dsl, "Indent newline lambda body":
+        inside(p(NODE_LAMBDA) & p(not_on_top_level) & p(pattern_newline) & p(lambda_outside_node_pattern))
+            .not_matching(p(TOKEN_COMMENT) | p(VALUES))
+            .set(Indent);
+        r#"
+            {}:
+              {
+            foo =
+              # describe bar
+              bar:
+              # describe baz
+              baz:
+              fnbody;
+            }
+        "# => r#"
+            {}:
+            {
+              foo =
+                # describe bar
+                bar:
+                # describe baz
+                baz:
+                fnbody;
+            }
+        "#,
+    );
+
+    rule!(dsl, "Indent top-level apply arg":
+        inside(p(NODE_APPLY) & p(on_top_level))
+            .not_matching([T!["{"], T!["}"]])
+            .set(Continuation);
+        r#"
+            foo
+            bar baz
+        "# => r#"
+            foo
+              bar
+              baz
+        "#,
+    );
+
+    rule!(dsl, "Indent apply arg":
+        inside(p(NODE_APPLY) & p(not_on_top_level) & p(not_inline_apply))
+            .not_matching([T!["{"], T!["}"]])
+            .set(Continuation);
+        r#"
+            foo
+            bar baz
+        "# => r#"
+            foo
+              bar
+              baz
+        "#,
+    );
+
+    rule!(dsl, "Indent apply arg":
+        inside(p(NODE_APPLY) & p(not_on_top_level) & p(inline_apply))
+            .not_matching([T!["{"], T!["}"]])
+            .set(Continuation);
+    );
+
+    rule!(dsl, "Indent with and assert body":
+        inside(p([NODE_WITH, NODE_ASSERT]) & p(not_on_top_level))
+            .when_anchor(not_chained_with_or_assert)
+            .set(Indent);
+        r#"
+            with foo;
+              {
+              bar = with baz;
+              body;
+              }
+        "# => r#"
+            with foo;
+            {
+              bar = with baz;
+                body;
+            }
+        "#,
+        r#"
+            [
+              (with foo;
+              bar)
+            ]
+        "# => r#"
+            [
+              (with foo;
+                bar)
+            ]
+        "#,
+        r#"
+            {
+              foo = with bar;
+              assert baz;
+              with quux;
+              body;
+            }
+        "# => r#"
+            {
+              foo = with bar;
+                assert baz;
+                with quux;
+                body;
+            }
+        "#,
+        r#"
+            {
+              foo = if bar then assert baz;
+              quux else null;
+            }
+        "# => r#"
+            {
+              foo =
+                if bar then assert baz;
+                  quux else null;
+            }
+        "#,
+    );
+
+    rule!(dsl, "Indent or default":
+        inside(NODE_OR_DEFAULT)
+            .set(Continuation);
+        r#"
+            {
+              x = foo or
+              bar;
+            }
+        "# => r#"
+            {
+              x = foo or
+                bar;
+            }
+        "#,
+    );
 
+    rule!(dsl, "Indent if-then-else":
+        inside(p(NODE_IF_ELSE) & p(inline_if_else))
+            .not_matching(p([T![if], T![then], T![else]]) | p(VALUES))
+            .set(Indent);
+    );
+
+    rule!(dsl, "Indent if-then-else":
+        inside(p(NODE_IF_ELSE) & p(not_inline_if_else))
+            .not_matching(p([T![if], T![then], T![else]]) | p(comment_before_keyword))
+            .set(Indent);
+        r#"
+            if
+            foo
+            then
+            bar
+            else
+            baz
+        "# => r#"
+            if
+              foo
+            then
+              bar
+            else
+              baz
+        "#,
+        r#"
+            if foo then
+            # describe bar
+            bar
+            # describe else
+            else
+            # describe baz
+            baz
+        "# => r#"
+            if foo then
+              # describe bar
+              bar
+            # describe else
+            else
+              # describe baz
+              baz
+        "#,
+        r#"
+            {
+            x =
+            if a then
+            b
+            else if c then
+            d
+            else
+            e;
+            }
+        "# => r#"
+            {
+              x =
+                if a then
+                  b
+                else if c then
+                  d
+                else
+                  e;
+            }
+        "#,
+    );
 
-        .rule("Indent binops")
-            .inside(p(NODE_BIN_OP) & p(after_concat_is_newline) & p(not_on_top_level))
-            .set(Indent)
+    rule!(dsl, "Indent inherit parts":
+        inside(NODE_INHERIT)
+            .set(Continuation);
+        r#"
+            {
+              inherit
+              (builtins)
+              # comment
+              toString
+              ;
+            }
+        "# => r#"
+            {
+              inherit
+                (builtins)
+                # comment
+                toString
+                ;
+            }
+        "#,
+    );
+
+    dsl
+}
+
+#[rustfmt::skip]
+pub(crate) fn wrapping(_config: &Config) -> WrapDsl {
+    let mut dsl = WrapDsl::default();
+    dsl
+        .rule("Break after with or assert head")
+            .inside(p(NODE_ASSERT) | p(p(NODE_WITH) & p(with_compound_namespace)))
+            .break_before_each(with_or_assert_body)
+            .test(r#"
+                with import ./nix { system = "x"; }; hello
+            "#, r#"
+                with import ./nix { system = "x"; };
+                hello
+            "#)
             .test(r#"
+                with import <nixpkgs> { overlays = [ foo bar ]; }; hello
+            "#, r#"
+                with import <nixpkgs>
+                  {
+                    overlays = [ foo bar ];
+                  };
+                hello
+            "#)
+            .test(r#"
+                assert stdenv.isLinux -> withSystemd; mkDerivation { }
+            "#, r#"
+                assert stdenv.isLinux -> withSystemd;
+                mkDerivation { }
+            "#)
+            .test(r#"
+                { meta = with lib; { license = licenses.mit; }; }
+            "#, r#"
                 {
-                foo = bar ++
-                [ baz ];
+                  meta = with lib; {
+                    license = licenses.mit;
+                  };
                 }
+            "#)
+
+        .rule("Break if-then-else")
+            .inside(NODE_IF_ELSE)
+            .break_before([T![then], T![else]])
+            .test(r#"
+                if stdenv.isDarwin then darwinFlags else linuxFlags
+            "#, r#"
+                if stdenv.isDarwin
+                then darwinFlags
+                else linuxFlags
+            "#)
+            .test(r#"
+                if withPython && pythonVersion != null && !stdenv.isDarwin then a else b
+            "#, r#"
+                if withPython
+                  && pythonVersion != null
+                  && !stdenv.isDarwin
+                then a
+                else b
+            "#)
+
+        .rule("Break attribute set")
+            .inside(NODE_ATTR_SET)
+            .break_before(T!["}"])
+            .test(r#"
+                { alpha = 1; beta = 2; gamma = 3; delta = 4; }
             "#, r#"
                 {
-                  foo = bar ++
-                    [ baz ];
+                  alpha = 1;
+                  beta = 2;
+                  gamma = 3;
+                  delta = 4;
                 }
             "#)
-        .rule("Indent binops top level")
-            .inside(p(NODE_BIN_OP) & p(on_top_level))
-            .not_matching(p(T![++]) | p(VALUES))
-            .set(Indent)
             .test(r#"
+                { a = { alpha = 1; beta = 2; gamma = 3; }; b = { }; }
+            "#, r#"
                 {
-                foo = bar ++
-                [ baz ];
+                  a = {
+                    alpha = 1;
+                    beta = 2;
+                    gamma = 3;
+                  };
+                  b = { };
                 }
+            "#)
+            .test(r#"
+                { short = { a = 1; }; }
+            "#, r#"
+                { short = { a = 1; }; }
+            "#)
+            .test(r#"
+                { greeting = "こんにちは"; name = "世界"; }
             "#, r#"
                 {
-                  foo = bar ++
-                    [ baz ];
+                  greeting = "こんにちは";
+                  name = "世界";
                 }
             "#)
-
-        .rule("Indent list content")
-            .inside(NODE_LIST)
-            .not_matching([T!["["], T!["]"]])
-            .set(Indent)
             .test(r#"
-                [
-                92
-                ]
+                { a = "ääääääääääääää"; b = "öööö"; }
             "#, r#"
-                [
-                  92
-                ]
+                { a = "ääääääääääääää"; b = "öööö"; }
             "#)
-        .rule("Indent parenthesized expressions")
-            .inside(NODE_PAREN)
-            .not_matching([T!["("],T![")"]])
-            .set(Indent)
+
+        .rule("One formal per line")
+            .inside(NODE_PATTERN)
+            .break_before(T!["}"])
             .test(r#"
-                (
-                92
-                )
+                { stdenv, fetchurl, lib, perl, python3 }:
+                stdenv.mkDerivation { }
             "#, r#"
-                (
-                  92
-                )
+                { stdenv
+                , fetchurl
+                , lib
+                , perl
+                , python3
+                }:
+                stdenv.mkDerivation { }
             "#)
-        
-        .rule("Indent attribute set content")
-            .inside(NODE_ATTR_SET)
-            .not_matching([T!["{"], T!["}"]])
-            .set(Indent)
             .test(r#"
+                { pkgs = callPackage ({ lib, fetchurl, perl }: { }) { }; }
+            "#, r#"
                 {
-                foo = bar;
+                  pkgs = callPackage
+                    ({ lib, fetchurl, perl }: { })
+                    { };
                 }
+            "#)
+
+        .rule("One inherited name per line")
+            .inside(NODE_INHERIT)
+            .break_before(NODE_IDENT)
+            .test(r#"
+                { inherit (pkgs) stdenv fetchurl perl python3; }
             "#, r#"
                 {
-                  foo = bar;
+                  inherit (pkgs)
+                    stdenv
+                    fetchurl
+                    perl
+                    python3;
                 }
             "#)
 
-        .rule("Indent newline let bindings ")
-            .inside(p(NODE_LET_IN) & p(newline_let))
-            .not_matching([T![let], T![in]])
-            .set(Indent)
-
-        .rule("Indent let bindings after key value")
-            .inside(p(NODE_LET_IN) & p(no_newline_let))
-            .not_matching(p([T![let], T![in], NODE_WITH, NODE_ASSERT]) | p(VALUES))
-            .set(Indent)
+        .rule("One let binding per line")
+            .inside(NODE_LET_IN)
+            .break_before(NODE_KEY_VALUE)
             .test(r#"
-                (
-                  let
-                  x = 1;
-                  inherit z;
-                  in
-                  x
-                )
+                { x = let alpha = 1; beta = 2; in alpha + beta; }
             "#, r#"
-                (
-                  let
-                    x = 1;
-                    inherit z;
-                  in
-                  x
-                )
+                {
+                  x =
+                    let
+                      alpha = 1;
+                      beta = 2;
+                    in
+                    alpha + beta;
+                }
+            "#)
+        .rule("Join short let")
+            .inside(p(NODE_LET_IN) & p(at_most_two_bindings))
+            .join()
+            .test(r#"
+                let
+                  a = 1;
+                in
+                a
+            "#, r#"
+                let a = 1; in a
+            "#)
+            .test(r#"
+                let
+                  a = 1;
+                  b = 2;
+                  c = 3;
+                in
+                a
+            "#, r#"
+                let
+                  a = 1;
+                  b = 2;
+                  c = 3;
+                in
+                a
             "#)
 
-
-        .rule("Indent attribute value")
-            .inside(NODE_KEY_VALUE)
-            .not_matching(T![;])
-            .set(Indent)
+        .rule("Join attribute set")
+            .inside(p(NODE_ATTR_SET) & p(no_magic_break))
+            .join()
             .test(r#"
+                { a = 1;
+                  b = { c = 2;
+                  };
+                }
+            "#, r#"
+                { a = 1; b = { c = 2; }; }
+            "#)
+            .test(r#"
+                { alpha = 1;
+                  beta = { gamma = 2; delta = 3; };
+                }
+            "#, r#"
                 {
-                  foo =
-                  92;
+                  alpha = 1;
+                  beta = { gamma = 2; delta = 3; };
+                }
+            "#)
+            .test(r#"
+                { a = 1; # comment
+                  b = { c = 2;
+                  };
                 }
             "#, r#"
                 {
-                  foo =
-                    92;
+                  a = 1; # comment
+                  b = { c = 2; };
                 }
             "#)
-
-        .rule("Indent lambda parameters")
-            .inside(NODE_PATTERN)
-            .not_matching([T!["{"], T!["}"], T![,]])
-            .set(Indent)
+            // The first entry on its own line is a request to keep the set
+            // exploded.
             .test(r#"
                 {
-                # comment
-                foo ? bar
-                , baz
-                }: foo
+                  a = 1;
+                  b = { c = 2;
+                  };
+                }
             "#, r#"
                 {
-                  # comment
-                  foo ? bar
-                , baz
-                }: foo
+                  a = 1;
+                  b = { c = 2; };
+                }
             "#)
-
-        .rule("Indent lambda body")
-            .inside(p(NODE_LAMBDA) & p(not_on_top_level) & p(pattern_not_newline))
-            .set(Indent)
-        .rule("Indent newline lambda body")
-            .inside(p(NODE_LAMBDA) & p(not_on_top_level) & p(pattern_newline) & p(lambda_inside_node_pattern))
-            .not_matching(p(TOKEN_COMMENT))
-            .set(Indent)
-         .rule("Indent newline lambda body")
-            .inside(p(NODE_LAMBDA) & p(not_on_top_level) & p(pattern_newline) & p(lambda_outside_node_pattern))
-            .not_matching(p(TOKEN_COMMENT) | p(VALUES))
-            .set(Indent)
             .test(r#"
-                {}:
-                  {
-                foo =
-                  # describe bar
-                  bar:
-                  # describe baz
-                  baz:
-                  fnbody;
+                { a = 1;
+
+                  # Describes the group below
+
+                  b = 2;
+                  # Describes c
+                  c = { d = 3;
+                  };
                 }
             "#, r#"
-                {}:
                 {
-                  foo =
-                    # describe bar
-                    bar:
-                    # describe baz
-                    baz:
-                    fnbody;
+                  a = 1;
+
+                  # Describes the group below
+
+                  b = 2;
+                  # Describes c
+                  c = { d = 3; };
                 }
             "#)
 
-        .rule("Indent top-level apply arg")
-            .inside(p(NODE_APPLY) & p(on_top_level))
-            .not_matching([T!["{"], T!["}"], NODE_ATTR_SET])
-            .set(Indent)
+        .rule("Join list")
+            .inside(p(NODE_LIST) & p(no_magic_break))
+            .join()
+            .test(r#"
+                {
+                  xs = [ 1
+                    2
+                  ];
+                  ys = [ "alpha" "beta" "gamma" "delta" "epsilon"
+                  ];
+                  zs = [
+                    1
+                    2
+                  ];
+                }
+            "#, r#"
+                {
+                  xs = [ 1 2 ];
+                  ys = [
+                    "alpha"
+                    "beta"
+                    "gamma"
+                    "delta"
+                    "epsilon"
+                  ];
+                  zs = [
+                    1
+                    2
+                  ];
+                }
+            "#)
             .test(r#"
-                foo
-                bar baz
+                [ # comment
+                  1
+                ]
             "#, r#"
-                foo
-                  bar
-                  baz
+                [ # comment
+                  1
+                ]
             "#)
 
-        .rule("Indent apply arg")
-            .inside(p(NODE_APPLY) & p(not_on_top_level) & p(not_inline_apply))
-            .not_matching([T!["{"], T!["}"]])
-            .set(Indent)
+        .rule("One list element per line")
+            .inside(NODE_LIST)
+            .break_before(T!["]"])
             .test(r#"
-                foo
-                bar baz
+                [ "alpha" "beta" "gamma" "delta" "epsilon" ]
             "#, r#"
-                foo
-                  bar
-                  baz
+                [
+                  "alpha"
+                  "beta"
+                  "gamma"
+                  "delta"
+                  "epsilon"
+                ]
+            "#)
+            .test(r#"
+                f [ 1 2 ] [ "alpha" "beta" "gamma" "delta" ]
+            "#, r#"
+                f [ 1 2 ] [
+                  "alpha"
+                  "beta"
+                  "gamma"
+                  "delta"
+                ]
+            "#)
+            .test(r#"
+                [ "alpha" "beta" "gamma" # why
+                  "delta" ]
+            "#, r#"
+                [
+                  "alpha"
+                  "beta"
+                  "gamma" # why
+                  "delta"
+                ]
             "#)
 
-        .rule("Indent apply arg")
-            .inside(p(NODE_APPLY) & p(not_on_top_level) & p(inline_apply))
-            .not_matching([T!["{"], T!["}"]])
-            .set(Indent)
-
-        .rule("Indent with body in attribute")
-            .inside([NODE_WITH, NODE_ASSERT])
-            .when_anchor(NODE_KEY_VALUE)
-            .set(Indent)
+        .rule("Break operator chain")
+            .inside(p(NODE_BIN_OP) & p(outermost_operator_chain))
+            .break_before_each(chain_operators)
             .test(r#"
-                with foo;
-                  {
-                  bar = with baz;
-                  body;
-                  }
+                { x = defaults // overrides // extraOverrides; }
             "#, r#"
-                with foo;
                 {
-                  bar = with baz;
-                    body;
+                  x = defaults
+                    // overrides
+                    // extraOverrides;
                 }
             "#)
-
-        .rule("Indent or default")
-            .inside(NODE_OR_DEFAULT)
-            .set(Indent)
             .test(r#"
+                { x = "--prefix=" + placeholder "out" + "/share"; }
+            "#, r#"
                 {
-                  x = foo or
-                  bar;
+                  x = "--prefix="
+                    + placeholder "out"
+                    + "/share";
                 }
+            "#)
+            .test(r#"
+                { x = firstNumber + secondNumber + thirdNumber; }
             "#, r#"
                 {
-                  x = foo or
-                    bar;
+                  x =
+                    firstNumber + secondNumber + thirdNumber;
                 }
             "#)
+            .test(r#"
+                srcs ++ patches ++ optional withDocs docs ++ extraFiles
+            "#, r#"
+                srcs
+                ++ patches
+                ++ optional withDocs docs
+                ++ extraFiles
+            "#)
 
-        .rule("Indent if-then-else")
-            .inside(p(NODE_IF_ELSE) & p(inline_if_else))
-            .not_matching(p([T![if], T![then], T![else]]) | p(VALUES))
-            .set(Indent)
-
-        .rule("Indent if-then-else")
-            .inside(p(NODE_IF_ELSE) & p(not_inline_if_else))
-            .not_matching([T![if], T![then], T![else], TOKEN_COMMENT])
-            .set(Indent)
+        .rule("One argument per line")
+            .inside(p(NODE_APPLY) & p(outermost_apply))
+            .break_before_each(apply_arguments)
             .test(r#"
-                if
-                foo
-                then
-                bar
-                else
-                baz
+                someFunction argumentOne argumentTwo argumentThree
             "#, r#"
-                if
-                  foo
-                then
-                  bar
-                else
-                  baz
+                someFunction
+                  argumentOne
+                  argumentTwo
+                  argumentThree
+            "#)
+            .test(r#"
+                { pkg = callPackage ./some/package/path.nix { }; }
+            "#, r#"
+                {
+                  pkg = callPackage
+                    ./some/package/path.nix
+                    { };
+                }
+            "#)
+            .test(r#"
+                stdenv.mkDerivation rec { pname = "hello"; version = "2.10"; }
+            "#, r#"
+                stdenv.mkDerivation rec {
+                  pname = "hello";
+                  version = "2.10";
+                }
             "#)
 
-        .rule("Indent inherit parts")
-            .inside(NODE_INHERIT)
-            .set(Indent)
+        // Only if the value itself can't be broken
+        .rule("Break before attribute value")
+            .inside(NODE_KEY_VALUE)
+            .break_before_each(key_value_value)
+            .test(r#"
+                { description = "Yet another rather long string"; }
+            "#, r#"
+                {
+                  description =
+                    "Yet another rather long string";
+                }
+            "#)
             .test(r#"
+                { description = # temporary
+                    "Yet another rather long string"; }
+            "#, r#"
                 {
-                  inherit
-                  (builtins)
-                  # comment
-                  toString
-                  ;
+                  description = # temporary
+                    "Yet another rather long string";
                 }
+            "#)
+            .test(r#"
+                { description = /* why */ "Yet another long string"; }
             "#, r#"
                 {
-                  inherit
-                    (builtins)
-                    # comment
-                    toString
-                    ;
+                  description =
+                    /* why */ "Yet another long string";
                 }
             "#)
     ;
@@ -658,6 +1448,156 @@ pub(crate) fn indentation() -> IndentDsl {
     dsl
 }
 
+/// Is this application not a function of another one?
+///
+/// `f a b` is parsed as `(f a) b`, and it is the outer application which
+/// should be broken.
+fn outermost_apply(element: &SyntaxElement) -> bool {
+    match element.parent() {
+        Some(parent) if parent.kind() == NODE_APPLY => {
+            parent.first_child().map(SyntaxElement::from) != Some(element.clone())
+        }
+        _ => true,
+    }
+}
+
+/// Collects the arguments of a (curried) function application.
+fn apply_arguments(node: &SyntaxNode) -> Vec<SyntaxElement> {
+    let mut res: Vec<SyntaxElement> =
+        successors(Apply::cast(node.clone()), |it| it.lambda().and_then(Apply::cast))
+            .filter_map(|it| it.value())
+            .map(SyntaxElement::from)
+            .collect();
+    res.reverse();
+    res
+}
+
+/// Which operator chains can be broken?
+///
+/// For `+`, only string concatenations are broken: breaking arithmetic hurts
+/// readability more than a long line.
+fn chain_operator(node: &SyntaxNode) -> Option<BinOpKind> {
+    match BinOp::cast(node.clone())?.operator() {
+        it @ BinOpKind::Update
+        | it @ BinOpKind::Concat
+        | it @ BinOpKind::Add
+        | it @ BinOpKind::And
+        | it @ BinOpKind::Or => Some(it),
+        _ => None,
+    }
+}
+
+/// Is this a breakable chain of operators which is not an operand of the same
+/// operator?
+fn outermost_operator_chain(element: &SyntaxElement) -> bool {
+    let node = match element.as_node() {
+        Some(it) => it,
+        None => return false,
+    };
+    let operator = match chain_operator(node) {
+        Some(it) => it,
+        None => return false,
+    };
+    if node.parent().and_then(|it| chain_operator(&it)) == Some(operator) {
+        return false;
+    }
+    operator != BinOpKind::Add || chain_operands(node).iter().any(|it| it.kind() == NODE_STRING)
+}
+
+/// Collects the operators of a chain of the same binary operator.
+fn chain_operators(node: &SyntaxNode) -> Vec<SyntaxElement> {
+    let operator = chain_operator(node);
+    let mut res = Vec::new();
+    for child in node.children_with_tokens() {
+        match &child {
+            NodeOrToken::Node(it) if chain_operator(it) == operator => {
+                res.extend(chain_operators(it))
+            }
+            NodeOrToken::Token(it) if BIN_OPS.contains(&it.kind()) => res.push(child),
+            _ => (),
+        }
+    }
+    res
+}
+
+fn chain_operands(node: &SyntaxNode) -> Vec<SyntaxNode> {
+    let operator = chain_operator(node);
+    let mut res = Vec::new();
+    for child in node.children() {
+        if chain_operator(&child) == operator {
+            res.extend(chain_operands(&child))
+        } else {
+            res.push(child)
+        }
+    }
+    res
+}
+
+/// `with lib;` is short, and is better kept on the line of its body.
+fn with_compound_namespace(element: &SyntaxElement) -> bool {
+    let namespace = element.as_node().cloned().and_then(With::cast).and_then(|it| it.namespace());
+    namespace.map(|it| it.kind() != NODE_IDENT && it.kind() != NODE_SELECT) == Some(true)
+}
+
+fn with_or_assert_body(node: &SyntaxNode) -> Vec<SyntaxElement> {
+    let body = match node.kind() {
+        NODE_WITH => With::cast(node.clone()).and_then(|it| it.body()),
+        _ => Assert::cast(node.clone()).and_then(|it| it.body()),
+    };
+    body.map(SyntaxElement::from).into_iter().collect()
+}
+
+fn at_most_two_bindings(element: &SyntaxElement) -> bool {
+    let n_bindings = element.as_node().map(|it| {
+        it.children().filter(|it| it.kind() == NODE_KEY_VALUE || it.kind() == NODE_INHERIT).count()
+    });
+    n_bindings.map(|it| it <= 2) == Some(true)
+}
+
+/// Is the first element of a list or an attribute set on the line of the
+/// opening bracket?
+///
+/// Otherwise, the author has clearly asked for the exploded layout.
+fn no_magic_break(element: &SyntaxElement) -> bool {
+    let node = match element.as_node() {
+        Some(it) => it,
+        None => return false,
+    };
+    let open = node.children_with_tokens().find(|it| it.kind() == T!["{"] || it.kind() == T!["["]);
+    let ws = open.and_then(|it| it.next_sibling_or_token());
+    match ws {
+        Some(NodeOrToken::Token(it)) if it.kind() == TOKEN_WHITESPACE => !it.text().contains('\n'),
+        _ => true,
+    }
+}
+
+fn key_value_value(node: &SyntaxNode) -> Vec<SyntaxElement> {
+    KeyValue::cast(node.clone())
+        .and_then(|it| it.value())
+        .map(SyntaxElement::from)
+        .into_iter()
+        .collect()
+}
+
+/// Is this binary operator, or the chain of operators it belongs to, on the
+/// top level?
+fn binop_chain_on_top_level(element: &SyntaxElement) -> bool {
+    match element.parent() {
+        Some(parent) if parent.kind() == NODE_BIN_OP => binop_chain_on_top_level(&parent.into()),
+        _ => on_top_level(element),
+    }
+}
+
+fn binop_chain_not_on_top_level(element: &SyntaxElement) -> bool {
+    !binop_chain_on_top_level(element)
+}
+
+/// Consecutive `with`s and `assert`s starting their own lines are indented
+/// only once.
+fn not_chained_with_or_assert(element: &SyntaxElement) -> bool {
+    element.kind() != NODE_WITH && element.kind() != NODE_ASSERT
+}
+
 fn inline_apply(element: &SyntaxElement) -> bool {
     !not_inline_apply(element)
 }
@@ -695,6 +1635,15 @@ fn not_inline_if_else(element: &SyntaxElement) -> bool {
     not_inline_if_then_else(element) == Some(true)
 }
 
+/// Is this a comment which describes the following `then` or `else` keyword,
+/// rather than the branch body?
+fn comment_before_keyword(element: &SyntaxElement) -> bool {
+    match element.as_token().filter(|it| it.kind() == TOKEN_COMMENT).map(comments::attachment) {
+        Some(Attachment::Leading(it)) => it.kind() == T![then] || it.kind() == T![else],
+        _ => false,
+    }
+}
+
 fn pattern_newline(element: &SyntaxElement) -> bool {
     fn lambda_has_newline(element: &SyntaxElement) -> Option<bool> {
         let first_el = element
@@ -751,6 +1700,91 @@ fn no_newline_let(element: &SyntaxElement) -> bool {
     !newline_let(element)
 }
 
+/// The width for the "Line too long" lint when `Config::max_width` is not set.
+const DEFAULT_LINT_WIDTH: u32 = 100;
+
+/// Attribute sets nested deeper than this are flagged.
+const MAX_SET_DEPTH: usize = 6;
+
+#[rustfmt::skip]
+pub(crate) fn lints(config: &Config) -> LintDsl {
+    let max_width = config.max_width.unwrap_or(DEFAULT_LINT_WIDTH);
+    let mut dsl = LintDsl::default();
+    dsl
+        .rule("Line too long")
+            .matching(NODE_ROOT)
+            .warn_at(move |root| overlong_lines(root, max_width))
+            .test(
+                "{\n  description = \"A string which does not fit\";\n}\n",
+                &[" fit\";"],
+            )
+            .test("{\n  a = 1;\n}\n", &[])
+
+        .rule("Attribute set nested too deeply")
+            .matching(p(NODE_ATTR_SET) & p(|it: &SyntaxElement| set_depth(it) == MAX_SET_DEPTH))
+            .warn()
+            .test("{a={b={c={d={e={f=1;};};};};};}", &["{f=1;}"])
+            .test("{a={b={c={d={e=1;};};};};}", &[])
+
+        .rule("Indentation mixes tabs and spaces")
+            .matching(TOKEN_WHITESPACE)
+            .warn_at(mixed_indentation)
+            .test("{\n\t  a = 1;\n  b = 2;\n}\n", &["\t  "])
+            .test("{\n\t\ta = 1;\n}\n", &[])
+    ;
+
+    dsl
+}
+
+/// The parts of the lines of `root` which stick out past `max_width`.
+fn overlong_lines(root: &SyntaxElement, max_width: u32) -> Vec<TextRange> {
+    let text = root.to_string();
+    let mut res = Vec::new();
+    let mut line_start = root.text_range().start();
+    for raw_line in text.split('\n') {
+        let line = raw_line.strip_suffix('\r').unwrap_or(raw_line);
+        let mut width = 0;
+        let overflow = line.char_indices().find(|&(_, c)| {
+            width += c.width().unwrap_or(0);
+            width > max_width as usize
+        });
+        if let Some((idx, _)) = overflow {
+            let start = line_start + TextSize::of(&line[..idx]);
+            res.push(TextRange::new(start, line_start + TextSize::of(line)));
+        }
+        line_start += TextSize::of(raw_line) + TextSize::of("\n");
+    }
+    res
+}
+
+/// How many attribute sets, including this one, contain this element?
+fn set_depth(element: &SyntaxElement) -> usize {
+    let parents = successors(element.parent(), |it| it.parent());
+    let self_depth = if element.kind() == NODE_ATTR_SET { 1 } else { 0 };
+    self_depth + parents.filter(|it| it.kind() == NODE_ATTR_SET).count()
+}
+
+/// The indentation at the end of this whitespace, if it has both tabs and
+/// spaces.
+fn mixed_indentation(element: &SyntaxElement) -> Vec<TextRange> {
+    let token = match element.as_token() {
+        Some(it) => it,
+        None => return Vec::new(),
+    };
+    let text = token.text();
+    let indent_start = match text.rfind('\n') {
+        Some(it) => it + 1,
+        None if token.text_range().start() == 0.into() => 0,
+        None => return Vec::new(),
+    };
+    let indent = text[indent_start..].trim_start_matches('\r');
+    if !(indent.contains('\t') && indent.contains(' ')) {
+        return Vec::new();
+    }
+    let end = token.text_range().end();
+    vec![TextRange::new(end - TextSize::of(indent), end)]
+}
+
 static VALUES: &[SyntaxKind] = &[
     NODE_LAMBDA,
     NODE_IDENT,
@@ -786,19 +1820,28 @@ static BIN_OPS: &[SyntaxKind] = &[
 #[cfg(test)]
 mod tests {
     use std::{
+        collections::{BTreeMap, HashSet},
         fs,
         path::{Path, PathBuf},
     };
 
+    use rnix::{SyntaxKind::TOKEN_WHITESPACE, TextRange};
+
     use crate::{
-        reformat_string,
-        rules::{indentation, spacing},
+        builtin_rules,
+        config::{Config, StyleVersion},
+        dsl::{InlineTest, TEST_WIDTH},
+        incremental, is_formatted, reformat_string_with_config,
+        rules::{indentation, lints, spacing, wrapping},
+        tree_utils::walk_tokens,
+        Formatter, RuleGroup, TextEdit,
     };
 
     #[test]
     fn smoke() {
         TestCase {
             name: None,
+            config: Config::default(),
             before: "{
 foo = x:
 92;
@@ -821,14 +1864,23 @@ foo = x:
     /// This test extracts such test cases and checks them.
     #[test]
     fn test_inline_spacing_tests() {
-        let rules = spacing();
+        let rules = spacing(&Config::default());
         let tests: Vec<TestCase> = rules
             .tests
             .iter()
-            .map(|&(before, after)| {
-                let before = before.to_string();
-                let after = format!("{}\n", after);
-                TestCase::from_before_after(before, after)
+            .map(|test| match test.name {
+                Some(_) => {
+                    let mut res = TestCase::from_inline(test);
+                    if !res.after.ends_with('\n') {
+                        res.after.push('\n')
+                    }
+                    res
+                }
+                None => {
+                    let before = test.before.to_string();
+                    let after = format!("{}\n", test.after);
+                    TestCase::from_before_after(before, after)
+                }
             })
             .collect();
         run(&tests)
@@ -836,19 +1888,59 @@ foo = x:
 
     #[test]
     fn test_inline_indentation_tests() {
-        let rules = indentation();
+        let rules = indentation(&Config::default());
+        let tests: Vec<TestCase> = rules.tests.iter().map(TestCase::from_inline).collect();
+        run(&tests)
+    }
+
+    #[test]
+    fn test_inline_wrapping_tests() {
+        let rules = wrapping(&Config::default());
+        let config =
+            Config { max_width: Some(TEST_WIDTH), canonical_layout: true, ..Config::default() };
         let tests: Vec<TestCase> = rules
             .tests
             .iter()
-            .map(|&(before, after)| {
-                let before = unindent::unindent(before);
-                let after = unindent::unindent(after);
-                TestCase::from_before_after(before, after)
-            })
+            .map(|test| TestCase { config: config.clone(), ..TestCase::from_inline(test) })
             .collect();
         run(&tests)
     }
 
+    #[test]
+    fn test_trailing_comma_formals() {
+        let config = Config { trailing_commas: true, ..Config::default() };
+        let wrapping = Config { max_width: Some(TEST_WIDTH), ..config.clone() };
+        let case = |config: &Config, before: &str, after: &str| TestCase {
+            config: config.clone(),
+            ..TestCase::from_before_after(before.to_string(), after.to_string())
+        };
+        run(&[
+            case(&config, "{ foo,bar }: 92", "{ foo, bar }: 92\n"),
+            case(&config, "{ a\n, b ? 1\n, c\n}:\na", "{\n  a,\n  b ? 1,\n  c\n}:\na\n"),
+            case(
+                &wrapping,
+                "{ stdenv, fetchurl, lib, perl, python3 }:\nstdenv.mkDerivation { }",
+                "{\n  stdenv,\n  fetchurl,\n  lib,\n  perl,\n  python3\n}:\nstdenv.mkDerivation { }\n",
+            ),
+        ])
+    }
+
+    #[test]
+    fn test_inline_lint_tests() {
+        let config = Config { max_width: Some(TEST_WIDTH), ..Config::default() };
+        let mut failures = Vec::new();
+        for test in lints(&config).tests.iter() {
+            let flagged: Vec<&str> = crate::lint(test.text, &config)
+                .into_iter()
+                .map(|it| &test.text[it.range])
+                .collect();
+            if flagged != test.flagged {
+                failures.push(format!("{:?}: {:?} != {:?}", test.text, flagged, test.flagged))
+            }
+        }
+        assert!(failures.is_empty(), "{:#?}", failures);
+    }
+
     #[test]
     fn test_bad_good_tests() {
         let test_data = {
@@ -869,6 +1961,194 @@ foo = x:
         run(&tests);
     }
 
+    /// The formatted files of the corpus.
+    fn formatted_files() -> Vec<TestCase> {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let mut res = TestCase::collect_from_dir(&dir.join("test_data"));
+        res.extend(TestCase::collect_from_dir(&dir.join("test_data/nixpkgs_repository")));
+        res
+    }
+
+    /// An extra space in the indentation of a formatted file is fixed the way
+    /// formatting the whole file fixes it.
+    #[test]
+    fn test_incremental_reformat_of_extra_space() {
+        let formatter = Formatter::new(Config::default());
+        for test in formatted_files() {
+            let text = &test.after;
+            let tree = rnix::parse(text).node();
+            let indents = walk_tokens(&tree)
+                .filter(|it| it.kind() == TOKEN_WHITESPACE && it.text().contains('\n'));
+            // Every line would be too slow for a debug build.
+            for token in indents.step_by(4) {
+                let range = TextRange::empty(token.text_range().end());
+                let edit = TextEdit { range, new_text: " ".into() };
+                let (actual, new_tree) = incremental::reformat(text, &tree, &edit, &formatter);
+                let mut new_text = text.clone();
+                new_text.insert(usize::from(range.start()), ' ');
+                assert_eq!(
+                    actual,
+                    formatter.format(&new_text),
+                    "\nTest: {}, offset {:?}\n",
+                    test.name.as_deref().unwrap_or(""),
+                    range.start(),
+                );
+                assert_eq!(
+                    format!("{:#?}", new_tree),
+                    format!("{:#?}", rnix::parse(&actual).node())
+                );
+            }
+        }
+    }
+
+    /// Reformatting any part of a formatted file leaves it as is.
+    #[test]
+    fn test_incremental_reformat_of_formatted() {
+        for test in formatted_files() {
+            let formatter = Formatter::new(test.config.clone());
+            let text = &test.after;
+            let tokens: Vec<TextRange> =
+                walk_tokens(&rnix::parse(text).node()).map(|it| it.text_range()).collect();
+            // Ranges of one to seven tokens, starting at every eighth one, as every
+            // token would be too slow for a debug build.
+            for start in (0..tokens.len()).step_by(8) {
+                let end = (start + start % 7).min(tokens.len() - 1);
+                let range = tokens[start].cover(tokens[end]);
+                assert_eq!(
+                    &incremental::reformat_changed(text, &[range], &formatter),
+                    text,
+                    "\nTest: {}, range {:?}\n",
+                    test.name.as_deref().unwrap_or(""),
+                    range,
+                );
+            }
+        }
+    }
+
+    /// The output for a pinned `StyleVersion` must never change.
+    ///
+    /// The whole corpus is formatted with `StyleVersion::V1`, and the hashes
+    /// of the results are compared with `test_data/style_v1.hashes`. The
+    /// `.good.nix` files may change with a new version, but the hashes
+    /// don't: only the lines of new files are ever added there.
+    #[test]
+    fn test_style_v1_snapshot() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let frozen = fs::read_to_string(dir.join("test_data/style_v1.hashes")).unwrap();
+        let mut frozen: BTreeMap<&str, &str> =
+            frozen.lines().filter_map(|line| line.split_once(' ')).map(|(h, n)| (n, h)).collect();
+        let mut errors = Vec::new();
+        for subdir in ["", "nixpkgs_repository/"].iter() {
+            for mut test in TestCase::collect_from_dir(&dir.join("test_data").join(subdir)) {
+                test.config.style_version = StyleVersion::V1;
+                let name = format!("{}{}", subdir, test.name.unwrap());
+                let actual = format!(
+                    "{:016x}",
+                    stable_hash(&reformat_string_with_config(&test.before, &test.config))
+                );
+                match frozen.remove(name.as_str()) {
+                    Some(hash) if hash == actual => (),
+                    Some(hash) => errors.push(format!("{}: {} is now {}", name, hash, actual)),
+                    None => {
+                        errors.push(format!("{}: not in the list, add `{} {}`", name, actual, name))
+                    }
+                }
+            }
+        }
+        errors.extend(frozen.keys().map(|name| format!("{}: not in the corpus", name)));
+        assert!(errors.is_empty(), "\nThe V1 output changed:\n{}\n", errors.join("\n"));
+    }
+
+    /// FNV-1a, which unlike `DefaultHasher` is the same in every Rust release.
+    fn stable_hash(text: &str) -> u64 {
+        text.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+
+    /// Every spacing and indentation rule must decide some whitespace in the
+    /// test corpus, otherwise it is dead, or shadowed by other rules. Likewise,
+    /// every wrapping rule must break or join some lines, with the width of
+    /// the inline wrapping tests.
+    ///
+    /// Set `NIXPKGS` to a nixpkgs checkout to also list the rules which never
+    /// apply to it.
+    #[test]
+    fn test_rule_coverage() {
+        let formatter = Formatter::new(Config::default());
+        let wrap_formatter = Formatter::new(Config {
+            max_width: Some(TEST_WIDTH),
+            canonical_layout: true,
+            ..Config::default()
+        });
+        let mut texts: Vec<String> = spacing(&Config::default())
+            .tests
+            .iter()
+            .map(|test| unindent::unindent(test.before))
+            .collect();
+        texts.extend(
+            indentation(&Config::default())
+                .tests
+                .iter()
+                .map(|test| unindent::unindent(test.before)),
+        );
+        texts.extend(
+            wrapping(&Config::default()).tests.iter().map(|test| unindent::unindent(test.before)),
+        );
+        for dir in ["test_data", "test_data/nixpkgs_repository"].iter() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(dir);
+            texts.extend(TestCase::collect_from_dir(&dir).into_iter().map(|it| it.before));
+        }
+        let never_fired = |texts: &[String]| -> Vec<&'static str> {
+            let fired: HashSet<&'static str> = texts
+                .iter()
+                .flat_map(|text| formatter.fired_rules(text))
+                .chain(texts.iter().flat_map(|text| wrap_formatter.fired_rules(text)))
+                .collect();
+            builtin_rules()
+                .into_iter()
+                .filter(|it| it.group != RuleGroup::Lint)
+                .filter(|it| !fired.contains(it.name))
+                .map(|it| it.name)
+                .collect()
+        };
+        let dead = never_fired(&texts);
+        assert!(dead.is_empty(), "rules which never apply to the test corpus: {:#?}", dead);
+
+        if let Some(nixpkgs) = std::env::var_os("NIXPKGS") {
+            let mut texts = Vec::new();
+            collect_nix_files(Path::new(&nixpkgs), &mut texts);
+            eprintln!("rules which never apply to nixpkgs: {:#?}", never_fired(&texts));
+        }
+
+        fn collect_nix_files(dir: &Path, acc: &mut Vec<String>) {
+            for entry in fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    collect_nix_files(&path, acc)
+                } else if path.extension().map(|it| it == "nix") == Some(true) {
+                    acc.extend(fs::read_to_string(&path).ok())
+                }
+            }
+        }
+    }
+
+    /// Where spacing rules disagree, the winner must be chosen by priority,
+    /// not by the order of the rules.
+    #[test]
+    fn test_no_spacing_rule_conflicts() {
+        let formatter = Formatter::new(Config::default());
+        let mut texts: Vec<String> =
+            spacing(&Config::default()).tests.iter().map(|test| test.before.into()).collect();
+        for dir in ["test_data", "test_data/nixpkgs_repository"].iter() {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(dir);
+            texts.extend(TestCase::collect_from_dir(&dir).into_iter().map(|it| it.before));
+        }
+        let conflicts: Vec<_> =
+            texts.iter().flat_map(|text| formatter.rule_conflicts(text)).collect();
+        assert!(conflicts.is_empty(), "conflicting spacing rules: {:#?}", conflicts);
+    }
+
     #[test]
     fn test_syntax_errors_tests() {
         let test_data = {
@@ -898,13 +2178,24 @@ foo = x:
     #[derive(Debug)]
     struct TestCase {
         name: Option<String>,
+        config: Config,
         before: String,
         after: String,
     }
 
     impl TestCase {
         fn from_before_after(before: String, after: String) -> TestCase {
-            TestCase { name: None, before, after }
+            TestCase { name: None, config: Config::default(), before, after }
+        }
+
+        /// Multiline inline tests are indented like the code around them.
+        fn from_inline(test: &InlineTest) -> TestCase {
+            let before = unindent::unindent(test.before);
+            let after = unindent::unindent(test.after);
+            TestCase {
+                name: test.name.map(String::from),
+                ..TestCase::from_before_after(before, after)
+            }
         }
 
         fn collect_from_dir(dir: &Path) -> Vec<TestCase> {
@@ -917,6 +2208,7 @@ foo = x:
                     let after_name = before_name.replace(".bad.", ".good.");
                     let test_case = TestCase {
                         name: Some(after_name.to_string()),
+                        config: Config::default(),
                         before: fs::read_to_string(dir.join(before_name)).unwrap(),
                         after: fs::read_to_string(dir.join(&after_name)).unwrap_or_else(|_err| {
                             panic!("{} not found", after_name);
@@ -932,8 +2224,8 @@ foo = x:
         fn run(&self) -> Result<(), String> {
             let name = self.name.as_ref().map(|it| it.as_str()).unwrap_or("");
             let expected = &self.after;
-            let actual = &reformat_string(&self.before);
-            let second_round = &reformat_string(actual);
+            let actual = &reformat_string_with_config(&self.before, &self.config);
+            let second_round = &reformat_string_with_config(actual, &self.config);
             if actual != second_round {
                 return Err(format!(
                     "\n\nAssertion failed: formatting is not idempotent\
@@ -943,6 +2235,22 @@ foo = x:
                     name, actual, second_round,
                 ));
             }
+            if is_formatted(&self.before, &self.config) != (&self.before == actual) {
+                return Err(format!(
+                    "\n\nAssertion failed: is_formatted disagrees with formatting\
+                     \nTest: {}\n\
+                     \nBefore:\n{}\n",
+                    name, self.before,
+                ));
+            }
+            if !is_formatted(actual, &self.config) {
+                return Err(format!(
+                    "\n\nAssertion failed: is_formatted rejects the formatted text\
+                     \nTest: {}\n\
+                     \nAfter:\n{}\n",
+                    name, actual,
+                ));
+            }
             if expected != actual {
                 return Err(format!(
                     "\n\nAssertion failed: wrong formatting\
@@ -3,8 +3,8 @@ use std::iter::successors;
 use rnix::{
     NodeOrToken, SyntaxElement,
     SyntaxKind::{
-        NODE_APPLY, NODE_ASSERT, NODE_IF_ELSE, NODE_LAMBDA, NODE_LET_IN, NODE_PAREN, NODE_ROOT,
-        NODE_STRING_INTERPOL, NODE_WITH, TOKEN_WHITESPACE,
+        NODE_APPLY, NODE_ASSERT, NODE_ERROR, NODE_IF_ELSE, NODE_LAMBDA, NODE_LET_IN, NODE_PAREN,
+        NODE_ROOT, NODE_STRING_INTERPOL, NODE_WITH, TOKEN_WHITESPACE,
     },
     SyntaxNode, SyntaxToken, WalkEvent,
 };
@@ -16,29 +16,36 @@ pub(crate) fn walk(node: &SyntaxNode) -> impl Iterator<Item = SyntaxElement> {
     })
 }
 
-pub(crate) fn walk_non_whitespace_non_interpol(
-    node: &SyntaxNode,
-) -> impl Iterator<Item = SyntaxElement> {
-    let mut interpool_level = 0;
-    node.preorder_with_tokens().filter_map(move |event| {
-        match &event {
-            WalkEvent::Enter(element) if element.kind() == NODE_STRING_INTERPOL => {
-                interpool_level += 1
+/// Walks the elements which are subject to formatting.
+///
+/// That is, everything except for whitespace, the insides of string
+/// interpolations, and the insides of syntax errors. The latter are left
+/// exactly as they were written: we can't know what the author meant there.
+pub(crate) fn walk_formattable(node: &SyntaxNode) -> impl Iterator<Item = SyntaxElement> {
+    let mut skip_level = 0;
+    node.preorder_with_tokens().filter_map(move |event| match event {
+        WalkEvent::Enter(element) => {
+            let res = Some(element.clone())
+                .filter(|it| skip_level == 0 && it.kind() != TOKEN_WHITESPACE)
+                .filter(|it| it.kind() != NODE_STRING_INTERPOL);
+            if is_opaque(&element) {
+                skip_level += 1
             }
-            WalkEvent::Leave(element) if element.kind() == NODE_STRING_INTERPOL => {
-                interpool_level -= 1
-            }
-            _ => (),
+            res
         }
-        match event {
-            WalkEvent::Enter(element) => {
-                Some(element).filter(|it| interpool_level == 0 && it.kind() != TOKEN_WHITESPACE)
+        WalkEvent::Leave(element) => {
+            if is_opaque(&element) {
+                skip_level -= 1
             }
-            WalkEvent::Leave(_) => None,
+            None
         }
     })
 }
 
+fn is_opaque(element: &SyntaxElement) -> bool {
+    element.kind() == NODE_STRING_INTERPOL || element.kind() == NODE_ERROR
+}
+
 pub(crate) fn walk_tokens(node: &SyntaxNode) -> impl Iterator<Item = SyntaxToken> {
     walk(node).filter_map(|element| match element {
         NodeOrToken::Token(token) => Some(token),
@@ -122,3 +129,19 @@ pub(crate) fn next_non_whitespace_sibling(element: &SyntaxElement) -> Option<Syn
 pub(crate) fn preceding_tokens(node: &SyntaxNode) -> impl Iterator<Item = SyntaxToken> {
     successors(node.first_token().and_then(|it| it.prev_token()), |it| it.prev_token())
 }
+
+/// The positions of the `node` and its ancestors among their parents'
+/// children, from the root down.
+pub(crate) fn path_from_root(node: &SyntaxNode) -> Vec<usize> {
+    let mut res: Vec<usize> = node
+        .ancestors()
+        .filter_map(|it| it.parent()?.children().position(|child| child == it))
+        .collect();
+    res.reverse();
+    res
+}
+
+/// Finds the node at the `path` returned by `path_from_root`.
+pub(crate) fn node_at_path(root: &SyntaxNode, path: &[usize]) -> Option<SyntaxNode> {
+    path.iter().try_fold(root.clone(), |node, &idx| node.children().nth(idx))
+}
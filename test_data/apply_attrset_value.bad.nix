@@ -0,0 +1,12 @@
+{
+  src = fetchFromGitHub {
+        owner = "x";
+   repo = "y";
+       };
+  meta = lib.recursiveUpdate old.meta (lib.optionalAttrs x {
+        a = 1;
+      });
+  package = pkgs.callPackage ./pkg.nix {
+              inherit (pkgs) lib;
+     };
+}
\ No newline at end of file
@@ -0,0 +1,7 @@
+{ callPackage }:
+callPackage
+  ./pkg.nix
+  {
+    inherit (lib)
+      foo;
+  }
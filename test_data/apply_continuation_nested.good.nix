@@ -0,0 +1,10 @@
+{
+  x = callPackage
+    ./pkg.nix
+    { };
+  y = [
+    (f
+      a
+      b)
+  ];
+}
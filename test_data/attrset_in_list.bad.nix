@@ -0,0 +1,14 @@
+{
+  imports = [
+        ./a.nix
+   { services.foo.enable = true;
+       services.bar = {
+    enable = true;
+           };
+          }
+    ];
+  modules = [ { a = 1; }
+      {
+     b = 2;
+        } ];
+}
\ No newline at end of file
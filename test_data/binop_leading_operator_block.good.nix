@@ -0,0 +1,11 @@
+{
+  buildInputs = [ a ]
+    ++ lib.optionals stdenv.isLinux [
+      b
+      c
+    ]
+    ++ lib.optional d {
+      e = 1;
+    }
+    ++ f;
+}
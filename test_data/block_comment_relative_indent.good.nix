@@ -0,0 +1,14 @@
+{
+  /* Example:
+       foo = {
+         bar = 1;
+       };
+  */
+  foo = 1;
+  bar = {
+    /* Shifted right, together with
+         the nested lines.
+    */
+    baz = 2;
+  };
+}
@@ -0,0 +1,23 @@
+{
+  a = {
+    x = 1;
+        };
+  b = [
+    1
+      ];
+  c = (
+    foo
+       );
+  e = foo (
+    bar
+        );
+  f = x: {
+      y = [
+        x
+          ];
+    };
+  nested = { inner = [ (
+    foo
+      ) ];
+ };
+}
@@ -0,0 +1,26 @@
+{
+  a = {
+    x = 1;
+  };
+  b = [
+    1
+  ];
+  c = (
+    foo
+  );
+  e = foo (
+    bar
+  );
+  f = x: {
+    y = [
+      x
+    ];
+  };
+  nested = {
+    inner = [
+      (
+        foo
+      )
+    ];
+  };
+}
@@ -0,0 +1,17 @@
+{
+  a = # c
+    x: x;
+  b = # c
+    let
+      y = 1;
+    in
+    y;
+  c = # c
+    # more
+    [
+      1
+    ];
+  d = # c
+    f
+      g;
+}
@@ -0,0 +1,18 @@
+{
+    a = 1;
+
+  # Describes the group below
+
+      b = 2;
+  # Describes c
+  c = 3;
+  xs = [
+    1
+
+      # Describes the rest of the list
+
+    2
+    # Describes 3
+    3
+  ];
+}
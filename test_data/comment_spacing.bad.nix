@@ -0,0 +1,14 @@
+#!/usr/bin/env nix-shell
+## Options
+{
+  #comment
+  a = 1; #why
+  b = /*inline */ 2;
+  e = /*tight*/ 5;
+  /**
+    Documentation
+  */
+  c = 3;
+  #nixfmt: off
+  d = 4;
+}
@@ -0,0 +1,27 @@
+/**
+  Functions for packages.
+*/
+
+{ lib }:
+{
+      /**
+        Adds one.
+
+        # Example
+
+            addOne 1
+      */
+
+  addOne = x: x + 1;
+
+    /**
+     * Old-style doc comment
+        * with stars
+       */
+  double = x: x * 2;
+
+  ## A doc comment in the shell style,
+  ## which is never reflowed.
+
+  inherit (lib) id;
+}
@@ -0,0 +1,16 @@
+x:
+{
+  a = if x == 1 then 1
+    else if x == 2 then 2
+      else if x == 3 then 3
+        else 4;
+  b = if x == 1 then
+    1
+  else if x == 2 then
+      2
+      else
+    3;
+  c = y: if y then "a"
+    else if x == 2 then "b"
+      else "c";
+}
\ No newline at end of file
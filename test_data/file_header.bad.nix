@@ -0,0 +1,12 @@
+
+
+#Copyright (c) 2020 Some Author
+#
+#      _   _ _
+#     | \ | (_)_  __
+#     |  \| | \ \/ /
+#     | |\  | |>  <
+#     |_| \_|_/_/\_\
+#
+{ pkgs }:
+pkgs.hello
@@ -0,0 +1,9 @@
+{ url?null
+, src  ?  {
+    outPath = ./.;
+  }
+, meta
+  ?
+  { }
+}:
+url
@@ -0,0 +1,7 @@
+{ url ? null
+, src ? {
+    outPath = ./.;
+  }
+, meta ? { }
+}:
+url
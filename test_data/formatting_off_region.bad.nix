@@ -0,0 +1,11 @@
+{
+  a  =  1;
+  # nixfmt: off
+  matrix = [
+    1 0
+	0 1
+  ];
+      # nixfmt: on
+  b   = 2;
+    c = [1    2];
+}
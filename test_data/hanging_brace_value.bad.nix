@@ -0,0 +1,24 @@
+let
+  drv = stdenv.mkDerivation rec {
+        name = "x";
+          meta = with lib; {
+              license = licenses.mit;
+              };
+     };
+  o = self: super: {
+        a = 1;
+     };
+in
+{
+  p = pkgs.callPackage ./x {
+    a = 1;
+      };
+  q = f (x: {
+      a = 1;
+      });
+  l = [
+      1
+   ];
+  v = lib.mkIf cfg.enable {
+       a = 1; };
+}
\ No newline at end of file
@@ -0,0 +1,13 @@
+{
+  f = x: let
+  a = 1;
+        # describe b
+   b = {
+ x = a;
+ };
+    inherit (x)
+  c;
+  in
+    # body
+      b // { inherit c; };
+}
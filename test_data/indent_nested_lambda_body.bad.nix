@@ -0,0 +1,19 @@
+{
+  overlay = self: super:
+  {
+  x = 1;
+  };
+  f = { pkgs }:
+  pkgs.stdenv.mkDerivation {
+  name = "x";
+  };
+  g = [
+    (x:
+    x + 1)
+  ];
+  b = {
+    c = x:
+          { y }:
+              y;
+  };
+}
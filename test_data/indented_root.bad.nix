@@ -0,0 +1,11 @@
+    # pasted from somewhere else
+    { lib, ... }:
+    {
+      a = 1;
+      b = {
+          c = 2;
+        };
+      d = ''
+        text
+      '';
+    }
@@ -0,0 +1,12 @@
+# pasted from somewhere else
+
+{ lib, ... }:
+{
+  a = 1;
+  b = {
+    c = 2;
+  };
+  d = ''
+    text
+  '';
+}
@@ -0,0 +1,13 @@
+{
+  inherit (pkgs)
+  a
+      b
+  c
+  ;
+  inherit
+  d e;
+  x = let
+    inherit (lib) f
+          g;
+  in f;
+}
@@ -0,0 +1,15 @@
+{
+  inherit (pkgs)
+    a
+    b
+    c
+    ;
+  inherit
+    d e;
+  x =
+    let
+      inherit (lib) f
+        g;
+    in
+    f;
+}
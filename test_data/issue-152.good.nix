@@ -4,18 +4,18 @@ let
     # comment
     x;
   foo = x:
-    #comment
+    # comment
     x + x;
   bar = x:
     let y = x;
     in
-    #comment
+    # comment
     x;
   baz = x:
-    #comment
+    # comment
     y:
-    #comment
+    # comment
     foo + bar;
 in
-#comment
+# comment
 id 1
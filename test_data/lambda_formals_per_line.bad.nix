@@ -0,0 +1,15 @@
+{ lib
+    , stdenv
+        , fetchurl ? null
+  # used by tests
+  , withDocs ? false
+    }:
+
+{
+  mkPkg = { name
+            , version
+    }: stdenv.mkDerivation { inherit name version; };
+  mkOther = args @ { src,
+      patches ? [],
+  ... }: args;
+}
\ No newline at end of file
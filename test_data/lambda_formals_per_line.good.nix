@@ -0,0 +1,18 @@
+{ lib
+, stdenv
+, fetchurl ? null
+  # used by tests
+, withDocs ? false
+}:
+
+{
+  mkPkg =
+    { name
+    , version
+    }: stdenv.mkDerivation { inherit name version; };
+  mkOther =
+    args @ { src
+    , patches ? [ ]
+    , ...
+    }: args;
+}
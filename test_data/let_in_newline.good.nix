@@ -0,0 +1,22 @@
+x:
+let
+  a = 1;
+  b = 2;
+in
+{
+  inherit a b;
+  c = f (
+    let
+      d = a;
+    in
+    d
+  );
+  e = [
+    (
+      let
+        g = b;
+      in
+      g
+    )
+  ];
+}
@@ -0,0 +1,24 @@
+{
+  a = 1;
+  bcd = 2;
+  list = [
+    "a"
+    "b"
+  ];
+  x =
+    if a
+    then b
+    else c;
+  y = a +
+    b;
+  z =
+    let
+      a = 1;
+      b = 2;
+    in
+    a;
+  # aligned comment
+  w = foo
+    bar
+    baz;
+}
@@ -0,0 +1,13 @@
+{ a = {
+        b = {
+ c = 1;
+                };
+   };
+      d = { e = { f = {
+  g = 1;
+  }; }; };
+            h = [ {
+  i = { j = 1;
+          };
+} ];
+}
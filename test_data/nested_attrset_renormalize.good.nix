@@ -0,0 +1,19 @@
+{
+  a = {
+    b = {
+      c = 1;
+    };
+  };
+  d = {
+    e = {
+      f = {
+        g = 1;
+      };
+    };
+  };
+  h = [{
+    i = {
+      j = 1;
+    };
+  }];
+}
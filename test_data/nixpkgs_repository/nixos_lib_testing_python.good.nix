@@ -17,7 +17,7 @@
       wrapProgram $out/bin/nixos-test-driver \
         --add-flags "''${vms[*]}" \
         ${lib.optionalString enableOCR
-        "--prefix PATH : '${ocrProg}/bin:${imagemagick_tiff}/bin'"} \
+          "--prefix PATH : '${ocrProg}/bin:${imagemagick_tiff}/bin'"} \
         --run "export testScript=\"\$(${coreutils}/bin/cat $out/test-script)\"" \
         --set VLANS '${toString vlans}'
       ln -s ${testDriver}/bin/nixos-test-driver $out/bin/nixos-run-vms
@@ -0,0 +1,15 @@
+{
+  x = (
+  a
+      );
+  y = f (
+  a b
+  );
+  z = (a
+  b);
+  w = [
+      (
+    g
+        )
+  ];
+}
@@ -0,0 +1,21 @@
+{
+  a = rec {
+     x = 1;
+       };
+  b = foo rec {
+      x = 1;
+        };
+  c = [
+    rec {
+        x = 1;
+      }
+  ];
+  d = rec
+  {
+    x = 1;
+    y = rec   { z = x; };
+  };
+  e = with lib; rec {
+       x = 1;
+    };
+}
\ No newline at end of file
@@ -0,0 +1,15 @@
+{
+  foo = bar
+   ;
+  baz = with x; {
+    x = 1;
+  }
+      ;
+  commented = bar # comment
+      ;
+  l = let a = 1; in a
+  ;
+  m = a
+    ++ b
+    ;
+}
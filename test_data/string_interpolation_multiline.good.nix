@@ -0,0 +1,19 @@
+{
+  buildPhase = ''
+      make ${lib.concatStringsSep " " [
+        a
+        b
+      ]}
+    install ${
+      foo
+    }
+  '';
+  checkPhase = ''
+    test ${
+      check "a
+  b" {
+        c = 1;
+      }
+    }
+  '';
+}
@@ -0,0 +1,9 @@
+{
+  a = 1;
+  b = [ 1 2 )   { x = 1; } ];
+  c =
+    if then { y = 2; };
+  d = {
+    e   = 3;
+  };
+}
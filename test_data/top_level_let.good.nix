@@ -2,8 +2,7 @@
 let
   pname = "hello";
   version = "1.2.3";
-in
-#comment
+in # comment
 with pname;
 stdenv.mkDerivation {
   inherit pname version;
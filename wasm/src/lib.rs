@@ -1,4 +1,7 @@
 use std::panic;
+
+use nixpkgs_fmt::Config;
+use serde_json::Value;
 use wasm_bindgen::prelude::*;
 
 // Use the smaller `wee_alloc` as the global allocator.
@@ -29,3 +32,51 @@ pub fn reformat_string(text: &str, format: &str) -> String {
         return out;
     }
 }
+
+/// Formats `source` with the options from `config_json`, like
+/// `{"maxWidth": 80, "canonicalLayout": true}`. Missing options keep their
+/// defaults, and an empty string means the default config.
+#[wasm_bindgen]
+pub fn format(source: &str, config_json: &str) -> Result<String, JsValue> {
+    let config = parse_config(config_json).map_err(|err| JsValue::from_str(&err))?;
+    Ok(nixpkgs_fmt::reformat_string_with_config(source, &config))
+}
+
+fn parse_config(json: &str) -> Result<Config, String> {
+    let mut config = Config::default();
+    if json.trim().is_empty() {
+        return Ok(config);
+    }
+    let value: Value = serde_json::from_str(json).map_err(|err| err.to_string())?;
+    let options = value.as_object().ok_or("config must be a JSON object")?;
+    for (key, value) in options {
+        match key.as_str() {
+            "continuationIndent" => config.continuation_indent = number(key, value)?,
+            "maxWidth" => config.max_width = optional_number(key, value)?,
+            "canonicalLayout" => config.canonical_layout = boolean(key, value)?,
+            "commentWidth" => config.comment_width = optional_number(key, value)?,
+            "alignTrailingComments" => config.align_trailing_comments = boolean(key, value)?,
+            "trailingCommas" => config.trailing_commas = boolean(key, value)?,
+            _ => return Err(format!("unknown option `{}`", key)),
+        }
+    }
+    Ok(config)
+}
+
+fn number(key: &str, value: &Value) -> Result<u32, String> {
+    match value.as_u64() {
+        Some(it) if it <= u32::MAX as u64 => Ok(it as u32),
+        _ => Err(format!("`{}` must be a non-negative integer", key)),
+    }
+}
+
+fn optional_number(key: &str, value: &Value) -> Result<Option<u32>, String> {
+    if value.is_null() {
+        return Ok(None);
+    }
+    number(key, value).map(Some)
+}
+
+fn boolean(key: &str, value: &Value) -> Result<bool, String> {
+    value.as_bool().ok_or_else(|| format!("`{}` must be a boolean", key))
+}